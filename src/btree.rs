@@ -1,8 +1,13 @@
-//! B-Tree Node Implementation
+//! B-Tree Implementation
 //!
-//! This module implements both Leaf and Internal nodes for the B-Tree.
+//! This module implements both Leaf and Internal nodes for the B-Tree, and
+//! the `BTree` that tables and secondary indexes store their entries in.
+//! Keys are encoded by a `BTreeKey`; a leaf cell is a key followed by a
+//! fixed-size value, an internal cell a child pointer followed by a key.
 
-use crate::pager::PAGE_SIZE;
+use crate::pager::{FileHeader, Pager, FORMAT_VERSION, HEADER_PAGE, PAGE_SIZE};
+use std::fmt::Debug;
+use std::marker::PhantomData;
 use std::ptr;
 
 // --- Common Node Header ---
@@ -23,8 +28,6 @@ pub const INTERNAL_NODE_HEADER_SIZE: usize = COMMON_NODE_HEADER_SIZE + 8;
 
 // --- Internal Node Body ---
 pub const INTERNAL_NODE_CHILD_SIZE: usize = 4;
-pub const INTERNAL_NODE_KEY_SIZE: usize = 4;
-pub const INTERNAL_NODE_CELL_SIZE: usize = INTERNAL_NODE_CHILD_SIZE + INTERNAL_NODE_KEY_SIZE;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
@@ -101,16 +104,9 @@ pub fn set_leaf_node_next_leaf(page: &mut [u8; PAGE_SIZE], next_leaf: u32) {
     }
 }
 
-/// Get the key at a given cell index in a leaf node
-pub fn leaf_node_key(page: &[u8; PAGE_SIZE], cell_num: u32, cell_size: usize) -> u32 {
-    let offset = LEAF_NODE_HEADER_SIZE + (cell_num as usize * cell_size);
-    unsafe { ptr::read_unaligned(page.as_ptr().add(offset) as *const u32) }
-}
-
-/// Get pointer to cell data at given index
-pub fn leaf_node_cell(page: &mut [u8; PAGE_SIZE], cell_num: u32, cell_size: usize) -> *mut u8 {
-    let offset = LEAF_NODE_HEADER_SIZE + (cell_num as usize * cell_size);
-    unsafe { page.as_mut_ptr().add(offset) }
+/// Byte offset of a cell in a leaf node
+pub fn leaf_node_cell(cell_num: u32, cell_size: usize) -> usize {
+    LEAF_NODE_HEADER_SIZE + cell_num as usize * cell_size
 }
 
 /// Calculate max cells that fit in a leaf node
@@ -156,48 +152,23 @@ pub fn set_internal_node_right_child(page: &mut [u8; PAGE_SIZE], right_child: u3
     }
 }
 
-/// Get child pointer at index
-pub fn internal_node_child(page: &[u8; PAGE_SIZE], child_num: u32) -> u32 {
-    let num_keys = internal_node_num_keys(page);
-    if child_num == num_keys {
-        return internal_node_right_child(page);
-    }
-    let offset = INTERNAL_NODE_HEADER_SIZE + (child_num as usize * INTERNAL_NODE_CELL_SIZE);
-    unsafe { ptr::read_unaligned(page.as_ptr().add(offset) as *const u32) }
+/// Byte offset of a cell (child pointer, then key) in an internal node
+pub fn internal_node_cell(cell_num: u32, cell_size: usize) -> usize {
+    INTERNAL_NODE_HEADER_SIZE + cell_num as usize * cell_size
 }
 
-pub fn set_internal_node_child(page: &mut [u8; PAGE_SIZE], child_num: u32, child: u32) {
-    let num_keys = internal_node_num_keys(page);
-    if child_num == num_keys {
-        set_internal_node_right_child(page, child);
-        return;
-    }
-    let offset = INTERNAL_NODE_HEADER_SIZE + (child_num as usize * INTERNAL_NODE_CELL_SIZE);
-    unsafe {
-        ptr::write_unaligned(page.as_mut_ptr().add(offset) as *mut u32, child);
+/// Get child pointer at index; index `num_keys` is the right child
+pub fn internal_node_child(page: &[u8; PAGE_SIZE], child_num: u32, cell_size: usize) -> u32 {
+    if child_num == internal_node_num_keys(page) {
+        return internal_node_right_child(page);
     }
-}
-
-/// Get key at index in internal node
-pub fn internal_node_key(page: &[u8; PAGE_SIZE], key_num: u32) -> u32 {
-    let offset = INTERNAL_NODE_HEADER_SIZE
-        + (key_num as usize * INTERNAL_NODE_CELL_SIZE)
-        + INTERNAL_NODE_CHILD_SIZE;
+    let offset = internal_node_cell(child_num, cell_size);
     unsafe { ptr::read_unaligned(page.as_ptr().add(offset) as *const u32) }
 }
 
-pub fn set_internal_node_key(page: &mut [u8; PAGE_SIZE], key_num: u32, key: u32) {
-    let offset = INTERNAL_NODE_HEADER_SIZE
-        + (key_num as usize * INTERNAL_NODE_CELL_SIZE)
-        + INTERNAL_NODE_CHILD_SIZE;
-    unsafe {
-        ptr::write_unaligned(page.as_mut_ptr().add(offset) as *mut u32, key);
-    }
-}
-
 /// Calculate max keys that fit in an internal node
-pub fn internal_node_max_keys() -> usize {
-    (PAGE_SIZE - INTERNAL_NODE_HEADER_SIZE) / INTERNAL_NODE_CELL_SIZE
+pub fn internal_node_max_keys(cell_size: usize) -> usize {
+    (PAGE_SIZE - INTERNAL_NODE_HEADER_SIZE) / cell_size
 }
 
 /// Initialize a new internal node
@@ -208,22 +179,1033 @@ pub fn initialize_internal_node(page: &mut [u8; PAGE_SIZE]) {
     set_internal_node_right_child(page, 0);
 }
 
-/// Find the index of the child that should contain the given key
-pub fn internal_node_find_child(page: &[u8; PAGE_SIZE], key: u32) -> u32 {
-    let num_keys = internal_node_num_keys(page);
+// ============== B-Tree ==============
+
+/// A key the B-Tree can store: a fixed number of bytes whose order is the
+/// key's order
+pub trait BTreeKey: Ord + Clone + Debug {
+    /// Bytes a key takes in a cell
+    const SIZE: usize;
+
+    /// Encode the key into exactly `SIZE` bytes
+    fn write(&self, buf: &mut [u8]);
+
+    /// Decode a key written by `write`
+    fn read(buf: &[u8]) -> Self;
+}
+
+impl BTreeKey for u32 {
+    const SIZE: usize = 4;
+
+    fn write(&self, buf: &mut [u8]) {
+        buf[..4].copy_from_slice(&self.to_le_bytes());
+    }
+
+    fn read(buf: &[u8]) -> Self {
+        u32::from_le_bytes(buf[..4].try_into().unwrap())
+    }
+}
+
+/// A leaf node's cells, read out of its page
+type LeafCells<K> = Vec<(K, Vec<u8>)>;
+
+/// An internal node's cells and right child, read out of its page. Each
+/// cell's key is the largest key under its child; keys under the right
+/// child are larger than all of them.
+type InternalCells<K> = (Vec<(u32, K)>, u32);
+
+/// A B-Tree of unique keys, each with a value of `value_size` bytes, kept
+/// in one file behind a `FileHeader`.
+///
+/// Leaves are linked left to right. A full node splits in two and adds the
+/// new half to its parent, splitting that in turn when it's full; a node
+/// left less than half full by a delete borrows cells from a sibling, or
+/// merges with it when both fit in one page. Merged-away pages go on a
+/// free list and are reused before the file grows.
+pub struct BTree<K: BTreeKey> {
+    pub pager: Pager,
+    pub root_page_num: u32,
+    /// Leave changed pages in memory until `flush_all`, for transactions
+    pub defer_flush: bool,
+    value_size: usize,
+    first_free_page: u32,
+    schema_cookie: u32,
+    key: PhantomData<K>,
+}
+
+impl<K: BTreeKey> BTree<K> {
+    /// Open the B-Tree in `filename`: read and check its header, or set up
+    /// a header and an empty root leaf in a new file. A file from before
+    /// the header, with its root on page 0, is rebuilt on first open.
+    pub fn open(filename: &str, value_size: usize, schema_cookie: u32) -> Result<Self, String> {
+        let mut pager = Pager::open(filename)
+            .map_err(|e| format!("Failed to open file '{}': {}", filename, e))?;
+
+        if pager.file_length > 0 && pager.read_header().is_none() {
+            return Self::upgrade_legacy(filename, pager, value_size, schema_cookie);
+        }
+        let mut header = match pager.read_header() {
+            Some(header) => header,
+            None => {
+                let root_page = HEADER_PAGE + 1;
+                let root = pager.get_page(root_page as usize);
+                initialize_leaf_node(root);
+                set_node_root(root, true);
+                FileHeader {
+                    root_page,
+                    page_count: root_page + 1,
+                    format_version: FORMAT_VERSION,
+                    schema_cookie,
+                    first_free_page: 0,
+                }
+            }
+        };
+        // Version 3 only differs in how index keys are laid out, and indexes
+        // that old are built again by the executor, so a table keeps its pages
+        let outdated = header.format_version == 3;
+        if outdated {
+            header.format_version = FORMAT_VERSION;
+        }
+        if header.format_version != FORMAT_VERSION {
+            return Err(format!(
+                "File '{}' uses format version {}, expected {}",
+                filename, header.format_version, FORMAT_VERSION
+            ));
+        }
+        if header.schema_cookie != schema_cookie {
+            return Err(format!(
+                "File '{}' does not match the schema in the catalog",
+                filename
+            ));
+        }
+        pager.num_pages = pager.num_pages.max(header.page_count);
+
+        let mut tree = BTree {
+            pager,
+            root_page_num: header.root_page,
+            defer_flush: false,
+            value_size,
+            first_free_page: header.first_free_page,
+            schema_cookie,
+            key: PhantomData,
+        };
+        if tree.pager.file_length == 0 || outdated {
+            tree.write(tree.root_page_num);
+            tree.save_header();
+        }
+        Ok(tree)
+    }
+
+    /// Rebuild a file from before the header. Its internal nodes could
+    /// send a key to the wrong leaf, but every leaf is on the leaf chain, so
+    /// the entries are read along the chain and inserted into a new tree in
+    /// a file beside it, which then replaces the old one. A key the old tree
+    /// filed twice keeps its first entry.
+    fn upgrade_legacy(
+        filename: &str,
+        mut pager: Pager,
+        value_size: usize,
+        schema_cookie: u32,
+    ) -> Result<Self, String> {
+        let corrupt = || format!("File '{}' has no leaf chain to upgrade from", filename);
+        let leaf_cell_size = K::SIZE + value_size;
+        let internal_cell_size = INTERNAL_NODE_CHILD_SIZE + K::SIZE;
+
+        // The leftmost leaf is under the first child of each internal node,
+        // starting from the root on page 0
+        let mut page_num = HEADER_PAGE;
+        let mut depth = 0;
+        while get_node_type(pager.get_page(page_num as usize)) == NodeType::Internal {
+            page_num =
+                internal_node_child(pager.get_page(page_num as usize), 0, internal_cell_size);
+            depth += 1;
+            if page_num >= pager.num_pages || depth >= pager.num_pages {
+                return Err(corrupt());
+            }
+        }
+
+        let mut entries = Vec::new();
+        let mut leaves = 0;
+        loop {
+            let page = pager.get_page(page_num as usize);
+            let num_cells =
+                leaf_node_num_cells(page).min(leaf_node_max_cells(leaf_cell_size) as u32);
+            for cell_num in 0..num_cells {
+                let cell = &page[leaf_node_cell(cell_num, leaf_cell_size)..][..leaf_cell_size];
+                entries.push((K::read(cell), cell[K::SIZE..].to_vec()));
+            }
+            page_num = leaf_node_next_leaf(page);
+            leaves += 1;
+            if page_num == 0 {
+                break;
+            }
+            if page_num >= pager.num_pages || leaves >= pager.num_pages {
+                return Err(corrupt());
+            }
+        }
+        drop(pager);
+
+        let upgraded = format!("{}.upgrade", filename);
+        let _ = std::fs::remove_file(&upgraded);
+        let mut tree = BTree::<K>::open(&upgraded, value_size, schema_cookie)?;
+        for (key, value) in entries {
+            if tree.get(&key).is_none() {
+                tree.insert(key, &value)?;
+            }
+        }
+        drop(tree);
+        std::fs::rename(&upgraded, filename)
+            .map_err(|e| format!("Failed to replace '{}': {}", filename, e))?;
+        BTree::open(filename, value_size, schema_cookie)
+    }
+
+    /// Look up the value stored under a key
+    pub fn get(&mut self, key: &K) -> Option<Vec<u8>> {
+        let page_num = self.find_leaf(key);
+        let (cell_num, exists) = self.leaf_node_find(page_num, key);
+        exists.then(|| self.leaf_value(page_num, cell_num))
+    }
+
+    /// Insert a new key. The value is zero-padded to the tree's value size.
+    pub fn insert(&mut self, key: K, value: &[u8]) -> Result<(), String> {
+        let page_num = self.find_leaf(&key);
+        let (cell_num, exists) = self.leaf_node_find(page_num, &key);
+        if exists {
+            return Err(format!("Duplicate key {:?}", key));
+        }
+
+        let num_cells = leaf_node_num_cells(self.pager.get_page(page_num as usize));
+        if (num_cells as usize) < leaf_node_max_cells(self.leaf_cell_size()) {
+            let cell_size = self.leaf_cell_size();
+            let page = self.pager.get_page(page_num as usize);
+            let start = leaf_node_cell(cell_num, cell_size);
+            let end = leaf_node_cell(num_cells, cell_size);
+            page.copy_within(start..end, start + cell_size);
+            set_leaf_node_num_cells(page, num_cells + 1);
+            self.write_leaf_cell(page_num, cell_num, &key, value);
+            self.write(page_num);
+        } else {
+            self.split_leaf(page_num, cell_num, key, value);
+            self.save_header();
+        }
+        Ok(())
+    }
+
+    /// Overwrite the value of an existing key. Returns false if the key
+    /// isn't in the tree.
+    pub fn update(&mut self, key: &K, value: &[u8]) -> bool {
+        let page_num = self.find_leaf(key);
+        let (cell_num, exists) = self.leaf_node_find(page_num, key);
+        if exists {
+            self.write_leaf_cell(page_num, cell_num, key, value);
+            self.write(page_num);
+        }
+        exists
+    }
+
+    /// Remove a key. Returns false if it wasn't in the tree.
+    pub fn delete(&mut self, key: &K) -> bool {
+        let page_num = self.find_leaf(key);
+        let (cell_num, exists) = self.leaf_node_find(page_num, key);
+        if !exists {
+            return false;
+        }
+
+        let cell_size = self.leaf_cell_size();
+        let page = self.pager.get_page(page_num as usize);
+        let num_cells = leaf_node_num_cells(page);
+        let start = leaf_node_cell(cell_num + 1, cell_size);
+        let end = leaf_node_cell(num_cells, cell_size);
+        page.copy_within(start..end, start - cell_size);
+        set_leaf_node_num_cells(page, num_cells - 1);
+        let underfull =
+            !is_node_root(page) && ((num_cells - 1) as usize) < leaf_node_max_cells(cell_size) / 2;
+        self.write(page_num);
+
+        if underfull {
+            self.rebalance(page_num);
+            self.save_header();
+        }
+        true
+    }
+
+    /// Write every cached page to disk
+    pub fn flush_all(&mut self) {
+        self.pager.flush_all();
+    }
+
+    /// Forget unflushed changes and reload the tree as it is on disk
+    pub fn discard_changes(&mut self) {
+        if let Some(header) = self.pager.discard_changes() {
+            self.root_page_num = header.root_page;
+            self.first_free_page = header.first_free_page;
+        }
+    }
+
+    /// Find the leaf node that should contain the given key
+    fn find_leaf(&mut self, key: &K) -> u32 {
+        let cell_size = self.internal_cell_size();
+        let mut page_num = self.root_page_num;
+        loop {
+            let page = self.pager.get_page(page_num as usize);
+            if get_node_type(page) == NodeType::Leaf {
+                return page_num;
+            }
+
+            // The first child whose largest key is >= key, else the right child
+            let mut min = 0;
+            let mut max = internal_node_num_keys(page);
+            while min < max {
+                let mid = (min + max) / 2;
+                let offset = internal_node_cell(mid, cell_size) + INTERNAL_NODE_CHILD_SIZE;
+                if K::read(&page[offset..offset + K::SIZE]) >= *key {
+                    max = mid;
+                } else {
+                    min = mid + 1;
+                }
+            }
+            page_num = internal_node_child(page, min, cell_size);
+        }
+    }
+
+    /// Binary search within a leaf node: the key's cell, or where it would go
+    fn leaf_node_find(&mut self, page_num: u32, key: &K) -> (u32, bool) {
+        let mut min = 0;
+        let mut max = leaf_node_num_cells(self.pager.get_page(page_num as usize));
+        while min < max {
+            let mid = (min + max) / 2;
+            match self.leaf_key(page_num, mid).cmp(key) {
+                std::cmp::Ordering::Equal => return (mid, true),
+                std::cmp::Ordering::Greater => max = mid,
+                std::cmp::Ordering::Less => min = mid + 1,
+            }
+        }
+        (min, false)
+    }
+
+    /// The leaf before `page_num` in key order. Leaves only link forward,
+    /// so this climbs the parent pointers to the nearest ancestor with a
+    /// child further left, then follows right children down from it.
+    fn previous_leaf(&mut self, page_num: u32) -> Option<u32> {
+        let cell_size = self.internal_cell_size();
+        let mut child = page_num;
+        let mut page_num = loop {
+            if child == self.root_page_num {
+                return None;
+            }
+            let parent = get_parent_pointer(self.pager.get_page(child as usize));
+            let page = self.pager.get_page(parent as usize);
+            let position = (0..=internal_node_num_keys(page))
+                .find(|&i| internal_node_child(page, i, cell_size) == child)?;
+            if position > 0 {
+                break internal_node_child(page, position - 1, cell_size);
+            }
+            child = parent;
+        };
+        loop {
+            let page = self.pager.get_page(page_num as usize);
+            if get_node_type(page) == NodeType::Leaf {
+                return Some(page_num);
+            }
+            page_num = internal_node_right_child(page);
+        }
+    }
+
+    fn leaf_cell_size(&self) -> usize {
+        K::SIZE + self.value_size
+    }
+
+    fn internal_cell_size(&self) -> usize {
+        INTERNAL_NODE_CHILD_SIZE + K::SIZE
+    }
+
+    fn leaf_key(&mut self, page_num: u32, cell_num: u32) -> K {
+        let offset = leaf_node_cell(cell_num, self.leaf_cell_size());
+        K::read(&self.pager.get_page(page_num as usize)[offset..offset + K::SIZE])
+    }
+
+    fn leaf_value(&mut self, page_num: u32, cell_num: u32) -> Vec<u8> {
+        let offset = leaf_node_cell(cell_num, self.leaf_cell_size()) + K::SIZE;
+        self.pager.get_page(page_num as usize)[offset..offset + self.value_size].to_vec()
+    }
+
+    fn write_leaf_cell(&mut self, page_num: u32, cell_num: u32, key: &K, value: &[u8]) {
+        let offset = leaf_node_cell(cell_num, self.leaf_cell_size());
+        let value_size = self.value_size;
+        let cell =
+            &mut self.pager.get_page(page_num as usize)[offset..offset + K::SIZE + value_size];
+        cell.fill(0);
+        key.write(&mut cell[..K::SIZE]);
+        let len = value.len().min(value_size);
+        cell[K::SIZE..K::SIZE + len].copy_from_slice(&value[..len]);
+    }
+
+    fn read_leaf(&mut self, page_num: u32) -> LeafCells<K> {
+        let num_cells = leaf_node_num_cells(self.pager.get_page(page_num as usize));
+        (0..num_cells)
+            .map(|i| (self.leaf_key(page_num, i), self.leaf_value(page_num, i)))
+            .collect()
+    }
+
+    fn write_leaf(&mut self, page_num: u32, cells: &[(K, Vec<u8>)]) {
+        for (i, (key, value)) in cells.iter().enumerate() {
+            self.write_leaf_cell(page_num, i as u32, key, value);
+        }
+        set_leaf_node_num_cells(self.pager.get_page(page_num as usize), cells.len() as u32);
+    }
+
+    fn read_internal(&mut self, page_num: u32) -> InternalCells<K> {
+        let cell_size = self.internal_cell_size();
+        let page = self.pager.get_page(page_num as usize);
+        let cells = (0..internal_node_num_keys(page))
+            .map(|i| {
+                let offset = internal_node_cell(i, cell_size);
+                let key_offset = offset + INTERNAL_NODE_CHILD_SIZE;
+                (
+                    internal_node_child(page, i, cell_size),
+                    K::read(&page[key_offset..key_offset + K::SIZE]),
+                )
+            })
+            .collect();
+        (cells, internal_node_right_child(page))
+    }
+
+    /// Write an internal node's cells, and point each child back at it
+    fn write_internal(&mut self, page_num: u32, cells: &[(u32, K)], right_child: u32) {
+        let cell_size = self.internal_cell_size();
+        let page = self.pager.get_page(page_num as usize);
+        for (i, (child, key)) in cells.iter().enumerate() {
+            let offset = internal_node_cell(i as u32, cell_size);
+            page[offset..offset + INTERNAL_NODE_CHILD_SIZE].copy_from_slice(&child.to_le_bytes());
+            key.write(&mut page[offset + INTERNAL_NODE_CHILD_SIZE..offset + cell_size]);
+        }
+        set_internal_node_num_keys(page, cells.len() as u32);
+        set_internal_node_right_child(page, right_child);
+
+        for child in cells.iter().map(|(child, _)| *child).chain([right_child]) {
+            let child_page = self.pager.get_page(child as usize);
+            if get_parent_pointer(child_page) != page_num {
+                set_parent_pointer(child_page, page_num);
+                self.write(child);
+            }
+        }
+    }
+
+    /// Split a full leaf, with the new cell, into it and a new right sibling
+    fn split_leaf(&mut self, page_num: u32, cell_num: u32, key: K, value: &[u8]) {
+        let mut cells = self.read_leaf(page_num);
+        cells.insert(cell_num as usize, (key, value.to_vec()));
+        let left_count = cells.len().div_ceil(2);
+
+        let new_page_num = self.allocate_page();
+        let old_next = {
+            let new_page = self.pager.get_page(new_page_num as usize);
+            initialize_leaf_node(new_page);
+            let old_page = self.pager.get_page(page_num as usize);
+            let old_next = leaf_node_next_leaf(old_page);
+            set_leaf_node_next_leaf(old_page, new_page_num);
+            old_next
+        };
+        set_leaf_node_next_leaf(self.pager.get_page(new_page_num as usize), old_next);
+
+        self.write_leaf(page_num, &cells[..left_count]);
+        self.write_leaf(new_page_num, &cells[left_count..]);
+        self.write(page_num);
+        self.write(new_page_num);
+
+        let split_key = cells[left_count - 1].0.clone();
+        self.insert_into_parent(page_num, split_key, new_page_num);
+    }
+
+    /// Register `new_child`, the right half of the split `old_child`, in its
+    /// parent. `split_key` is the largest key left in `old_child`.
+    fn insert_into_parent(&mut self, old_child: u32, split_key: K, new_child: u32) {
+        let old_page = self.pager.get_page(old_child as usize);
+        if is_node_root(old_page) {
+            self.create_new_root(old_child, split_key, new_child);
+            return;
+        }
+        let parent = get_parent_pointer(old_page);
+
+        let (mut cells, mut right_child) = self.read_internal(parent);
+        match cells.iter().position(|(child, _)| *child == old_child) {
+            // The new page inherits the old upper bound
+            Some(i) => {
+                let old_key = std::mem::replace(&mut cells[i].1, split_key);
+                cells.insert(i + 1, (new_child, old_key));
+            }
+            // The right child was split: its left half becomes the last cell
+            None => {
+                cells.push((old_child, split_key));
+                right_child = new_child;
+            }
+        }
+
+        if cells.len() <= internal_node_max_keys(self.internal_cell_size()) {
+            self.write_internal(parent, &cells, right_child);
+            self.write(parent);
+        } else {
+            self.split_internal(parent, cells, right_child);
+        }
+    }
+
+    /// Split an internal node holding one cell too many. The middle key
+    /// moves up to the parent.
+    fn split_internal(&mut self, page_num: u32, cells: Vec<(u32, K)>, right_child: u32) {
+        let mid = cells.len() / 2;
+        let (left_right_child, up_key) = cells[mid].clone();
+
+        let new_page_num = self.allocate_page();
+        initialize_internal_node(self.pager.get_page(new_page_num as usize));
+        self.write_internal(page_num, &cells[..mid], left_right_child);
+        self.write_internal(new_page_num, &cells[mid + 1..], right_child);
+        self.write(page_num);
+        self.write(new_page_num);
 
-    let mut min = 0u32;
-    let mut max = num_keys;
+        self.insert_into_parent(page_num, up_key, new_page_num);
+    }
+
+    /// Put a new root above the two halves of the old one
+    fn create_new_root(&mut self, left_child: u32, split_key: K, right_child: u32) {
+        let root_page_num = self.allocate_page();
+        let root = self.pager.get_page(root_page_num as usize);
+        initialize_internal_node(root);
+        set_node_root(root, true);
+        self.write_internal(root_page_num, &[(left_child, split_key)], right_child);
+        set_node_root(self.pager.get_page(left_child as usize), false);
+
+        self.write(root_page_num);
+        self.write(left_child);
+        self.root_page_num = root_page_num;
+    }
 
-    while min < max {
-        let mid = (min + max) / 2;
-        let key_at_mid = internal_node_key(page, mid);
-        if key_at_mid >= key {
-            max = mid;
+    /// Refill a node left less than half full, from the sibling next to it
+    /// under the same parent: merge the two if they fit in one page,
+    /// otherwise share their cells out evenly.
+    fn rebalance(&mut self, page_num: u32) {
+        let parent = get_parent_pointer(self.pager.get_page(page_num as usize));
+        let (mut cells, mut right_child) = self.read_internal(parent);
+        let children: Vec<u32> = cells.iter().map(|(child, _)| *child).collect();
+        let position = children
+            .iter()
+            .position(|&child| child == page_num)
+            .unwrap_or(children.len());
+        if cells.is_empty() {
+            return;
+        }
+
+        // The pair of siblings to work on: the node and its left neighbour,
+        // or its right neighbour if it's the leftmost child
+        let j = position.saturating_sub(1);
+        let left = cells[j].0;
+        let right = cells.get(j + 1).map_or(right_child, |(child, _)| *child);
+
+        let merged = if get_node_type(self.pager.get_page(left as usize)) == NodeType::Leaf {
+            self.rebalance_leaves(left, right, &mut cells[j].1)
         } else {
-            min = mid + 1;
+            self.rebalance_internal(left, right, &mut cells[j].1)
+        };
+
+        if merged {
+            // `left` now covers the range `right` had, up to its bound
+            match cells.get_mut(j + 1) {
+                Some(cell) => cell.0 = left,
+                None => right_child = left,
+            }
+            cells.remove(j);
+            self.free_page(right);
+        }
+        self.write_internal(parent, &cells, right_child);
+        self.write(parent);
+
+        let parent_page = self.pager.get_page(parent as usize);
+        if is_node_root(parent_page) {
+            // A root left with a single child hands the root over to it
+            if cells.is_empty() {
+                set_node_root(self.pager.get_page(right_child as usize), true);
+                self.write(right_child);
+                self.root_page_num = right_child;
+                self.free_page(parent);
+            }
+        } else if cells.len() < internal_node_max_keys(self.internal_cell_size()) / 2 {
+            self.rebalance(parent);
+        }
+    }
+
+    /// Merge or even out two neighbouring leaves. `bound` is the parent's
+    /// key for `left`. Returns true if `right` was merged into `left`.
+    fn rebalance_leaves(&mut self, left: u32, right: u32, bound: &mut K) -> bool {
+        let mut cells = self.read_leaf(left);
+        cells.extend(self.read_leaf(right));
+
+        if cells.len() <= leaf_node_max_cells(self.leaf_cell_size()) {
+            let next_leaf = leaf_node_next_leaf(self.pager.get_page(right as usize));
+            set_leaf_node_next_leaf(self.pager.get_page(left as usize), next_leaf);
+            self.write_leaf(left, &cells);
+            self.write(left);
+            return true;
+        }
+
+        let left_count = cells.len().div_ceil(2);
+        self.write_leaf(left, &cells[..left_count]);
+        self.write_leaf(right, &cells[left_count..]);
+        self.write(left);
+        self.write(right);
+        *bound = cells[left_count - 1].0.clone();
+        false
+    }
+
+    /// Merge or even out two neighbouring internal nodes. `bound`, the
+    /// parent's key for `left`, comes down between their cells.
+    fn rebalance_internal(&mut self, left: u32, right: u32, bound: &mut K) -> bool {
+        let (mut cells, left_right_child) = self.read_internal(left);
+        let (right_cells, right_child) = self.read_internal(right);
+        cells.push((left_right_child, bound.clone()));
+        cells.extend(right_cells);
+
+        if cells.len() <= internal_node_max_keys(self.internal_cell_size()) {
+            self.write_internal(left, &cells, right_child);
+            self.write(left);
+            return true;
+        }
+
+        let mid = cells.len() / 2;
+        let (mid_child, mid_key) = cells[mid].clone();
+        self.write_internal(left, &cells[..mid], mid_child);
+        self.write_internal(right, &cells[mid + 1..], right_child);
+        self.write(left);
+        self.write(right);
+        *bound = mid_key;
+        false
+    }
+
+    /// A page for a new node: one from the free list, or a new one at the
+    /// end of the file
+    fn allocate_page(&mut self) -> u32 {
+        if self.first_free_page == 0 {
+            let page_num = self.pager.num_pages;
+            self.pager.get_page(page_num as usize);
+            return page_num;
+        }
+        let page_num = self.first_free_page;
+        let page = self.pager.get_page(page_num as usize);
+        self.first_free_page = u32::from_le_bytes(page[..4].try_into().unwrap());
+        page.fill(0);
+        page_num
+    }
+
+    /// Put a page no longer in the tree on the free list
+    fn free_page(&mut self, page_num: u32) {
+        let page = self.pager.get_page(page_num as usize);
+        page.fill(0);
+        page[..4].copy_from_slice(&self.first_free_page.to_le_bytes());
+        self.first_free_page = page_num;
+        self.write(page_num);
+    }
+
+    /// Record the root page, page count and free list in the file header
+    fn save_header(&mut self) {
+        let header = FileHeader {
+            root_page: self.root_page_num,
+            page_count: self.pager.num_pages,
+            format_version: FORMAT_VERSION,
+            schema_cookie: self.schema_cookie,
+            first_free_page: self.first_free_page,
+        };
+        self.pager.write_header(&header);
+        self.write(HEADER_PAGE);
+    }
+
+    /// Flush a changed page, unless writes are being deferred
+    fn write(&mut self, page_num: u32) {
+        if !self.defer_flush {
+            self.pager.flush(page_num as usize);
+        }
+    }
+}
+
+/// A position among the entries of a B-Tree, in key order.
+///
+/// The cursor only records where it is; the tree is passed to each call,
+/// so any number of cursors can walk the same tree. Leaves are read one
+/// cell at a time as the cursor moves, nothing is materialized up front.
+pub struct Cursor {
+    page_num: u32,
+    cell_num: u32,
+    pub end_of_table: bool,
+}
+
+impl Cursor {
+    /// Position at the first entry of the tree
+    pub fn start<K: BTreeKey>(tree: &mut BTree<K>) -> Cursor {
+        let cell_size = tree.internal_cell_size();
+        let mut page_num = tree.root_page_num;
+        loop {
+            let page = tree.pager.get_page(page_num as usize);
+            if get_node_type(page) == NodeType::Leaf {
+                break;
+            }
+            page_num = internal_node_child(page, 0, cell_size);
         }
+
+        let mut cursor = Cursor {
+            page_num,
+            cell_num: 0,
+            end_of_table: false,
+        };
+        cursor.skip_forward(tree);
+        cursor
     }
 
-    min
+    /// Position at the last entry of the tree
+    pub fn end<K: BTreeKey>(tree: &mut BTree<K>) -> Cursor {
+        let mut page_num = tree.root_page_num;
+        loop {
+            let page = tree.pager.get_page(page_num as usize);
+            if get_node_type(page) == NodeType::Leaf {
+                break;
+            }
+            page_num = internal_node_right_child(page);
+        }
+
+        let mut cursor = Cursor {
+            page_num,
+            cell_num: leaf_node_num_cells(tree.pager.get_page(page_num as usize)),
+            end_of_table: false,
+        };
+        cursor.prev(tree);
+        cursor
+    }
+
+    /// Position at the first entry whose key is >= `key`
+    pub fn seek<K: BTreeKey>(tree: &mut BTree<K>, key: &K) -> Cursor {
+        let page_num = tree.find_leaf(key);
+        let (cell_num, _) = tree.leaf_node_find(page_num, key);
+        let mut cursor = Cursor {
+            page_num,
+            cell_num,
+            end_of_table: false,
+        };
+        cursor.skip_forward(tree);
+        cursor
+    }
+
+    /// Advance to the next entry, following the leaf chain
+    pub fn next<K: BTreeKey>(&mut self, tree: &mut BTree<K>) {
+        if self.end_of_table {
+            return;
+        }
+        self.cell_num += 1;
+        self.skip_forward(tree);
+    }
+
+    /// Step back to the previous entry; stepping back from the first one
+    /// leaves the cursor at the end of the table
+    pub fn prev<K: BTreeKey>(&mut self, tree: &mut BTree<K>) {
+        if self.end_of_table {
+            return;
+        }
+        while self.cell_num == 0 {
+            match tree.previous_leaf(self.page_num) {
+                Some(page_num) => {
+                    self.page_num = page_num;
+                    self.cell_num = leaf_node_num_cells(tree.pager.get_page(page_num as usize));
+                }
+                None => {
+                    self.end_of_table = true;
+                    return;
+                }
+            }
+        }
+        self.cell_num -= 1;
+    }
+
+    /// Key of the entry under the cursor
+    pub fn key<K: BTreeKey>(&self, tree: &mut BTree<K>) -> K {
+        tree.leaf_key(self.page_num, self.cell_num)
+    }
+
+    /// Value of the entry under the cursor
+    pub fn value<K: BTreeKey>(&self, tree: &mut BTree<K>) -> Vec<u8> {
+        tree.leaf_value(self.page_num, self.cell_num)
+    }
+
+    /// Move past the end of exhausted (or empty) leaves
+    fn skip_forward<K: BTreeKey>(&mut self, tree: &mut BTree<K>) {
+        loop {
+            let page = tree.pager.get_page(self.page_num as usize);
+            if self.cell_num < leaf_node_num_cells(page) {
+                return;
+            }
+            let next_leaf = leaf_node_next_leaf(page);
+            if next_leaf == 0 {
+                self.end_of_table = true;
+                return;
+            }
+            self.page_num = next_leaf;
+            self.cell_num = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    /// Values large enough that leaves hold a handful of cells, so a few
+    /// thousand keys build a tree several levels deep
+    const VALUE_SIZE: usize = 400;
+
+    struct TempFile(String);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("rsql-btree-{}-{}.db", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            TempFile(path.to_str().unwrap().to_string())
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Keys in a fixed pseudo-random order (xorshift), without repeats
+    fn shuffled(count: u32, mut seed: u32) -> Vec<u32> {
+        let mut keys: Vec<u32> = (1..=count).map(|key| key * 3).collect();
+        for i in (1..keys.len()).rev() {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            keys.swap(i, seed as usize % (i + 1));
+        }
+        keys
+    }
+
+    fn value(key: u32) -> Vec<u8> {
+        let mut value = vec![0u8; VALUE_SIZE];
+        value[..4].copy_from_slice(&key.to_le_bytes());
+        value[VALUE_SIZE - 4..].copy_from_slice(&(!key).to_le_bytes());
+        value
+    }
+
+    /// Every entry, read with a cursor
+    fn entries(tree: &mut BTree<u32>) -> Vec<(u32, Vec<u8>)> {
+        let mut cursor = Cursor::start(tree);
+        let mut entries = Vec::new();
+        while !cursor.end_of_table {
+            entries.push((cursor.key(tree), cursor.value(tree)));
+            cursor.next(tree);
+        }
+        entries
+    }
+
+    /// Every key, read backwards from the last one
+    fn keys_backwards(tree: &mut BTree<u32>) -> Vec<u32> {
+        let mut cursor = Cursor::end(tree);
+        let mut keys = Vec::new();
+        while !cursor.end_of_table {
+            keys.push(cursor.key(tree));
+            cursor.prev(tree);
+        }
+        keys
+    }
+
+    fn check(tree: &mut BTree<u32>, expected: &BTreeMap<u32, Vec<u8>>) {
+        let expected_entries: Vec<(u32, Vec<u8>)> =
+            expected.iter().map(|(k, v)| (*k, v.clone())).collect();
+        assert_eq!(entries(tree), expected_entries);
+        let backwards: Vec<u32> = expected.keys().rev().copied().collect();
+        assert_eq!(keys_backwards(tree), backwards);
+        for (key, value) in expected {
+            assert_eq!(tree.get(key).as_ref(), Some(value));
+        }
+    }
+
+    fn root_is_leaf(tree: &mut BTree<u32>) -> bool {
+        let root = tree.root_page_num as usize;
+        get_node_type(tree.pager.get_page(root)) == NodeType::Leaf
+    }
+
+    /// Write a file the way trees were stored before the header: the root
+    /// on page 0 and each leaf, in chain order, holding the given entries.
+    /// Like those trees, the root sends keys to the wrong leaves: its right
+    /// child is the second leaf, and its keys are left as they were after
+    /// the first split.
+    fn write_legacy(path: &str, leaves: &[Vec<(u32, Vec<u8>)>]) {
+        let leaf_cell_size = 4 + VALUE_SIZE;
+        let internal_cell_size = INTERNAL_NODE_CHILD_SIZE + 4;
+        // Leaves after the first are spread over the file out of chain order
+        let page_of = |leaf: usize| match leaf {
+            0 => 1,
+            _ => 2 + (leaf * 5) % (leaves.len() - 1),
+        };
+        let mut pages = vec![[0u8; PAGE_SIZE]; leaves.len() + 1];
+
+        let root = &mut pages[0];
+        initialize_internal_node(root);
+        set_node_root(root, true);
+        set_internal_node_num_keys(root, leaves.len() as u32 - 1);
+        set_internal_node_right_child(root, page_of(1) as u32);
+        let first_split = leaves[0].last().unwrap().0;
+        for (cell, leaf) in (0..leaves.len()).filter(|&leaf| leaf != 1).enumerate() {
+            let offset = internal_node_cell(cell as u32, internal_cell_size);
+            root[offset..offset + 4].copy_from_slice(&(page_of(leaf) as u32).to_le_bytes());
+            root[offset + 4..offset + 8].copy_from_slice(&first_split.to_le_bytes());
+        }
+
+        for (leaf, cells) in leaves.iter().enumerate() {
+            let page = &mut pages[page_of(leaf)];
+            initialize_leaf_node(page);
+            set_leaf_node_num_cells(page, cells.len() as u32);
+            let next = if leaf + 1 < leaves.len() {
+                page_of(leaf + 1)
+            } else {
+                0
+            };
+            set_leaf_node_next_leaf(page, next as u32);
+            for (cell_num, (key, value)) in cells.iter().enumerate() {
+                let offset = leaf_node_cell(cell_num as u32, leaf_cell_size);
+                page[offset..offset + 4].copy_from_slice(&key.to_le_bytes());
+                page[offset + 4..offset + leaf_cell_size].copy_from_slice(value);
+            }
+        }
+        std::fs::write(path, pages.concat()).unwrap();
+    }
+
+    #[test]
+    fn random_inserts_and_deletes_keep_order() {
+        let file = TempFile::new("order");
+        let mut tree = BTree::<u32>::open(&file.0, VALUE_SIZE, 7).unwrap();
+        let mut expected = BTreeMap::new();
+
+        for key in shuffled(5000, 0x2545_f491) {
+            tree.insert(key, &value(key)).unwrap();
+            expected.insert(key, value(key));
+        }
+        assert!(tree.insert(3, &value(3)).is_err());
+        assert!(!root_is_leaf(&mut tree));
+        check(&mut tree, &expected);
+
+        let mut seek = Cursor::seek(&mut tree, &1000);
+        assert_eq!(seek.key(&mut tree), 1002);
+        seek.prev(&mut tree);
+        assert_eq!(seek.key(&mut tree), 999);
+        let mut first = Cursor::start(&mut tree);
+        first.prev(&mut tree);
+        assert!(first.end_of_table);
+
+        for key in shuffled(5000, 0x9e37_79b9).into_iter().take(4500) {
+            assert!(tree.delete(&key));
+            assert!(!tree.delete(&key));
+            expected.remove(&key);
+        }
+        check(&mut tree, &expected);
+        for key in (1..=5000).map(|key| key * 3) {
+            assert_eq!(tree.get(&key).is_some(), expected.contains_key(&key));
+        }
+    }
+
+    #[test]
+    fn deleting_almost_everything_collapses_the_root() {
+        let file = TempFile::new("collapse");
+        let mut tree = BTree::<u32>::open(&file.0, VALUE_SIZE, 7).unwrap();
+        let keys = shuffled(3000, 0x1234_5678);
+        for &key in &keys {
+            tree.insert(key, &value(key)).unwrap();
+        }
+
+        let (gone, kept) = keys.split_at(keys.len() - 3);
+        for key in gone {
+            assert!(tree.delete(key));
+        }
+        assert!(root_is_leaf(&mut tree));
+        let expected = kept.iter().map(|&key| (key, value(key))).collect();
+        check(&mut tree, &expected);
+
+        for key in kept {
+            assert!(tree.delete(key));
+        }
+        assert!(root_is_leaf(&mut tree));
+        assert!(entries(&mut tree).is_empty());
+        assert!(Cursor::start(&mut tree).end_of_table);
+        assert!(Cursor::end(&mut tree).end_of_table);
+    }
+
+    #[test]
+    fn freed_pages_are_reused_after_reopen() {
+        let file = TempFile::new("reuse");
+        let mut expected = BTreeMap::new();
+        let page_count = {
+            let mut tree = BTree::<u32>::open(&file.0, VALUE_SIZE, 7).unwrap();
+            let keys = shuffled(4000, 0xdead_beef);
+            for &key in &keys {
+                tree.insert(key, &value(key)).unwrap();
+            }
+            for &key in &keys[..3500] {
+                tree.delete(&key);
+            }
+            for &key in &keys[3500..] {
+                expected.insert(key, value(key));
+            }
+            assert_ne!(tree.first_free_page, 0);
+            tree.pager.num_pages
+        };
+
+        let mut tree = BTree::<u32>::open(&file.0, VALUE_SIZE, 7).unwrap();
+        assert_eq!(tree.pager.num_pages, page_count);
+        assert_ne!(tree.first_free_page, 0);
+        check(&mut tree, &expected);
+
+        // Fewer new keys than were deleted fit in the freed pages
+        for key in (1..=2000).map(|key| key * 3 + 1) {
+            tree.insert(key, &value(key)).unwrap();
+            expected.insert(key, value(key));
+        }
+        assert_eq!(tree.pager.num_pages, page_count);
+        check(&mut tree, &expected);
+
+        assert!(BTree::<u32>::open(&file.0, VALUE_SIZE, 8).is_err());
+    }
+
+    #[test]
+    fn legacy_files_are_rebuilt_from_their_leaf_chain() {
+        let file = TempFile::new("legacy");
+        let mut leaves: Vec<Vec<(u32, Vec<u8>)>> = (0..11)
+            .map(|leaf| {
+                (1..=8)
+                    .map(|i| leaf * 24 + i * 3)
+                    .map(|key| (key, value(key)))
+                    .collect()
+            })
+            .collect();
+        // The last leaf took keys its parent misrouted, one of them a key
+        // already filed in the first leaf
+        leaves.push(vec![(3, value(4)), (50, value(50)), (100, value(100))]);
+        write_legacy(&file.0, &leaves);
+
+        let mut expected: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+        for (key, value) in leaves.iter().flatten() {
+            expected.entry(*key).or_insert_with(|| value.clone());
+        }
+        assert_eq!(expected.len(), 90);
+        {
+            let mut tree = BTree::<u32>::open(&file.0, VALUE_SIZE, 7).unwrap();
+            assert!(!root_is_leaf(&mut tree));
+            check(&mut tree, &expected);
+        }
+        assert!(!Path::new(&format!("{}.upgrade", file.0)).exists());
+
+        // The rebuilt file has a header and opens as it is
+        let mut tree = BTree::<u32>::open(&file.0, VALUE_SIZE, 7).unwrap();
+        check(&mut tree, &expected);
+        tree.insert(1, &value(1)).unwrap();
+        expected.insert(1, value(1));
+        check(&mut tree, &expected);
+    }
 }
@@ -10,15 +10,22 @@ use rustyline::Helper;
 
 pub struct SqlCompleter {
     pub table_names: Vec<String>,
+    /// Functions registered with the executor, built-in or not
+    pub function_names: Vec<String>,
 }
 
 impl SqlCompleter {
     pub fn new() -> Self {
         SqlCompleter {
             table_names: Vec::new(),
+            function_names: Vec::new(),
         }
     }
 
+    pub fn set_functions(&mut self, names: Vec<String>) {
+        self.function_names = names;
+    }
+
     pub fn add_table(&mut self, name: String) {
         if !self.table_names.contains(&name) {
             self.table_names.push(name);
@@ -30,13 +37,18 @@ impl SqlCompleter {
     }
 }
 
+#[rustfmt::skip]
 const SQL_KEYWORDS: &[&str] = &[
     "SELECT", "INSERT", "UPDATE", "DELETE", "CREATE", "DROP", "TABLE", "FROM", "WHERE", "INTO",
     "VALUES", "SET", "AND", "OR", "NOT", "INTEGER", "TEXT", "VARCHAR", "PRIMARY", "KEY", "NULL",
     "DEFAULT", "ORDER", "BY", "ASC", "DESC", "LIMIT", "OFFSET", "JOIN", "ON", "LEFT", "RIGHT",
     "INNER", "OUTER", "GROUP", "HAVING", "COUNT", "SUM", "AVG", "MIN", "MAX", "DISTINCT", "AS",
     "LIKE", "IN", "BETWEEN", "EXISTS", "CASE", "WHEN", "THEN", "ELSE", "END", "INDEX", "UNIQUE",
-    "BEGIN", "COMMIT", "ROLLBACK", "DATABASE", "CONNECT",
+    "BEGIN", "COMMIT", "ROLLBACK", "DATABASE", "CONNECT", "FULL", "CROSS", "IS", "ANALYZE",
+    "EXPLAIN", "QUERY", "PLAN", "WITH", "RECURSIVE", "UNION", "ALL", "INTERSECT", "EXCEPT",
+    "OVER", "PARTITION", "ROWS", "PRECEDING", "FOLLOWING", "UNBOUNDED", "CURRENT", "ROW",
+    "ROW_NUMBER", "RANK", "DENSE_RANK", "LAG", "LEAD", "COALESCE", "NULLIF", "IFNULL", "IIF",
+    "RETURNING",
 ];
 
 impl Completer for SqlCompleter {
@@ -71,6 +83,16 @@ impl Completer for SqlCompleter {
             }
         }
 
+        // Match function names
+        for name in &self.function_names {
+            if name.starts_with(&word_upper) && !word.is_empty() {
+                matches.push(Pair {
+                    display: name.clone(),
+                    replacement: name.clone(),
+                });
+            }
+        }
+
         // Match table names (case-insensitive)
         for table in &self.table_names {
             if table.to_uppercase().starts_with(&word_upper) && !word.is_empty() {
@@ -0,0 +1,533 @@
+//! Expression evaluation over rows
+
+use crate::operator::{collect_rows, ExecContext, Operator, Row};
+use crate::parser::*;
+use crate::value::SqlValue;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Columns visible to an expression, grouped by the table (or alias) they
+/// belong to. Rows are laid out in the same order the tables were added,
+/// followed by the values of any aggregates computed over them, then those
+/// of any window functions.
+///
+/// The scope of a subquery links to the scope of the query around it, so
+/// correlated columns resolve to the outer row being evaluated.
+#[derive(Clone)]
+pub struct Scope {
+    tables: Vec<(String, Vec<String>)>,
+    aggregates: Vec<Expr>,
+    windows: Vec<Expr>,
+    outer: Option<Box<Scope>>,
+    /// Plans for the subqueries of expressions evaluated in this scope
+    subqueries: Vec<(SelectStmt, Rc<RefCell<Subquery>>)>,
+}
+
+/// A planned subquery. One that doesn't refer to the outer query runs once
+/// and keeps its rows; a correlated one runs again for every outer row.
+pub struct Subquery {
+    plan: Box<dyn Operator>,
+    correlated: bool,
+    rows: Option<Rc<Vec<Row>>>,
+}
+
+impl Default for Scope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scope {
+    pub fn new() -> Self {
+        Scope {
+            tables: Vec::new(),
+            aggregates: Vec::new(),
+            windows: Vec::new(),
+            outer: None,
+            subqueries: Vec::new(),
+        }
+    }
+
+    /// An empty scope for a subquery of a query whose rows `outer` describes
+    pub fn nested(outer: Option<&Scope>) -> Self {
+        Scope {
+            outer: outer.map(|outer| {
+                Box::new(Scope {
+                    subqueries: Vec::new(),
+                    ..outer.clone()
+                })
+            }),
+            ..Scope::new()
+        }
+    }
+
+    /// The scope of the query this one is a subquery of
+    pub fn outer(&self) -> Option<&Scope> {
+        self.outer.as_deref()
+    }
+
+    /// Add a table, or a derived table, by its column names
+    pub fn add_columns(&mut self, binding: &str, columns: Vec<String>) {
+        self.tables.push((binding.to_string(), columns));
+    }
+
+    /// The scope of rows coming out of an Aggregate operator, which appends
+    /// one value per aggregate to each row
+    pub fn with_aggregates(&self, aggregates: Vec<Expr>) -> Scope {
+        Scope {
+            tables: self.tables.clone(),
+            aggregates,
+            windows: Vec::new(),
+            outer: self.outer.clone(),
+            subqueries: Vec::new(),
+        }
+    }
+
+    /// The scope of rows coming out of a Window operator, which appends one
+    /// value per window function to each row
+    pub fn with_windows(&self, windows: Vec<Expr>) -> Scope {
+        Scope {
+            windows,
+            subqueries: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Whether a plan for `query` has been attached
+    pub fn has_subquery(&self, query: &SelectStmt) -> bool {
+        self.subqueries.iter().any(|(q, _)| q == query)
+    }
+
+    /// Attach the plan that evaluates `query` within this scope
+    pub fn add_subquery(&mut self, query: &SelectStmt, plan: Box<dyn Operator>, correlated: bool) {
+        let subquery = Subquery {
+            plan,
+            correlated,
+            rows: None,
+        };
+        self.subqueries
+            .push((query.clone(), Rc::new(RefCell::new(subquery))));
+    }
+
+    /// Rows of a subquery for the outer row `row`
+    fn subquery_rows(
+        &self,
+        query: &SelectStmt,
+        row: &[SqlValue],
+        ctx: &mut ExecContext,
+    ) -> Result<Rc<Vec<Row>>, String> {
+        let (_, subquery) = self
+            .subqueries
+            .iter()
+            .find(|(q, _)| q == query)
+            .ok_or_else(|| format!("Subquery was not planned: {}", query))?;
+        let mut subquery = subquery.borrow_mut();
+        if let Some(rows) = &subquery.rows {
+            return Ok(Rc::clone(rows));
+        }
+
+        if !subquery.correlated {
+            let rows = Rc::new(collect_rows(subquery.plan.as_mut(), ctx)?);
+            subquery.rows = Some(Rc::clone(&rows));
+            return Ok(rows);
+        }
+        ctx.outer.push(row.to_vec());
+        let rows = collect_rows(subquery.plan.as_mut(), ctx);
+        ctx.outer.pop();
+        Ok(Rc::new(rows?))
+    }
+
+    /// Number of table columns in a row of this scope
+    pub fn width(&self) -> usize {
+        self.tables.iter().map(|(_, columns)| columns.len()).sum()
+    }
+
+    /// Position of an aggregate's value in a row of this scope
+    fn aggregate_position(&self, expr: &Expr) -> Option<usize> {
+        let slot = self.aggregates.iter().position(|a| a == expr)?;
+        Some(self.width() + slot)
+    }
+
+    /// Whether an aggregate's value is computed for rows of this scope
+    pub fn has_aggregate(&self, expr: &Expr) -> bool {
+        self.aggregates.contains(expr)
+    }
+
+    /// Position of a window function's value in a row of this scope
+    fn window_position(&self, expr: &Expr) -> Option<usize> {
+        let slot = self.windows.iter().position(|w| w == expr)?;
+        Some(self.width() + self.aggregates.len() + slot)
+    }
+
+    /// Position of a column in a row of this scope, if it has one
+    fn find(&self, col: &ColumnRef) -> Result<Option<usize>, String> {
+        let mut found = None;
+        let mut offset = 0;
+
+        for (binding, columns) in &self.tables {
+            let in_scope = col.table.as_ref().is_none_or(|t| t == binding);
+            if in_scope {
+                if let Some(pos) = columns.iter().position(|c| *c == col.column) {
+                    if found.is_some() {
+                        return Err(format!("Ambiguous column name '{}'", col));
+                    }
+                    found = Some(offset + pos);
+                }
+            }
+            offset += columns.len();
+        }
+        Ok(found)
+    }
+
+    /// Resolve a column reference to its position in a row of this scope
+    pub fn resolve(&self, col: &ColumnRef) -> Result<usize, String> {
+        self.find(col)?.ok_or_else(|| self.not_found(col))
+    }
+
+    fn not_found(&self, col: &ColumnRef) -> String {
+        match &col.table {
+            Some(t) if !self.tables.iter().any(|(binding, _)| binding == t) => {
+                format!("Unknown table '{}' in column '{}'", t, col)
+            }
+            _ => format!("Column '{}' not found", col),
+        }
+    }
+
+    /// Resolve a column here or, failing that, in the enclosing queries:
+    /// how many queries out it was found, and its position in their row
+    pub fn locate(&self, col: &ColumnRef) -> Result<(usize, usize), String> {
+        if let Some(pos) = self.find(col)? {
+            return Ok((0, pos));
+        }
+        match &self.outer {
+            Some(outer) => match outer.locate(col) {
+                Ok((depth, pos)) => Ok((depth + 1, pos)),
+                Err(_) => Err(self.not_found(col)),
+            },
+            None => Err(self.not_found(col)),
+        }
+    }
+
+    /// Verify every column referenced by an expression resolves, and that
+    /// aggregates and window functions only appear where their values have
+    /// been computed.
+    /// Subqueries are checked when they are planned.
+    pub fn check(&self, expr: &Expr) -> Result<(), String> {
+        match expr {
+            Expr::Column(col) => self.locate(col).map(|_| ()),
+            Expr::Aggregate { .. } => match self.aggregate_position(expr) {
+                Some(_) => Ok(()),
+                None => Err(format!("Misuse of aggregate function {}", expr)),
+            },
+            // A user-defined aggregate has been computed like any other
+            Expr::Function { .. } if self.has_aggregate(expr) => Ok(()),
+            Expr::Window { .. } => match self.window_position(expr) {
+                Some(_) => Ok(()),
+                None => Err(format!("Misuse of window function {}", expr)),
+            },
+            _ => expr
+                .operands()
+                .into_iter()
+                .try_for_each(|operand| self.check(operand)),
+        }
+    }
+
+    /// Headers and expressions for a select list. An empty list means every
+    /// table column, which needs no expressions and yields None.
+    pub fn projection(
+        &self,
+        items: &[SelectItem],
+        qualified: bool,
+    ) -> Result<(Vec<String>, Option<Vec<Expr>>), String> {
+        if items.is_empty() {
+            let mut headers = Vec::new();
+            for (binding, cols) in &self.tables {
+                for col in cols {
+                    if qualified {
+                        headers.push(format!("{}.{}", binding, col));
+                    } else {
+                        headers.push(col.clone());
+                    }
+                }
+            }
+            return Ok((headers, None));
+        }
+
+        let headers = items.iter().map(|item| item.header()).collect();
+        let exprs = items.iter().map(|item| item.expr.clone()).collect();
+        for item in items {
+            self.check(&item.expr)?;
+        }
+        Ok((headers, Some(exprs)))
+    }
+
+    /// Column references for every table column, in row order
+    pub fn all_columns(&self) -> Vec<Expr> {
+        self.tables
+            .iter()
+            .flat_map(|(binding, cols)| {
+                cols.iter().map(move |col| {
+                    Expr::Column(ColumnRef {
+                        table: Some(binding.clone()),
+                        column: col.clone(),
+                    })
+                })
+            })
+            .collect()
+    }
+}
+
+/// Evaluate an expression against a row laid out as described by `scope`
+pub fn evaluate(
+    expr: &Expr,
+    row: &[SqlValue],
+    scope: &Scope,
+    ctx: &mut ExecContext,
+) -> Result<SqlValue, String> {
+    match expr {
+        Expr::Column(col) => match scope.locate(col)? {
+            (0, pos) => Ok(row[pos].clone()),
+            // A correlated column: read it from the outer query's row
+            (depth, pos) => Ok(ctx.outer[ctx.outer.len() - depth][pos].clone()),
+        },
+        Expr::Literal(value) => Ok(SqlValue::from(value)),
+        Expr::Compare { left, op, right } => {
+            let left = evaluate(left, row, scope, ctx)?;
+            let right = evaluate(right, row, scope, ctx)?;
+            let result = match left.compare(&right) {
+                None => return Ok(SqlValue::Null),
+                Some(ord) => match op {
+                    CompareOp::Equals => ord.is_eq(),
+                    CompareOp::NotEquals => ord.is_ne(),
+                    CompareOp::LessThan => ord.is_lt(),
+                    CompareOp::GreaterThan => ord.is_gt(),
+                    CompareOp::LessEquals => ord.is_le(),
+                    CompareOp::GreaterEquals => ord.is_ge(),
+                },
+            };
+            Ok(SqlValue::Integer(result as i64))
+        }
+        Expr::Arithmetic { left, op, right } => {
+            let left = evaluate(left, row, scope, ctx)?;
+            left.arithmetic(*op, &evaluate(right, row, scope, ctx)?)
+        }
+        Expr::Negate(expr) => evaluate(expr, row, scope, ctx)?.negate(),
+        Expr::Logical { left, op, right } => {
+            let left = evaluate(left, row, scope, ctx)?.is_true();
+            let result = match op {
+                LogicalOp::And => left && evaluate(right, row, scope, ctx)?.is_true(),
+                LogicalOp::Or => left || evaluate(right, row, scope, ctx)?.is_true(),
+            };
+            Ok(SqlValue::Integer(result as i64))
+        }
+        Expr::IsNull { expr, negated } => {
+            let is_null = evaluate(expr, row, scope, ctx)? == SqlValue::Null;
+            Ok(SqlValue::Integer((is_null != *negated) as i64))
+        }
+        Expr::InList {
+            expr,
+            list,
+            negated,
+        } => {
+            let value = evaluate(expr, row, scope, ctx)?;
+            if value == SqlValue::Null {
+                return Ok(SqlValue::Null);
+            }
+            let mut saw_null = false;
+            for item in list {
+                match value.compare(&evaluate(item, row, scope, ctx)?) {
+                    Some(ord) if ord.is_eq() => return Ok(SqlValue::Integer(!*negated as i64)),
+                    Some(_) => {}
+                    None => saw_null = true,
+                }
+            }
+            // x IN (..., NULL) is unknown rather than false when nothing matched
+            if saw_null {
+                Ok(SqlValue::Null)
+            } else {
+                Ok(SqlValue::Integer(*negated as i64))
+            }
+        }
+        Expr::Between {
+            expr,
+            low,
+            high,
+            negated,
+        } => {
+            let value = evaluate(expr, row, scope, ctx)?;
+            let low = value.compare(&evaluate(low, row, scope, ctx)?);
+            let high = value.compare(&evaluate(high, row, scope, ctx)?);
+            match (low, high) {
+                (Some(low), Some(high)) => {
+                    let inside = low.is_ge() && high.is_le();
+                    Ok(SqlValue::Integer((inside != *negated) as i64))
+                }
+                _ => Ok(SqlValue::Null),
+            }
+        }
+        Expr::Like {
+            expr,
+            pattern,
+            negated,
+        } => {
+            let value = evaluate(expr, row, scope, ctx)?;
+            let pattern = evaluate(pattern, row, scope, ctx)?;
+            if value == SqlValue::Null || pattern == SqlValue::Null {
+                return Ok(SqlValue::Null);
+            }
+            let matched = like(&value.to_string(), &pattern.to_string());
+            Ok(SqlValue::Integer((matched != *negated) as i64))
+        }
+        Expr::Aggregate { .. } => match scope.aggregate_position(expr) {
+            Some(pos) => Ok(row[pos].clone()),
+            None => Err(format!("Misuse of aggregate function {}", expr)),
+        },
+        Expr::Case {
+            operand,
+            branches,
+            else_result,
+        } => {
+            let operand = match operand {
+                Some(operand) => Some(evaluate(operand, row, scope, ctx)?),
+                None => None,
+            };
+            for (when, then) in branches {
+                let when = evaluate(when, row, scope, ctx)?;
+                // A NULL operand or WHEN value never matches
+                let matched = match &operand {
+                    Some(value) => value.compare(&when).is_some_and(|ord| ord.is_eq()),
+                    None => when.is_true(),
+                };
+                if matched {
+                    return evaluate(then, row, scope, ctx);
+                }
+            }
+            match else_result {
+                Some(else_result) => evaluate(else_result, row, scope, ctx),
+                None => Ok(SqlValue::Null),
+            }
+        }
+        Expr::Function { name, args } => match scope.aggregate_position(expr) {
+            Some(pos) => Ok(row[pos].clone()),
+            None => call_function(name, args, row, scope, ctx),
+        },
+        Expr::Window { .. } => match scope.window_position(expr) {
+            Some(pos) => Ok(row[pos].clone()),
+            None => Err(format!("Misuse of window function {}", expr)),
+        },
+        Expr::Subquery(query) => {
+            let rows = scope.subquery_rows(query, row, ctx)?;
+            Ok(rows
+                .first()
+                .map_or(SqlValue::Null, |first| first[0].clone()))
+        }
+        Expr::InSubquery {
+            expr,
+            query,
+            negated,
+        } => {
+            let value = evaluate(expr, row, scope, ctx)?;
+            if value == SqlValue::Null {
+                return Ok(SqlValue::Null);
+            }
+            let rows = scope.subquery_rows(query, row, ctx)?;
+            let mut saw_null = false;
+            for candidate in rows.iter() {
+                match value.compare(&candidate[0]) {
+                    Some(ord) if ord.is_eq() => return Ok(SqlValue::Integer(!*negated as i64)),
+                    Some(_) => {}
+                    None => saw_null = true,
+                }
+            }
+            if saw_null {
+                Ok(SqlValue::Null)
+            } else {
+                Ok(SqlValue::Integer(*negated as i64))
+            }
+        }
+        Expr::Exists { query, negated } => {
+            let rows = scope.subquery_rows(query, row, ctx)?;
+            Ok(SqlValue::Integer((rows.is_empty() == *negated) as i64))
+        }
+    }
+}
+
+/// Functions evaluated here rather than through the registry, since they
+/// only evaluate the arguments they need
+pub const CONDITIONAL_FUNCTIONS: [&str; 4] = ["COALESCE", "IFNULL", "NULLIF", "IIF"];
+
+/// Evaluate a scalar function call. The conditional functions only
+/// evaluate the arguments they need.
+fn call_function(
+    name: &str,
+    args: &[Expr],
+    row: &[SqlValue],
+    scope: &Scope,
+    ctx: &mut ExecContext,
+) -> Result<SqlValue, String> {
+    match name {
+        // The first argument that isn't NULL
+        "COALESCE" | "IFNULL" => {
+            for arg in args {
+                let value = evaluate(arg, row, scope, ctx)?;
+                if value != SqlValue::Null {
+                    return Ok(value);
+                }
+            }
+            Ok(SqlValue::Null)
+        }
+        // NULL when both arguments are equal, otherwise the first
+        "NULLIF" => {
+            let value = evaluate(&args[0], row, scope, ctx)?;
+            let other = evaluate(&args[1], row, scope, ctx)?;
+            match value.compare(&other) {
+                Some(ord) if ord.is_eq() => Ok(SqlValue::Null),
+                _ => Ok(value),
+            }
+        }
+        "IIF" => match evaluate(&args[0], row, scope, ctx)?.is_true() {
+            true => evaluate(&args[1], row, scope, ctx),
+            false => evaluate(&args[2], row, scope, ctx),
+        },
+        _ => {
+            let function = ctx.functions.resolve(name, args.len())?.clone();
+            let values = args
+                .iter()
+                .map(|arg| evaluate(arg, row, scope, ctx))
+                .collect::<Result<Vec<_>, _>>()?;
+            function(&values)
+        }
+    }
+}
+
+/// Whether `text` matches a LIKE pattern: `%` matches any run of
+/// characters, `_` any single one, and every other character itself
+fn like(text: &str, pattern: &str) -> bool {
+    let text: Vec<char> = text.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+    let (mut t, mut p) = (0, 0);
+    // The last `%` seen, and where in the text its run currently ends
+    let mut percent: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('%') => {
+                percent = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '_' || c == text[t] => {
+                t += 1;
+                p += 1;
+            }
+            // Let the last `%` take one more character and try again
+            _ => match percent {
+                Some((percent_p, percent_t)) => {
+                    percent = Some((percent_p, percent_t + 1));
+                    p = percent_p + 1;
+                    t = percent_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '%')
+}
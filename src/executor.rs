@@ -1,18 +1,47 @@
 //! SQL Query Executor - Executes parsed SQL statements
 
-use crate::btree::*;
+use crate::eval::{evaluate, Scope, CONDITIONAL_FUNCTIONS};
+use crate::functions::FunctionRegistry;
 use crate::index::Index;
+use crate::operator::*;
+use crate::optimizer::*;
+use crate::pager::is_outdated_file;
 use crate::parser::*;
 use crate::table::{DataType, Table};
-use std::collections::HashMap;
+use crate::value::SqlValue;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
-use std::ptr;
+use std::rc::Rc;
 
 pub struct Executor {
     pub tables: HashMap<String, Table>,
     pub in_transaction: bool,
     pub current_db: Option<String>,
     pub db_base_path: PathBuf,
+    /// Statistics gathered by ANALYZE, keyed by table name
+    pub stats: HashMap<String, TableStats>,
+    /// Common table expressions of the statement being run, by name
+    ctes: HashMap<String, CommonTable>,
+    /// Scalar functions SQL can call
+    pub functions: FunctionRegistry,
+}
+
+/// The rows of a common table expression, computed before the statement
+/// that uses it runs
+#[derive(Clone)]
+struct CommonTable {
+    columns: Vec<String>,
+    rows: Rc<Vec<Row>>,
+}
+
+/// Rounds a recursive common table expression may take before it is
+/// assumed never to finish
+const MAX_RECURSION_ROUNDS: usize = 10_000;
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Executor {
@@ -30,9 +59,42 @@ impl Executor {
             in_transaction: false,
             current_db: None,
             db_base_path,
+            stats: HashMap::new(),
+            ctes: HashMap::new(),
+            functions: FunctionRegistry::new(),
         }
     }
 
+    /// Make a scalar function callable from SQL, replacing any function of
+    /// the same name and arity. `arity` None accepts any number of
+    /// arguments. The closure gets the evaluated arguments.
+    pub fn register_scalar_function(
+        &mut self,
+        name: &str,
+        arity: Option<usize>,
+        function: impl Fn(&[SqlValue]) -> Result<SqlValue, String> + 'static,
+    ) -> Result<(), String> {
+        check_function_name(name)?;
+        self.functions.register(name, arity, function);
+        Ok(())
+    }
+
+    /// Make an aggregate callable from SQL, like COUNT or SUM. Each group
+    /// starts from `A::default()`; `step` adds one row's arguments to it and
+    /// `finalize` gives the group's value.
+    pub fn register_aggregate_function<A: Default + 'static>(
+        &mut self,
+        name: &str,
+        arity: Option<usize>,
+        step: impl Fn(&mut A, &[SqlValue]) -> Result<(), String> + 'static,
+        finalize: impl Fn(&A) -> Result<SqlValue, String> + 'static,
+    ) -> Result<(), String> {
+        check_function_name(name)?;
+        self.functions
+            .register_aggregate(name, arity, step, finalize);
+        Ok(())
+    }
+
     /// Get the current database path, if connected
     pub fn get_db_path(&self) -> Option<PathBuf> {
         self.current_db
@@ -48,12 +110,24 @@ impl Executor {
 
     pub fn execute(&mut self, stmt: Statement) -> Result<ExecuteResult, String> {
         match stmt {
+            // ROLLBACK only discards the pages of files that already exist
+            Statement::CreateTable(_)
+            | Statement::CreateIndex(_)
+            | Statement::DropTable(_)
+            | Statement::DropIndex(_)
+                if self.in_transaction =>
+            {
+                Err(
+                    "Tables and indexes can't be created or dropped inside a transaction"
+                        .to_string(),
+                )
+            }
             Statement::CreateDatabase(name) => self.execute_create_database(name),
             Statement::Connect(name) => self.execute_connect(name),
             Statement::CreateTable(create) => self.execute_create(create),
             Statement::CreateIndex(create_idx) => self.execute_create_index(create_idx),
             Statement::Insert(insert) => self.execute_insert(insert),
-            Statement::Select(select) => self.execute_select(select),
+            Statement::Select(select) => self.execute_select(*select),
             Statement::Delete(delete) => self.execute_delete(delete),
             Statement::Update(update) => self.execute_update(update),
             Statement::DropTable(name) => self.execute_drop(name),
@@ -61,7 +135,123 @@ impl Executor {
             Statement::Begin => self.execute_begin(),
             Statement::Commit => self.execute_commit(),
             Statement::Rollback => self.execute_rollback(),
+            Statement::Analyze(name) => self.execute_analyze(name),
+            Statement::Explain { analyze, statement } => self.execute_explain(analyze, *statement),
+            Statement::With { with, statement } => {
+                self.with_ctes(&with, |executor| executor.execute(*statement))
+            }
+        }
+    }
+
+    /// Run `f` with the common table expressions of `with` in scope. Each
+    /// is materialized in turn, so later ones can read earlier ones.
+    fn with_ctes<T>(
+        &mut self,
+        with: &WithClause,
+        f: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let enclosing = self.ctes.clone();
+        let mut result = Ok(());
+        for cte in &with.ctes {
+            result = self.materialize(cte, with.recursive);
+            if result.is_err() {
+                break;
+            }
+        }
+        let result = result.and_then(|_| f(self));
+        self.ctes = enclosing;
+        result
+    }
+
+    /// Compute the rows of a common table expression. Under WITH RECURSIVE
+    /// a query whose last block follows UNION [ALL] and reads the table
+    /// itself is recursive: the blocks before it give the first rows, then
+    /// the last block runs against the rows the previous round added until
+    /// a round adds none.
+    fn materialize(&mut self, cte: &Cte, recursive: bool) -> Result<(), String> {
+        let step = match cte.query.compound.last() {
+            Some((operator @ (SetOperator::Union | SetOperator::UnionAll), step))
+                if recursive && reads_table(step, &cte.name) =>
+            {
+                Some((*operator, step))
+            }
+            _ => None,
+        };
+        let mut query = cte.query.clone();
+        if step.is_some() {
+            if !query.order_by.is_empty() || query.limit.is_some() || query.offset.is_some() {
+                return Err(format!(
+                    "ORDER BY and LIMIT are not supported in recursive table '{}'",
+                    cte.name
+                ));
+            }
+            query.compound.pop();
+        }
+
+        let (headers, plan) = self.plan_select(&query, None)?;
+        let columns = match &cte.columns {
+            Some(columns) if columns.len() != headers.len() => {
+                return Err(format!(
+                    "'{}' names {} columns but its query returns {}",
+                    cte.name,
+                    columns.len(),
+                    headers.len()
+                ))
+            }
+            Some(columns) => columns.clone(),
+            None => self.output_columns(&query)?,
+        };
+        let mut rows = self.run_plan(plan)?;
+
+        if let Some((operator, step)) = step {
+            // UNION keeps one copy of each row, UNION ALL keeps them all
+            let distinct = operator == SetOperator::Union;
+            let mut seen = HashSet::new();
+            if distinct {
+                rows.retain(|row| seen.insert(row_key(row)));
+            }
+
+            let mut added = rows.clone();
+            for round in 0.. {
+                if round == MAX_RECURSION_ROUNDS {
+                    return Err(format!(
+                        "Recursive table '{}' did not finish after {} rounds",
+                        cte.name, MAX_RECURSION_ROUNDS
+                    ));
+                }
+                let previous = CommonTable {
+                    columns: columns.clone(),
+                    rows: Rc::new(added),
+                };
+                self.ctes.insert(cte.name.clone(), previous);
+
+                let (headers, plan) = self.plan_select(step, None)?;
+                if headers.len() != columns.len() {
+                    return Err(column_count_mismatch(operator));
+                }
+                added = self.run_plan(plan)?;
+                if distinct {
+                    added.retain(|row| seen.insert(row_key(row)));
+                }
+                if added.is_empty() {
+                    break;
+                }
+                rows.extend(added.iter().cloned());
+            }
         }
+
+        let table = CommonTable {
+            columns,
+            rows: Rc::new(rows),
+        };
+        self.ctes.insert(cte.name.clone(), table);
+        Ok(())
+    }
+
+    /// Run a plan to completion and collect its rows
+    fn run_plan(&mut self, mut plan: Box<dyn Operator>) -> Result<Vec<Row>, String> {
+        let mut ctx = ExecContext::new(&mut self.tables, &self.functions);
+        collect_rows(plan.as_mut(), &mut ctx)
     }
 
     fn execute_create_database(&mut self, name: String) -> Result<ExecuteResult, String> {
@@ -98,6 +288,7 @@ impl Executor {
 
         // Load metadata and restore tables
         self.load_metadata()?;
+        self.stats = load_stats(&db_path);
 
         Ok(ExecuteResult::DatabaseConnected(name))
     }
@@ -180,88 +371,80 @@ impl Executor {
             .map_err(|e| format!("Failed to read metadata: {}", e))?;
 
         // Simple JSON parsing (avoiding external dependencies)
-        // Format: {"tables":{"tablename":{"columns":[{"name":"col","type":"INTEGER"}],"indexes":[]}}}
-
-        // Extract table entries
-        if let Some(tables_start) = content.find("\"tables\":{") {
-            let tables_content = &content[tables_start + 10..];
-
-            // Parse each table
-            let mut pos = 0;
-            while let Some(name_start) = tables_content[pos..].find('"') {
-                let actual_start = pos + name_start + 1;
-                if let Some(name_end) = tables_content[actual_start..].find('"') {
-                    let table_name = &tables_content[actual_start..actual_start + name_end];
-
-                    if table_name == "}" || table_name.is_empty() {
-                        break;
-                    }
-
-                    // Find columns array
-                    if let Some(cols_start) = tables_content[actual_start..].find("\"columns\":[") {
-                        let cols_section = &tables_content[actual_start + cols_start..];
-
-                        // Parse columns
-                        let mut columns: Vec<(&str, DataType)> = Vec::new();
-                        let mut col_pos = 11; // After "columns":[
-
-                        while let Some(col_start) = cols_section[col_pos..].find("{\"name\":\"") {
-                            let cn_start = col_pos + col_start + 9;
-                            if let Some(cn_end) = cols_section[cn_start..].find('"') {
-                                let col_name = &cols_section[cn_start..cn_start + cn_end];
-
-                                // Find type
-                                let type_start = cn_start + cn_end;
-                                if let Some(t_start) = cols_section[type_start..].find("\"type\":")
-                                {
-                                    let t_section = &cols_section[type_start + t_start + 7..];
-
-                                    let data_type = if t_section.starts_with("\"INTEGER\"") {
-                                        DataType::Integer
-                                    } else if t_section.starts_with("\"TEXT(") {
-                                        // Extract size
-                                        if let Some(size_end) = t_section[6..].find(')') {
-                                            let size: u32 =
-                                                t_section[6..6 + size_end].parse().unwrap_or(255);
-                                            DataType::Text(size)
-                                        } else {
-                                            DataType::Text(255)
-                                        }
-                                    } else {
-                                        DataType::Text(255)
-                                    };
-
-                                    columns.push((
-                                        Box::leak(col_name.to_string().into_boxed_str()),
-                                        data_type,
-                                    ));
-                                }
-                            }
-                            col_pos = cn_start + 1;
-
-                            // Check if we've reached the end of columns array
-                            if cols_section[col_pos..].starts_with(']') {
-                                break;
-                            }
-                        }
+        // Format: {"tables":{"t":{"columns":[{"name":"id","type":"INTEGER"}],
+        //          "indexes":[{"name":"i","column":"id","unique":false}]}}}
+        let Some(tables_start) = content.find("\"tables\":{") else {
+            return Ok(());
+        };
+        let mut rest = &content[tables_start + 10..];
 
-                        if !columns.is_empty() {
-                            // Create table from stored data
-                            let table_file = db_path.join(format!("{}.db", table_name));
-                            let table = Table::new(table_file.to_str().unwrap(), columns);
-                            self.tables.insert(table_name.to_string(), table);
-                        }
-                    }
+        // Each table is `"name":{...}`; its object runs to the matching brace
+        while let Some(name_start) = rest.find('"') {
+            let after_name = &rest[name_start + 1..];
+            let Some(name_end) = after_name.find('"') else {
+                break;
+            };
+            let table_name = &after_name[..name_end];
+            let Some(body_start) = after_name.find('{') else {
+                break;
+            };
+            let body = &after_name[body_start..];
+            let body_end = matching_bracket(body)
+                .ok_or_else(|| format!("Malformed metadata for table '{}'", table_name))?;
+            let body = &body[..body_end];
+            rest = &after_name[body_start + body_end..];
+
+            let mut columns: Vec<(String, DataType)> = Vec::new();
+            for column in json_objects(body, "columns") {
+                let (Some(name), Some(type_str)) =
+                    (json_field(column, "name"), json_field(column, "type"))
+                else {
+                    continue;
+                };
+                let data_type = match type_str.strip_prefix("TEXT(") {
+                    _ if type_str == "INTEGER" => DataType::Integer,
+                    Some(size) => DataType::Text(size.trim_end_matches(')').parse().unwrap_or(255)),
+                    None => DataType::Text(255),
+                };
+                columns.push((name.to_string(), data_type));
+            }
+            if columns.is_empty() {
+                continue;
+            }
 
-                    // Move to next table
-                    pos = actual_start + name_end + 1;
-                    if let Some(next) = tables_content[pos..].find('}') {
-                        pos += next + 1;
-                    }
-                } else {
-                    break;
+            // Create table from stored data
+            let table_file = db_path.join(format!("{}.db", table_name));
+            let raw_cols = columns
+                .iter()
+                .map(|(n, t)| (n.as_str(), t.clone()))
+                .collect();
+            let mut table = Table::new(table_file.to_str().unwrap(), raw_cols)?;
+
+            // Reopen its indexes where CREATE INDEX left them
+            for entry in json_objects(body, "indexes") {
+                let (Some(name), Some(column)) =
+                    (json_field(entry, "name"), json_field(entry, "column"))
+                else {
+                    continue;
+                };
+                let unique = json_field(entry, "unique") == Some("true");
+                let index_file = db_path.join(format!("{}_{}.idx", table_name, name));
+                let index_file = index_file.to_str().unwrap();
+                // Indexes from before the file header, or from before index
+                // keys were typed, kept their entries in another order, so
+                // they are built again from the table, as is a missing one
+                if is_outdated_file(index_file) {
+                    let _ = std::fs::remove_file(index_file);
                 }
+                let missing = std::fs::metadata(index_file).map_or(true, |meta| meta.len() == 0);
+                let mut index = Index::new(index_file, name, table_name, column, unique)?;
+                if missing {
+                    populate_index(&mut table, &mut index)?;
+                }
+                table.indexes.insert(name.to_string(), index);
             }
+
+            self.tables.insert(table_name.to_string(), table);
         }
 
         Ok(())
@@ -272,9 +455,12 @@ impl Executor {
             return Err("Transaction already in progress".to_string());
         }
         self.in_transaction = true;
-        // Enable deferred flushing on all tables
+        // Enable deferred flushing on all tables and their indexes
         for table in self.tables.values_mut() {
-            table.defer_flush = true;
+            table.tree.defer_flush = true;
+            for index in table.indexes.values_mut() {
+                index.tree.defer_flush = true;
+            }
         }
         Ok(ExecuteResult::TransactionStarted)
     }
@@ -285,8 +471,12 @@ impl Executor {
         }
         // Flush all pages to disk and disable deferred flushing
         for table in self.tables.values_mut() {
-            table.pager.flush_all();
-            table.defer_flush = false;
+            table.tree.flush_all();
+            table.tree.defer_flush = false;
+            for index in table.indexes.values_mut() {
+                index.tree.flush_all();
+                index.tree.defer_flush = false;
+            }
         }
         self.in_transaction = false;
         Ok(ExecuteResult::TransactionCommitted)
@@ -298,15 +488,12 @@ impl Executor {
         }
         // Discard in-memory pages by clearing and reloading from disk
         for table in self.tables.values_mut() {
-            // Clear all cached pages
-            for i in 0..table.pager.pages.len() {
-                table.pager.pages[i] = None;
+            table.tree.discard_changes();
+            table.tree.defer_flush = false;
+            for index in table.indexes.values_mut() {
+                index.tree.discard_changes();
+                index.tree.defer_flush = false;
             }
-            // Reset num_pages to what's actually on disk
-            let file_len = table.pager.file.metadata().map(|m| m.len()).unwrap_or(0);
-            table.pager.num_pages = (file_len / crate::pager::PAGE_SIZE as u64) as u32;
-            table.pager.file_length = file_len;
-            table.defer_flush = false;
         }
         self.in_transaction = false;
         Ok(ExecuteResult::TransactionRolledBack)
@@ -334,7 +521,7 @@ impl Executor {
         let filename = db_path.join(format!("{}.db", stmt.table_name));
         let _ = std::fs::remove_file(&filename);
 
-        let table = Table::new(filename.to_str().unwrap(), raw_cols);
+        let table = Table::new(filename.to_str().unwrap(), raw_cols)?;
         let table_name = stmt.table_name.clone();
         self.tables.insert(stmt.table_name, table);
 
@@ -345,415 +532,1125 @@ impl Executor {
     }
 
     fn execute_insert(&mut self, stmt: InsertStmt) -> Result<ExecuteResult, String> {
+        let returning = self.plan_returning(&stmt.table_name, stmt.returning.as_deref())?;
+
+        let rows = match &stmt.select {
+            None => vec![stmt.values.iter().map(SqlValue::from).collect()],
+            Some(query) => {
+                let (_, mut plan) = self.plan_select(query, None)?;
+                let mut ctx = ExecContext::new(&mut self.tables, &self.functions);
+                collect_rows(plan.as_mut(), &mut ctx)?
+            }
+        };
         let table = self
             .tables
-            .get_mut(&stmt.table_name)
+            .get(&stmt.table_name)
             .ok_or_else(|| format!("Table '{}' not found", stmt.table_name))?;
+        let positions = insert_positions(table, stmt.columns.as_deref())?;
+        let rows = rows
+            .into_iter()
+            .map(|row| {
+                if row.len() != positions.len() {
+                    return Err(format!(
+                        "Table '{}' expects {} value(s), got {}",
+                        stmt.table_name,
+                        positions.len(),
+                        row.len()
+                    ));
+                }
+                let mut values = vec![SqlValue::Null; table.columns.len()];
+                for (value, &pos) in row.into_iter().zip(&positions) {
+                    values[pos] = value;
+                }
+                Ok(values)
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let insert_all = |executor: &mut Self| {
+            let table = executor
+                .tables
+                .get_mut(&stmt.table_name)
+                .expect("table checked above");
+            rows.iter()
+                .map(|values| insert_row(table, values))
+                .collect::<Result<Vec<_>, String>>()
+        };
+        // A row that fails part way through an INSERT ... SELECT takes the
+        // rows before it with it
+        let inserted = match stmt.select {
+            Some(_) => self.atomically(insert_all)?,
+            None => insert_all(self)?,
+        };
+        let count = inserted.len();
+        self.returning(returning, inserted, ExecuteResult::RowsInserted(count))
+    }
 
-        if stmt.values.is_empty() {
-            return Err("No values provided".to_string());
+    /// Run `f` as a transaction of its own unless one is already open, so
+    /// that an error leaves none of its writes behind
+    fn atomically<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<T, String> {
+        if self.in_transaction {
+            return f(self);
         }
-
-        let id = match &stmt.values[0] {
-            Value::Integer(n) => *n as u32,
-            Value::Text(s) => s.parse::<u32>().map_err(|_| "Invalid ID")?,
-            Value::Identifier(s) => s.parse::<u32>().map_err(|_| "Invalid ID")?,
+        self.execute_begin()?;
+        let result = f(self);
+        match result {
+            Ok(_) => self.execute_commit()?,
+            Err(_) => self.execute_rollback()?,
         };
+        result
+    }
 
-        let mut row_data = vec![0u8; table.row_size];
+    fn execute_select(&mut self, stmt: SelectStmt) -> Result<ExecuteResult, String> {
+        let (headers, mut plan) = self.plan_select(&stmt, None)?;
 
-        // Build column info for value extraction
-        let col_info: Vec<(String, usize, usize)> = table
-            .columns
-            .iter()
-            .map(|c| (c.name.clone(), c.size, c.offset))
+        let mut ctx = ExecContext::new(&mut self.tables, &self.functions);
+        let rows = collect_rows(plan.as_mut(), &mut ctx)?
+            .into_iter()
+            .map(|row| row.iter().map(|v| v.to_string()).collect())
             .collect();
 
-        for (i, col) in table.columns.iter().enumerate() {
-            if col.name == "id" || i == 0 {
-                continue;
-            }
+        Ok(ExecuteResult::Rows { headers, rows })
+    }
 
-            let value_idx = i;
-            if let Some(value) = stmt.values.get(value_idx) {
-                let bytes = match value {
-                    Value::Integer(n) => n.to_string().into_bytes(),
-                    Value::Text(s) => s.as_bytes().to_vec(),
-                    Value::Identifier(s) => s.as_bytes().to_vec(),
-                };
-                let copy_len = bytes.len().min(col.size);
-                row_data[col.offset..col.offset + copy_len].copy_from_slice(&bytes[..copy_len]);
+    /// Describe the plan a statement would run, one row per operator with
+    /// its inputs indented beneath it. EXPLAIN ANALYZE also runs the query
+    /// and reports the rows and time each operator actually took.
+    fn execute_explain(
+        &mut self,
+        analyze: bool,
+        statement: Statement,
+    ) -> Result<ExecuteResult, String> {
+        let mut plan = match statement {
+            Statement::With { with, statement } => {
+                return self.with_ctes(&with, |executor| {
+                    executor.execute_explain(analyze, *statement)
+                })
+            }
+            Statement::Select(select) => self.plan_select(&select, None)?.1,
+            _ if analyze => return Err("EXPLAIN ANALYZE only supports SELECT".to_string()),
+            Statement::Update(update) => {
+                self.plan_matching(&update.table_name, update.where_clause.as_ref())?
             }
+            Statement::Delete(delete) => {
+                self.plan_matching(&delete.table_name, delete.where_clause.as_ref())?
+            }
+            _ => return Err("EXPLAIN expects SELECT, UPDATE or DELETE".to_string()),
+        };
+
+        let mut headers = vec![
+            "operator".to_string(),
+            "detail".to_string(),
+            "estimated rows".to_string(),
+        ];
+        if analyze {
+            headers.push("actual rows".to_string());
+            headers.push("time (ms)".to_string());
+
+            let mut ctx = ExecContext::new(&mut self.tables, &self.functions);
+            collect_rows(plan.as_mut(), &mut ctx)?;
         }
 
-        // Check UNIQUE constraints on all indexes BEFORE inserting
-        for index in table.indexes.values_mut() {
-            if index.unique {
-                // Get the value for this indexed column
-                let col_value = if let Some((_, size, offset)) = col_info
-                    .iter()
-                    .find(|(name, _, _)| *name == index.column_name)
-                {
-                    let data = &row_data[*offset..*offset + *size];
-                    String::from_utf8_lossy(data)
-                        .trim_matches(char::from(0))
-                        .to_string()
-                } else {
-                    continue;
-                };
+        let mut rows = Vec::new();
+        explain_rows(plan.as_ref(), 0, analyze, &mut rows);
+        Ok(ExecuteResult::Rows { headers, rows })
+    }
 
-                // Check if value already exists in index
-                let existing = index.find(&col_value);
-                if !existing.is_empty() {
-                    return Err(format!(
-                        "UNIQUE constraint failed: column '{}' value '{}' already exists",
-                        index.column_name, col_value
-                    ));
-                }
+    fn get_table(&self, name: &str) -> Result<&Table, String> {
+        self.tables
+            .get(name)
+            .ok_or_else(|| format!("Table '{}' not found", name))
+    }
+
+    /// Column names of a FROM or JOIN item
+    fn source_columns(&self, table_ref: &TableRef) -> Result<Vec<String>, String> {
+        if let Some(cte) = self.common_table(table_ref) {
+            return Ok(cte.columns.clone());
+        }
+        match &table_ref.subquery {
+            Some(query) => self.output_columns(query),
+            None => Ok(self
+                .get_table(&table_ref.name)?
+                .columns
+                .iter()
+                .map(|c| c.name.clone())
+                .collect()),
+        }
+    }
+
+    /// Names of the columns a SELECT returns, as a derived table exposes
+    /// them: the alias, the bare column name, or the expression as written
+    fn output_columns(&self, stmt: &SelectStmt) -> Result<Vec<String>, String> {
+        if stmt.columns.is_empty() {
+            let mut columns = Vec::new();
+            for table_ref in std::iter::once(&stmt.from).chain(stmt.joins.iter().map(|j| &j.table))
+            {
+                columns.extend(self.source_columns(table_ref)?);
             }
+            return Ok(columns);
         }
+        Ok(stmt
+            .columns
+            .iter()
+            .map(|item| match (&item.alias, &item.expr) {
+                (Some(alias), _) => alias.clone(),
+                (None, Expr::Column(col)) => col.column.clone(),
+                (None, expr) => expr.to_string(),
+            })
+            .collect())
+    }
 
-        // Insert into main table
-        table.insert(id, &row_data)?;
+    /// A scope holding just one FROM or JOIN item
+    fn source_scope(&self, table_ref: &TableRef, outer: Option<&Scope>) -> Result<Scope, String> {
+        let mut scope = Scope::nested(outer);
+        scope.add_columns(table_ref.binding(), self.source_columns(table_ref)?);
+        Ok(scope)
+    }
 
-        // Update all indexes with the new row
-        for index in table.indexes.values_mut() {
-            let col_value = if let Some((_, size, offset)) = col_info
-                .iter()
-                .find(|(name, _, _)| *name == index.column_name)
-            {
-                let data = &row_data[*offset..*offset + *size];
-                String::from_utf8_lossy(data)
-                    .trim_matches(char::from(0))
-                    .to_string()
-            } else {
+    /// The common table expression a FROM or JOIN item names, if any; it
+    /// hides a table of the same name
+    fn common_table(&self, table_ref: &TableRef) -> Option<&CommonTable> {
+        match table_ref.subquery {
+            Some(_) => None,
+            None => self.ctes.get(&table_ref.name),
+        }
+    }
+
+    /// Cost model of a FROM or JOIN item
+    fn cost_model(&self, table_ref: &TableRef) -> Result<CostModel<'_>, String> {
+        if let Some(cte) = self.common_table(table_ref) {
+            return Ok(CostModel::derived(Some(cte.rows.len() as f64)));
+        }
+        match &table_ref.subquery {
+            Some(query) => {
+                let (_, plan) = self.plan_select(query, None)?;
+                Ok(CostModel::derived(plan.estimated_rows()))
+            }
+            None => Ok(CostModel::new(
+                self.get_table(&table_ref.name)?,
+                self.stats.get(&table_ref.name),
+            )),
+        }
+    }
+
+    /// Check an expression against the scope it will be evaluated in and
+    /// plan its subqueries into that scope. A subquery is first planned on
+    /// its own, to run once; if it refers to the outer query it is planned
+    /// with `scope` around it instead, to rerun for every outer row.
+    fn bind(&self, expr: &Expr, scope: &mut Scope) -> Result<(), String> {
+        scope.check(expr)?;
+
+        let mut calls = Vec::new();
+        function_calls(expr, &mut calls);
+        for call in calls {
+            let Expr::Function { name, args } = call else {
                 continue;
             };
-
-            // Insert into index (ignore errors since we already validated uniqueness)
-            let _ = index.insert(&col_value, id);
+            if CONDITIONAL_FUNCTIONS.contains(&name.as_str()) || scope.has_aggregate(call) {
+                continue;
+            }
+            if self.functions.aggregate(name, args.len()).is_some() {
+                return Err(format!("Misuse of aggregate function {}", call));
+            }
+            self.functions.resolve(name, args.len())?;
         }
 
-        Ok(ExecuteResult::RowsInserted(1))
+        let mut queries = Vec::new();
+        subqueries(expr, &mut queries);
+        for (query, single_column) in queries {
+            if scope.has_subquery(query) {
+                continue;
+            }
+            let (headers, plan, correlated) = match self.plan_select(query, None) {
+                Ok((headers, plan)) => (headers, plan, false),
+                Err(_) => {
+                    let (headers, plan) = self.plan_select(query, Some(scope))?;
+                    (headers, plan, true)
+                }
+            };
+            if single_column && headers.len() != 1 {
+                return Err(format!(
+                    "Subquery returns {} columns - expected 1",
+                    headers.len()
+                ));
+            }
+            scope.add_subquery(query, plan, correlated);
+        }
+        Ok(())
     }
 
-    fn execute_select(&mut self, stmt: SelectStmt) -> Result<ExecuteResult, String> {
-        // Check if we have joins
-        if !stmt.joins.is_empty() {
-            return self.execute_select_with_join(stmt);
-        }
+    /// Whether an expression can be evaluated in `scope`
+    fn check(&self, expr: &Expr, scope: &Scope) -> Result<(), String> {
+        self.bind(expr, &mut scope.clone())
+    }
 
-        // Original single-table SELECT
-        let table = self
-            .tables
-            .get_mut(&stmt.table_name)
-            .ok_or_else(|| format!("Table '{}' not found", stmt.table_name))?;
+    /// The conditions among `pending` that `scope` can evaluate
+    fn applicable<'a>(&self, pending: &[&'a Expr], scope: &Scope) -> Vec<&'a Expr> {
+        pending
+            .iter()
+            .copied()
+            .filter(|c| self.check(c, scope).is_ok())
+            .collect()
+    }
 
-        let all_rows = table.select_all();
+    /// The ORDER BY of a query over a single table, if it is on one of the
+    /// table's columns and the rows reach the sort as the table produces
+    /// them: no grouping, aggregates or window functions in between
+    fn column_order(
+        &self,
+        stmt: &SelectStmt,
+        outer: Option<&Scope>,
+    ) -> Result<Option<ColumnOrder>, String> {
+        if !stmt.joins.is_empty() || !stmt.group_by.is_empty() {
+            return Ok(None);
+        }
+        let order_by = order_by_exprs(stmt)?;
+        let [(Expr::Column(col), descending)] = order_by.as_slice() else {
+            return Ok(None);
+        };
 
-        let col_info: Vec<(String, usize, usize)> = table
+        let mut aggregates = Vec::new();
+        let mut windows = Vec::new();
+        for expr in stmt
             .columns
             .iter()
-            .map(|c| (c.name.clone(), c.size, c.offset))
-            .collect();
+            .map(|item| &item.expr)
+            .chain(order_by.iter().map(|(expr, _)| expr))
+        {
+            collect_aggregates(expr, &self.functions, &mut aggregates);
+            collect_windows(expr, &mut windows);
+        }
+        if !aggregates.is_empty() || !windows.is_empty() {
+            return Ok(None);
+        }
 
-        let pk_col_name = col_info
-            .first()
-            .map(|(name, _, _)| name.clone())
-            .unwrap_or_default();
+        let scope = self.source_scope(&stmt.from, outer)?;
+        Ok(scope.resolve(col).ok().map(|column| ColumnOrder {
+            column,
+            descending: *descending,
+            limited: stmt.limit.is_some(),
+        }))
+    }
 
-        let select_cols: Vec<String> = if stmt.columns.is_empty() {
-            col_info.iter().map(|(name, _, _)| name.clone()).collect()
+    /// Build the operator tree for a SELECT: scan and join the tables,
+    /// filter, aggregate, sort, limit and finally project the select list.
+    /// A subquery is planned with the scope of the query around it, `outer`.
+    fn plan_select(
+        &self,
+        stmt: &SelectStmt,
+        outer: Option<&Scope>,
+    ) -> Result<(Vec<String>, Box<dyn Operator>), String> {
+        if !stmt.compound.is_empty() {
+            return self.plan_compound(stmt, outer);
+        }
+
+        // Inner joins may run in any order; outer joins run as written
+        let inner_only = stmt
+            .joins
+            .iter()
+            .all(|join| matches!(join.join_type, JoinType::Inner | JoinType::Cross));
+        let order = self.column_order(stmt, outer)?;
+        let (mut plan, mut scope, sorted) = if inner_only {
+            self.plan_inner_joins(stmt, outer, order.as_ref())?
         } else {
-            stmt.columns.clone()
+            let (plan, scope) = self.plan_outer_joins(stmt, outer)?;
+            (plan, scope, false)
         };
 
-        let headers: Vec<String> = select_cols.clone();
-        let mut results: Vec<Vec<String>> = Vec::new();
+        // Tables in the order they were written, which SELECT * follows
+        let mut written = Scope::nested(outer);
+        for table_ref in std::iter::once(&stmt.from).chain(stmt.joins.iter().map(|j| &j.table)) {
+            written.add_columns(table_ref.binding(), self.source_columns(table_ref)?);
+        }
+        let reordered = written.all_columns() != scope.all_columns();
 
-        for (id, row_data) in all_rows {
-            if let Some(ref where_clause) = stmt.where_clause {
-                if !evaluate_where(where_clause, id, &row_data, &col_info, &pk_col_name) {
-                    continue;
+        let order_by = order_by_exprs(stmt)?;
+        let having = stmt
+            .having
+            .as_ref()
+            .map(|having| replace_aliases(having, &stmt.columns));
+
+        // Aggregates anywhere above the WHERE turn the query into a grouping one
+        let mut aggregates = Vec::new();
+        for expr in stmt
+            .columns
+            .iter()
+            .map(|item| &item.expr)
+            .chain(&having)
+            .chain(order_by.iter().map(|(expr, _)| expr))
+        {
+            collect_aggregates(expr, &self.functions, &mut aggregates);
+        }
+
+        let grouped = !aggregates.is_empty() || !stmt.group_by.is_empty();
+        if grouped {
+            for expr in &stmt.group_by {
+                self.bind(expr, &mut scope)?;
+            }
+            for aggregate in &aggregates {
+                match aggregate {
+                    Expr::Aggregate { arg: Some(arg), .. } => self.bind(arg, &mut scope)?,
+                    Expr::Function { args, .. } => {
+                        for arg in args {
+                            self.bind(arg, &mut scope)?;
+                        }
+                    }
+                    _ => {}
                 }
             }
+            // Without GROUP BY everything folds into a single row
+            let estimate = stmt.group_by.is_empty().then_some(1.0);
+            plan = profiled(
+                Aggregate::new(
+                    plan,
+                    stmt.group_by.clone(),
+                    aggregates.clone(),
+                    scope.clone(),
+                ),
+                estimate,
+            );
+            scope = scope.with_aggregates(aggregates);
+
+            if let Some(having) = having {
+                self.bind(&having, &mut scope)?;
+                let estimate = filtered(plan.estimated_rows());
+                plan = profiled(Filter::new(plan, having, scope.clone()), estimate);
+            }
+        } else if having.is_some() {
+            return Err("HAVING requires GROUP BY or an aggregate".to_string());
+        }
 
-            let mut row: Vec<String> = Vec::new();
-            for col_name in &select_cols {
-                if col_name == &pk_col_name {
-                    row.push(id.to_string());
-                } else if let Some((_, size, offset)) =
-                    col_info.iter().find(|(name, _, _)| name == col_name)
-                {
-                    let data = &row_data[*offset..*offset + *size];
-                    let s = String::from_utf8_lossy(data)
-                        .trim_matches(char::from(0))
-                        .to_string();
-                    row.push(s);
-                } else {
-                    row.push(String::new());
+        // Window functions in the select list and ORDER BY see the rows
+        // left after grouping
+        let mut windows = Vec::new();
+        for expr in stmt
+            .columns
+            .iter()
+            .map(|item| &item.expr)
+            .chain(order_by.iter().map(|(expr, _)| expr))
+        {
+            collect_windows(expr, &mut windows);
+        }
+        let windowed = !windows.is_empty();
+        if windowed {
+            for window in &windows {
+                for expr in window.operands() {
+                    self.bind(expr, &mut scope)?;
                 }
             }
-            results.push(row);
+            let estimate = plan.estimated_rows();
+            plan = profiled(Window::new(plan, windows.clone(), scope.clone()), estimate);
+            scope = scope.with_windows(windows);
         }
 
-        Ok(ExecuteResult::Rows {
-            headers,
-            rows: results,
-        })
+        // Resolve the select list before running anything so unknown or
+        // ambiguous names are reported even when no rows match
+        for item in &stmt.columns {
+            self.bind(&item.expr, &mut scope)?;
+        }
+        let (mut headers, exprs) = scope.projection(&stmt.columns, !stmt.joins.is_empty())?;
+        if exprs.is_none() {
+            headers = written.projection(&stmt.columns, !stmt.joins.is_empty())?.0;
+        }
+
+        if !order_by.is_empty() {
+            for (expr, _) in &order_by {
+                self.bind(expr, &mut scope)?;
+            }
+            // An index may already have returned the rows in order
+            if !sorted {
+                let estimate = plan.estimated_rows();
+                plan = profiled(Sort::new(plan, order_by, scope.clone()), estimate);
+            }
+        }
+
+        plan = limited(plan, stmt);
+
+        // SELECT * passes rows through, unless the joins were reordered or
+        // aggregate or window values follow the table columns
+        let exprs = match exprs {
+            Some(exprs) => Some(exprs),
+            None if grouped || windowed || reordered => Some(written.all_columns()),
+            None => None,
+        };
+        if let Some(exprs) = exprs {
+            let estimate = plan.estimated_rows();
+            plan = profiled(Project::new(plan, exprs, scope), estimate);
+        }
+
+        Ok((headers, plan))
     }
 
-    fn execute_select_with_join(&mut self, stmt: SelectStmt) -> Result<ExecuteResult, String> {
-        // Get the first join clause (supporting single join for now)
-        let join = stmt.joins.first().ok_or("No join clause found")?;
-        let left_table_name = stmt.table_name.clone();
-        let right_table_name = join.table_name.clone();
-        let left_col = join.left_column.clone();
-        let right_col = join.right_column.clone();
+    /// Plan a compound SELECT: each query block on its own, combined left
+    /// to right, then sorted and limited as a whole. ORDER BY names the
+    /// output columns, as the first block calls them, or their positions.
+    fn plan_compound(
+        &self,
+        stmt: &SelectStmt,
+        outer: Option<&Scope>,
+    ) -> Result<(Vec<String>, Box<dyn Operator>), String> {
+        let first = SelectStmt {
+            order_by: Vec::new(),
+            limit: None,
+            offset: None,
+            compound: Vec::new(),
+            ..stmt.clone()
+        };
+        let (headers, mut plan) = self.plan_select(&first, outer)?;
 
-        // Get left table data
-        let left_table = self
-            .tables
-            .get_mut(&left_table_name)
-            .ok_or_else(|| format!("Table '{}' not found", left_table_name))?;
+        for (operator, query) in &stmt.compound {
+            let (block_headers, block) = self.plan_select(query, outer)?;
+            if block_headers.len() != headers.len() {
+                return Err(column_count_mismatch(*operator));
+            }
+            let estimate = match (plan.estimated_rows(), block.estimated_rows()) {
+                (Some(left), Some(right)) => Some(match operator {
+                    SetOperator::Union | SetOperator::UnionAll => left + right,
+                    SetOperator::Intersect => left.min(right),
+                    SetOperator::Except => left,
+                }),
+                _ => None,
+            };
+            plan = profiled(SetOperation::new(*operator, plan, block), estimate);
+        }
 
-        let left_rows = left_table.select_all();
-        let left_col_info: Vec<(String, usize, usize)> = left_table
-            .columns
-            .iter()
-            .map(|c| (c.name.clone(), c.size, c.offset))
-            .collect();
-        let left_pk = left_col_info
-            .first()
-            .map(|(name, _, _)| name.clone())
-            .unwrap_or_default();
+        if !stmt.order_by.is_empty() {
+            let columns = self.output_columns(&first)?;
+            let mut scope = Scope::nested(outer);
+            scope.add_columns("", columns.clone());
+
+            let mut order_by = Vec::new();
+            for item in &stmt.order_by {
+                let expr = match &item.expr {
+                    Expr::Literal(Value::Integer(n)) => columns
+                        .get((*n as usize).wrapping_sub(1))
+                        .map(|column| {
+                            Expr::Column(ColumnRef {
+                                table: None,
+                                column: column.clone(),
+                            })
+                        })
+                        .ok_or_else(|| format!("ORDER BY term out of range: {}", n))?,
+                    expr => expr.clone(),
+                };
+                self.bind(&expr, &mut scope)?;
+                order_by.push((expr, item.descending));
+            }
+            let estimate = plan.estimated_rows();
+            plan = profiled(Sort::new(plan, order_by, scope), estimate);
+        }
 
-        // Get right table data
-        let right_table = self
-            .tables
-            .get_mut(&right_table_name)
-            .ok_or_else(|| format!("Table '{}' not found", right_table_name))?;
+        Ok((headers, limited(plan, stmt)))
+    }
 
-        let right_rows = right_table.select_all();
-        let right_col_info: Vec<(String, usize, usize)> = right_table
-            .columns
+    /// Plan a FROM clause whose joins are all INNER or CROSS. Their ON
+    /// conditions and the WHERE clause are pooled: each table is read with
+    /// the conditions that concern only it, and the tables are joined in the
+    /// order the cost model finds cheapest, each join taking the conditions
+    /// that become checkable once its table is added. A lone table is read
+    /// in `order` if an index allows it, which the returned flag reports.
+    fn plan_inner_joins(
+        &self,
+        stmt: &SelectStmt,
+        outer: Option<&Scope>,
+        order: Option<&ColumnOrder>,
+    ) -> Result<(Box<dyn Operator>, Scope, bool), String> {
+        let refs: Vec<&TableRef> = std::iter::once(&stmt.from)
+            .chain(stmt.joins.iter().map(|j| &j.table))
+            .collect();
+        let columns = refs
             .iter()
-            .map(|c| (c.name.clone(), c.size, c.offset))
+            .map(|r| self.source_columns(r))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Check every condition as written: an ON may only refer to the
+        // tables joined before it
+        let mut written = Scope::nested(outer);
+        let mut conditions = Vec::new();
+        for (i, table_ref) in refs.iter().enumerate() {
+            written.add_columns(table_ref.binding(), columns[i].clone());
+            if let Some(on) = i.checked_sub(1).and_then(|j| stmt.joins[j].on.as_ref()) {
+                self.check(on, &written)?;
+                conjuncts(on, &mut conditions);
+            }
+        }
+        if let Some(ref where_clause) = stmt.where_clause {
+            self.check(where_clause, &written)?;
+            conjuncts(where_clause, &mut conditions);
+        }
+
+        // Conditions on a single table are applied while reading it
+        let single: Vec<Scope> = refs
+            .iter()
+            .zip(&columns)
+            .map(|(table_ref, columns)| {
+                let mut scope = Scope::nested(outer);
+                scope.add_columns(table_ref.binding(), columns.clone());
+                scope
+            })
             .collect();
-        let right_pk = right_col_info
-            .first()
-            .map(|(name, _, _)| name.clone())
-            .unwrap_or_default();
-
-        // Build combined column info with table prefixes for headers
-        let mut all_headers: Vec<String> = Vec::new();
-        let mut all_col_info: Vec<(String, String, usize, usize, bool)> = Vec::new(); // (col_name, table, size, offset, is_left)
-
-        for (name, size, offset) in &left_col_info {
-            all_headers.push(format!("{}.{}", left_table_name, name));
-            all_col_info.push((name.clone(), left_table_name.clone(), *size, *offset, true));
-        }
-        for (name, size, offset) in &right_col_info {
-            all_headers.push(format!("{}.{}", right_table_name, name));
-            all_col_info.push((
-                name.clone(),
-                right_table_name.clone(),
-                *size,
-                *offset,
-                false,
-            ));
+        let mut local: Vec<Vec<&Expr>> = vec![Vec::new(); refs.len()];
+        let mut pending: Vec<&Expr> = Vec::new();
+        for condition in conditions {
+            match single.iter().position(|s| self.check(condition, s).is_ok()) {
+                Some(i) => local[i].push(condition),
+                None => pending.push(condition),
+            }
         }
+        let local: Vec<Option<Expr>> = local.iter().map(|c| and_all(c)).collect();
+
+        let models = refs
+            .iter()
+            .map(|r| self.cost_model(r))
+            .collect::<Result<Vec<_>, _>>()?;
+        let reads: Vec<Estimate> = (0..refs.len())
+            .map(|i| choose_access_path(&models[i], &single[i], local[i].as_ref()).1)
+            .collect();
 
-        // Determine which columns to select
-        let select_cols: Vec<String> = if stmt.columns.is_empty() {
-            all_headers.clone()
+        // The same table joined twice under one name can't be told apart
+        // once moved, so such joins keep their written order
+        let mut bindings: Vec<&str> = refs.iter().map(|r| r.binding()).collect();
+        bindings.sort_unstable();
+        bindings.dedup();
+        let reorder = bindings.len() == refs.len();
+
+        // Start from the table expected to produce the fewest rows
+        let first = if reorder {
+            (0..refs.len())
+                .reduce(|best, i| {
+                    if reads[i].rows < reads[best].rows {
+                        i
+                    } else {
+                        best
+                    }
+                })
+                .unwrap_or(0)
         } else {
-            stmt.columns.clone()
+            0
         };
-
-        let mut results: Vec<Vec<String>> = Vec::new();
-
-        // Nested-loop join
-        for (left_id, left_data) in &left_rows {
-            // Get left join column value
-            let left_val =
-                get_column_value(&left_col, *left_id, left_data, &left_col_info, &left_pk);
-
-            for (right_id, right_data) in &right_rows {
-                // Get right join column value
-                let right_val = get_column_value(
-                    &right_col,
-                    *right_id,
-                    right_data,
-                    &right_col_info,
-                    &right_pk,
+        let order = order.filter(|_| refs.len() == 1);
+        let (mut plan, estimate, sorted) =
+            self.plan_table_read(refs[first], local[first].as_ref(), outer, order)?;
+        let mut rows = estimate.rows;
+        let mut scope = single[first].clone();
+        let mut remaining: Vec<usize> = (0..refs.len()).filter(|&i| i != first).collect();
+
+        while !remaining.is_empty() {
+            // Greedily add the cheapest table, preferring ones a condition
+            // connects to the tables joined so far over cross products
+            let mut best: Option<(usize, bool, JoinStrategy, Estimate)> = None;
+            let candidates = if reorder {
+                &remaining[..]
+            } else {
+                &remaining[..1]
+            };
+            for &i in candidates {
+                let mut joined = scope.clone();
+                joined.add_columns(refs[i].binding(), columns[i].clone());
+                let on = and_all(&self.applicable(&pending, &joined));
+                let (strategy, estimate) = choose_join_strategy(
+                    &JoinType::Inner,
+                    on.as_ref(),
+                    &joined,
+                    scope.width(),
+                    &models[i],
+                    rows,
+                    reads[i],
                 );
-
-                // Check join condition
-                if left_val == right_val {
-                    let mut row: Vec<String> = Vec::new();
-
-                    for col_name in &select_cols {
-                        // Try to find column in the combined info
-                        if let Some((_, table, size, offset, is_left)) =
-                            all_col_info.iter().find(|(name, tbl, _, _, _)| {
-                                col_name == &format!("{}.{}", tbl, name) || col_name == name
-                            })
-                        {
-                            if *is_left {
-                                if col_name.contains(&left_pk) || col_name == &left_pk {
-                                    row.push(left_id.to_string());
-                                } else {
-                                    let data = &left_data[*offset..*offset + *size];
-                                    let s = String::from_utf8_lossy(data)
-                                        .trim_matches(char::from(0))
-                                        .to_string();
-                                    row.push(s);
-                                }
-                            } else {
-                                if col_name.contains(&right_pk) || col_name == &right_pk {
-                                    row.push(right_id.to_string());
-                                } else {
-                                    let data = &right_data[*offset..*offset + *size];
-                                    let s = String::from_utf8_lossy(data)
-                                        .trim_matches(char::from(0))
-                                        .to_string();
-                                    row.push(s);
-                                }
-                            }
-                        } else {
-                            row.push(String::new());
-                        }
+                let connected = on.is_some();
+                let better = match &best {
+                    None => true,
+                    Some((_, best_connected, _, best_estimate)) => {
+                        (connected, -estimate.cost) > (*best_connected, -best_estimate.cost)
                     }
-
-                    results.push(row);
+                };
+                if better {
+                    best = Some((i, connected, strategy, estimate));
                 }
             }
+            let (i, _, strategy, estimate) = best.expect("a table remains to be joined");
+            remaining.retain(|&r| r != i);
+
+            let left_width = scope.width();
+            scope.add_columns(refs[i].binding(), columns[i].clone());
+            let on_terms = self.applicable(&pending, &scope);
+            pending.retain(|c| !on_terms.contains(c));
+
+            let on = and_all(&on_terms);
+            let mut on_scope = scope.clone();
+            if let Some(ref on) = on {
+                self.bind(on, &mut on_scope)?;
+            }
+            let spec = JoinSpec {
+                join_type: JoinType::Inner,
+                on,
+                scope: on_scope,
+                left_width,
+                right_width: scope.width() - left_width,
+            };
+            plan = self.join_operator(
+                plan,
+                refs[i],
+                strategy,
+                spec,
+                local[i].as_ref(),
+                estimate.rows,
+            )?;
+            rows = estimate.rows;
         }
 
-        Ok(ExecuteResult::Rows {
-            headers: select_cols,
-            rows: results,
-        })
+        Ok((plan, scope, sorted))
     }
 
-    fn execute_delete(&mut self, stmt: DeleteStmt) -> Result<ExecuteResult, String> {
-        let table = self
-            .tables
-            .get_mut(&stmt.table_name)
-            .ok_or_else(|| format!("Table '{}' not found", stmt.table_name))?;
+    /// Plan a FROM clause with outer joins: the tables are joined in the
+    /// order written, and the WHERE clause filters the joined rows
+    fn plan_outer_joins(
+        &self,
+        stmt: &SelectStmt,
+        outer: Option<&Scope>,
+    ) -> Result<(Box<dyn Operator>, Scope), String> {
+        let (mut plan, estimate, _) = self.plan_table_read(&stmt.from, None, outer, None)?;
+        let mut rows = estimate.rows;
+        let mut scope = self.source_scope(&stmt.from, outer)?;
+
+        for join in &stmt.joins {
+            let left_width = scope.width();
+            scope.add_columns(join.table.binding(), self.source_columns(&join.table)?);
+
+            // The ON condition may only refer to tables joined so far
+            let mut on_scope = scope.clone();
+            if let Some(ref on) = join.on {
+                self.bind(on, &mut on_scope)?;
+            }
 
-        let all_rows = table.select_all();
-        let col_info: Vec<(String, usize, usize)> = table
-            .columns
-            .iter()
-            .map(|c| (c.name.clone(), c.size, c.offset))
-            .collect();
+            let model = self.cost_model(&join.table)?;
+            let (strategy, estimate) = choose_join_strategy(
+                &join.join_type,
+                join.on.as_ref(),
+                &scope,
+                left_width,
+                &model,
+                rows,
+                model.estimate(&AccessPath::FullScan),
+            );
+            let spec = JoinSpec {
+                join_type: join.join_type.clone(),
+                on: join.on.clone(),
+                scope: on_scope,
+                left_width,
+                right_width: scope.width() - left_width,
+            };
+            plan = self.join_operator(plan, &join.table, strategy, spec, None, estimate.rows)?;
+            rows = estimate.rows;
+        }
 
-        let pk_col_name = col_info
-            .first()
-            .map(|(name, _, _)| name.as_str())
-            .unwrap_or("id");
+        if let Some(ref where_clause) = stmt.where_clause {
+            let mut where_scope = scope.clone();
+            self.bind(where_clause, &mut where_scope)?;
+            let estimate = filtered(plan.estimated_rows());
+            plan = profiled(
+                Filter::new(plan, where_clause.clone(), where_scope),
+                estimate,
+            );
+        }
 
-        let mut ids_to_delete = Vec::new();
+        Ok((plan, scope))
+    }
 
-        for (id, row_data) in all_rows {
-            let should_delete = match &stmt.where_clause {
-                Some(where_clause) => {
-                    evaluate_where(where_clause, id, &row_data, &col_info, pk_col_name)
-                }
-                None => true,
+    /// Read one table through the cheapest access path `condition` allows,
+    /// with the condition applied on top. A derived table runs its query.
+    /// The flag says whether the rows come back sorted as `order` asks.
+    fn plan_table_read(
+        &self,
+        table_ref: &TableRef,
+        condition: Option<&Expr>,
+        outer: Option<&Scope>,
+        order: Option<&ColumnOrder>,
+    ) -> Result<(Box<dyn Operator>, Estimate, bool), String> {
+        let mut scope = self.source_scope(table_ref, outer)?;
+        if let Some(condition) = condition {
+            self.bind(condition, &mut scope)?;
+        }
+
+        let model = self.cost_model(table_ref)?;
+        let (mut path, estimate) = choose_access_path(&model, &scope, condition);
+        let sorted = order.is_some_and(|order| order_access_path(&model, &mut path, order));
+        let candidates = model.estimate(&path).rows;
+        let mut plan: Box<dyn Operator> = match (self.common_table(table_ref), &table_ref.subquery)
+        {
+            (Some(cte), _) => profiled(
+                MaterializedScan::new(&table_ref.name, Rc::clone(&cte.rows)),
+                Some(candidates),
+            ),
+            (None, Some(query)) => self.plan_select(query, None)?.1,
+            (None, None) => Box::new(Profile::new(
+                access_operator(&table_ref.name, path),
+                Some(candidates),
+            )),
+        };
+        if let Some(condition) = condition {
+            plan = profiled(
+                Filter::new(plan, condition.clone(), scope),
+                Some(estimate.rows),
+            );
+        }
+        Ok((plan, estimate, sorted))
+    }
+
+    /// The operator joining `left` with the table `right`, whose own rows
+    /// must satisfy `local`; the join is expected to produce `rows` rows
+    fn join_operator(
+        &self,
+        left: Box<dyn Operator>,
+        right: &TableRef,
+        strategy: JoinStrategy,
+        spec: JoinSpec,
+        local: Option<&Expr>,
+        rows: f64,
+    ) -> Result<Box<dyn Operator>, String> {
+        let outer = spec.scope.outer().cloned();
+        let outer = outer.as_ref();
+        // Lookup joins rescan an index for each left row instead of reading
+        // the right table once
+        let probe = |index: Option<String>| -> Result<Box<dyn Operator>, String> {
+            let keys = match index {
+                Some(index) => Lookup::Values {
+                    index,
+                    values: Vec::new(),
+                },
+                None => Lookup::Keys(Vec::new()),
             };
+            let lookup = profiled(IndexScan::new(&right.name, keys), None);
+            Ok(match local {
+                Some(condition) => {
+                    let mut scope = self.source_scope(right, outer)?;
+                    self.bind(condition, &mut scope)?;
+                    profiled(Filter::new(lookup, condition.clone(), scope), None)
+                }
+                None => lookup,
+            })
+        };
 
-            if should_delete {
-                ids_to_delete.push(id);
+        Ok(match strategy {
+            JoinStrategy::NestedLoop => {
+                let (read, _, _) = self.plan_table_read(right, local, outer, None)?;
+                profiled(NestedLoopJoin::new(left, read, None, spec), Some(rows))
             }
-        }
+            JoinStrategy::Hash {
+                left: left_key,
+                right: right_key,
+            } => {
+                let (read, _, _) = self.plan_table_read(right, local, outer, None)?;
+                profiled(
+                    HashJoin::new(left, read, left_key, right_key, spec),
+                    Some(rows),
+                )
+            }
+            JoinStrategy::PrimaryKeyLookup { left: key } => profiled(
+                NestedLoopJoin::new(left, probe(None)?, Some(key), spec),
+                Some(rows),
+            ),
+            JoinStrategy::IndexLookup { left: key, index } => profiled(
+                NestedLoopJoin::new(left, probe(Some(index))?, Some(key), spec),
+                Some(rows),
+            ),
+        })
+    }
+
+    /// The plan reading the rows of a table that satisfy `where_clause`,
+    /// as UPDATE and DELETE find the rows they change
+    fn plan_matching(
+        &self,
+        table_name: &str,
+        where_clause: Option<&Expr>,
+    ) -> Result<Box<dyn Operator>, String> {
+        let table_ref = TableRef {
+            name: table_name.to_string(),
+            alias: None,
+            subquery: None,
+        };
+        Ok(self
+            .plan_table_read(&table_ref, where_clause, None, None)?
+            .0)
+    }
+
+    /// Primary keys of the rows of a table that satisfy `where_clause`
+    fn matching_ids(
+        &mut self,
+        table_name: &str,
+        where_clause: Option<&Expr>,
+    ) -> Result<Vec<u32>, String> {
+        let mut plan = self.plan_matching(table_name, where_clause)?;
+
+        let mut ctx = ExecContext::new(&mut self.tables, &self.functions);
+        Ok(collect_rows(plan.as_mut(), &mut ctx)?
+            .iter()
+            .filter_map(|row| row[0].to_key())
+            .collect())
+    }
+
+    fn execute_delete(&mut self, stmt: DeleteStmt) -> Result<ExecuteResult, String> {
+        let returning = self.plan_returning(&stmt.table_name, stmt.returning.as_deref())?;
+        let ids_to_delete = self.matching_ids(&stmt.table_name, stmt.where_clause.as_ref())?;
+        let table = self
+            .tables
+            .get_mut(&stmt.table_name)
+            .ok_or_else(|| format!("Table '{}' not found", stmt.table_name))?;
 
         let count = ids_to_delete.len();
 
-        // Delete each matching row
+        // Delete each matching row, and its entry in every index
+        let mut rows = Vec::new();
         for id in ids_to_delete {
+            let Some(row_data) = table.get(id) else {
+                continue;
+            };
+            rows.push(table.row_values(id, &row_data));
+            let keys = index_keys(table, id, &row_data);
+            for index in table.indexes.values_mut() {
+                if let Some(key) = keys.get(&index.name) {
+                    index.delete(key, id)?;
+                }
+            }
             table.delete(id)?;
         }
 
-        Ok(ExecuteResult::RowsDeleted(count))
+        self.returning(returning, rows, ExecuteResult::RowsDeleted(count))
     }
 
     fn execute_update(&mut self, stmt: UpdateStmt) -> Result<ExecuteResult, String> {
+        // Find the column each assignment writes to
+        let table = self.get_table(&stmt.table_name)?;
+        let mut targets: Vec<usize> = Vec::new();
+        for (name, _) in &stmt.assignments {
+            let pos = table
+                .columns
+                .iter()
+                .position(|c| c.name == *name)
+                .ok_or_else(|| {
+                    format!("Column '{}' not found in table '{}'", name, stmt.table_name)
+                })?;
+            if targets.contains(&pos) {
+                return Err(format!("Column '{}' is assigned more than once", name));
+            }
+            targets.push(pos);
+        }
+
+        // The SET expressions are evaluated against each row's current values
+        let columns = table.columns.iter().map(|c| c.name.clone()).collect();
+        let mut scope = Scope::new();
+        scope.add_columns(&stmt.table_name, columns);
+        for (_, expr) in &stmt.assignments {
+            self.bind(expr, &mut scope)?;
+        }
+        let returning = self.plan_returning(&stmt.table_name, stmt.returning.as_deref())?;
+
+        // Work out and validate the new values of every matching row before
+        // writing any, so a value that doesn't fit leaves the table as it was
+        let mut plan = self.plan_matching(&stmt.table_name, stmt.where_clause.as_ref())?;
+        let mut ctx = ExecContext::new(&mut self.tables, &self.functions);
+        let mut changes = Vec::new();
+        for row in collect_rows(plan.as_mut(), &mut ctx)? {
+            let Some(id) = row[0].to_key() else {
+                continue;
+            };
+            let Some(old) = ctx
+                .tables
+                .get_mut(&stmt.table_name)
+                .and_then(|table| table.get(id))
+            else {
+                continue;
+            };
+            let mut change = RowChange {
+                id,
+                new_id: id,
+                new: old.clone(),
+                old,
+            };
+            for ((name, expr), &pos) in stmt.assignments.iter().zip(&targets) {
+                let value = evaluate(expr, &row, &scope, &mut ctx)?;
+                // The primary key is the row's B-Tree key, not part of its bytes
+                if pos == 0 {
+                    change.new_id = value.to_key().ok_or_else(|| {
+                        format!("Invalid ID {} for primary key column '{}'", value, name)
+                    })?;
+                    continue;
+                }
+                let column = &ctx.tables[&stmt.table_name].columns[pos];
+                let bytes = column.encode(&value)?;
+                let field = &mut change.new[column.offset..column.offset + column.size];
+                field.fill(0);
+                field[..bytes.len()].copy_from_slice(&bytes);
+            }
+            changes.push(change);
+        }
+
         let table = self
             .tables
             .get_mut(&stmt.table_name)
             .ok_or_else(|| format!("Table '{}' not found", stmt.table_name))?;
 
-        let all_rows = table.select_all();
-        let col_info: Vec<(String, usize, usize)> = table
-            .columns
+        // A new primary key must not belong to another row afterwards: one
+        // that keeps its key, or one given the same new key
+        let rekeyed: HashSet<u32> = changes
             .iter()
-            .map(|c| (c.name.clone(), c.size, c.offset))
+            .filter(|c| c.new_id != c.id)
+            .map(|c| c.id)
             .collect();
-        let cell_size = table.cell_size;
-        let pk_col_name = col_info
-            .first()
-            .map(|(name, _, _)| name.as_str())
-            .unwrap_or("id");
-
-        // First, collect IDs to update
-        let mut ids_to_update = Vec::new();
-        for (id, row_data) in &all_rows {
-            let should_update = match &stmt.where_clause {
-                Some(where_clause) => {
-                    evaluate_where(where_clause, *id, row_data, &col_info, pk_col_name)
-                }
-                None => true,
-            };
-            if should_update {
-                ids_to_update.push(*id);
+        let mut seen = HashSet::new();
+        for change in changes.iter().filter(|c| c.new_id != c.id) {
+            let taken = table.get(change.new_id).is_some() && !rekeyed.contains(&change.new_id);
+            if taken || !seen.insert(change.new_id) {
+                return Err(format!(
+                    "UNIQUE constraint failed: column '{}' value '{}' already exists",
+                    table.columns[0].name, change.new_id
+                ));
             }
         }
 
-        let mut count = 0;
+        // Index entries that change, because the value or the row's key does
+        let mut moves: Vec<IndexMove> = Vec::new();
+        for change in &changes {
+            let old_keys = index_keys(table, change.id, &change.old);
+            for (index, new_key) in index_keys(table, change.new_id, &change.new) {
+                let old_key = old_keys[&index].clone();
+                if old_key != new_key || change.id != change.new_id {
+                    moves.push(IndexMove {
+                        index,
+                        id: change.id,
+                        new_id: change.new_id,
+                        old_key,
+                        new_key,
+                    });
+                }
+            }
+        }
 
-        // Now perform updates
-        for id in ids_to_update {
-            let leaf_page_num = table.find_leaf(id);
-            let (slot, exists) = table.leaf_node_find(leaf_page_num, id);
+        // A UNIQUE index must not end up with a value twice: once among the
+        // changed values, or once more in a row whose value isn't changing
+        for index in table.indexes.values_mut().filter(|index| index.unique) {
+            let moving: Vec<&IndexMove> = moves.iter().filter(|m| m.index == index.name).collect();
+            let mut seen = HashSet::new();
+            for key in moving.iter().map(|m| &m.new_key) {
+                let taken = key.hash_key().is_some_and(|hashed| !seen.insert(hashed))
+                    || index
+                        .find(key)
+                        .iter()
+                        .any(|id| !moving.iter().any(|m| m.id == *id));
+                if taken {
+                    return Err(format!(
+                        "UNIQUE constraint failed: column '{}' value '{}' already exists",
+                        index.column_name, key
+                    ));
+                }
+            }
+        }
 
-            if exists {
-                let page = table.pager.get_page(leaf_page_num as usize);
-                let cell_ptr = leaf_node_cell(page, slot, cell_size);
+        // Take every old entry out before adding the new ones, so rows can
+        // trade values in a UNIQUE index
+        for m in &moves {
+            if let Some(index) = table.indexes.get_mut(&m.index) {
+                index.delete(&m.old_key, m.id)?;
+            }
+        }
+        for m in &moves {
+            if let Some(index) = table.indexes.get_mut(&m.index) {
+                index.insert(&m.new_key, m.new_id)?;
+            }
+        }
 
-                for (col_name, value) in &stmt.assignments {
-                    if let Some((_, size, offset)) =
-                        col_info.iter().find(|(name, _, _)| name == col_name)
-                    {
-                        let bytes = match value {
-                            Value::Integer(n) => n.to_string().into_bytes(),
-                            Value::Text(s) => s.as_bytes().to_vec(),
-                            Value::Identifier(s) => s.as_bytes().to_vec(),
-                        };
+        // A row whose key changes is deleted and inserted again under its
+        // new key; all the deletes go first so rows can trade keys
+        for change in changes.iter().filter(|c| c.new_id != c.id) {
+            table.delete(change.id)?;
+        }
+        let count = changes.len();
+        let rows = changes
+            .iter()
+            .map(|c| table.row_values(c.new_id, &c.new))
+            .collect();
+        for change in changes {
+            if change.new_id == change.id {
+                table.update(change.id, &change.new)?;
+            } else {
+                table.insert(change.new_id, &change.new)?;
+            }
+        }
 
-                        unsafe {
-                            let row_ptr = cell_ptr.add(4);
-                            let dest = row_ptr.add(*offset);
-                            ptr::write_bytes(dest, 0, *size);
-                            ptr::copy_nonoverlapping(bytes.as_ptr(), dest, bytes.len().min(*size));
-                        }
-                    }
-                }
+        self.returning(returning, rows, ExecuteResult::RowsUpdated(count))
+    }
 
-                table.pager.flush(leaf_page_num as usize);
-                count += 1;
-            }
+    /// Bind a RETURNING list to the table a statement writes, before any
+    /// row changes
+    fn plan_returning(
+        &self,
+        table_name: &str,
+        items: Option<&[SelectItem]>,
+    ) -> Result<Option<Returning>, String> {
+        let Some(items) = items else {
+            return Ok(None);
+        };
+        let table = self.get_table(table_name)?;
+        let columns = table.columns.iter().map(|c| c.name.clone()).collect();
+        let mut scope = Scope::new();
+        scope.add_columns(table_name, columns);
+        for item in items {
+            self.bind(&item.expr, &mut scope)?;
         }
+        let (headers, exprs) = scope.projection(items, false)?;
+        Ok(Some(Returning {
+            scope,
+            headers,
+            exprs,
+        }))
+    }
 
-        Ok(ExecuteResult::RowsUpdated(count))
+    /// Attach the RETURNING rows, if any, to a statement's result. `rows`
+    /// holds the table values of each row the statement wrote: the new
+    /// values for INSERT and UPDATE, the old ones for DELETE.
+    fn returning(
+        &mut self,
+        returning: Option<Returning>,
+        rows: Vec<Vec<SqlValue>>,
+        result: ExecuteResult,
+    ) -> Result<ExecuteResult, String> {
+        let Some(returning) = returning else {
+            return Ok(result);
+        };
+        let mut ctx = ExecContext::new(&mut self.tables, &self.functions);
+        let mut output = Vec::new();
+        for row in rows {
+            let values = match &returning.exprs {
+                Some(exprs) => exprs
+                    .iter()
+                    .map(|expr| evaluate(expr, &row, &returning.scope, &mut ctx))
+                    .collect::<Result<Vec<_>, _>>()?,
+                None => row,
+            };
+            output.push(values.iter().map(|v| v.to_string()).collect());
+        }
+        Ok(ExecuteResult::Returning {
+            result: Box::new(result),
+            headers: returning.headers,
+            rows: output,
+        })
     }
 
     fn execute_drop(&mut self, table_name: String) -> Result<ExecuteResult, String> {
-        if self.tables.remove(&table_name).is_some() {
-            let filename = format!("{}.db", table_name);
-            let _ = std::fs::remove_file(&filename);
+        if let Some(table) = self.tables.remove(&table_name) {
+            let db_path = self.require_connection()?;
+            let _ = std::fs::remove_file(db_path.join(format!("{}.db", table_name)));
+            for index in table.indexes.values() {
+                let _ = std::fs::remove_file(&index.filename);
+            }
+            self.save_metadata()?;
+            if self.stats.remove(&table_name).is_some() {
+                if let Some(db_path) = self.get_db_path() {
+                    save_stats(&db_path, &self.stats)?;
+                }
+            }
             Ok(ExecuteResult::TableDropped(table_name))
         } else {
             Err(format!("Table '{}' not found", table_name))
@@ -761,6 +1658,7 @@ impl Executor {
     }
 
     fn execute_create_index(&mut self, stmt: CreateIndexStmt) -> Result<ExecuteResult, String> {
+        let db_path = self.require_connection()?;
         let table = self
             .tables
             .get_mut(&stmt.table_name)
@@ -780,163 +1678,352 @@ impl Executor {
             return Err(format!("Index '{}' already exists", stmt.index_name));
         }
 
-        // Create the index
+        // Create the index, in a fresh file inside the database directory
+        let filename = db_path.join(format!("{}_{}.idx", stmt.table_name, stmt.index_name));
+        let _ = std::fs::remove_file(&filename);
         let mut index = Index::new(
+            filename.to_str().unwrap(),
             &stmt.index_name,
             &stmt.table_name,
             &stmt.column_name,
             stmt.unique,
-        );
-
-        // Get column info for extracting values
-        let col_info: Vec<(String, usize, usize)> = table
-            .columns
-            .iter()
-            .map(|c| (c.name.clone(), c.size, c.offset))
-            .collect();
-        let pk_col_name = col_info
-            .first()
-            .map(|(name, _, _)| name.clone())
-            .unwrap_or_default();
+        )?;
 
         // Populate index with existing data
-        let all_rows = table.select_all();
-        for (row_id, row_data) in all_rows {
-            let col_value = if stmt.column_name == pk_col_name {
-                row_id.to_string()
-            } else if let Some((_, size, offset)) = col_info
-                .iter()
-                .find(|(name, _, _)| *name == stmt.column_name)
-            {
-                let data = &row_data[*offset..*offset + *size];
-                String::from_utf8_lossy(data)
-                    .trim_matches(char::from(0))
-                    .to_string()
-            } else {
-                continue;
-            };
-
-            index.insert(&col_value, row_id)?;
-        }
+        populate_index(table, &mut index)?;
 
         let index_name = stmt.index_name.clone();
         table.indexes.insert(stmt.index_name, index);
+        self.save_metadata()?;
 
         Ok(ExecuteResult::IndexCreated(index_name))
     }
 
+    /// Gather row counts, key ranges and index selectivity for one table,
+    /// or every table, and save them for the planner
+    fn execute_analyze(&mut self, table_name: Option<String>) -> Result<ExecuteResult, String> {
+        let db_path = self.require_connection()?;
+
+        let names: Vec<String> = match table_name {
+            Some(name) => {
+                if !self.tables.contains_key(&name) {
+                    return Err(format!("Table '{}' not found", name));
+                }
+                vec![name]
+            }
+            None => self.tables.keys().cloned().collect(),
+        };
+
+        for name in &names {
+            if let Some(table) = self.tables.get_mut(name) {
+                self.stats.insert(name.clone(), analyze(table));
+            }
+        }
+        save_stats(&db_path, &self.stats)?;
+
+        Ok(ExecuteResult::TablesAnalyzed(names.len()))
+    }
+
     fn execute_drop_index(&mut self, index_name: String) -> Result<ExecuteResult, String> {
         // Find and remove the index from any table
-        for table in self.tables.values_mut() {
-            if let Some(index) = table.indexes.remove(&index_name) {
-                // Delete the index file
-                let filename = format!("{}_{}.idx", index.table_name, index_name);
-                let _ = std::fs::remove_file(&filename);
-                return Ok(ExecuteResult::IndexDropped(index_name));
-            }
+        let index = self
+            .tables
+            .values_mut()
+            .find_map(|table| table.indexes.remove(&index_name))
+            .ok_or_else(|| format!("Index '{}' not found", index_name))?;
+        // Delete the index file
+        let _ = std::fs::remove_file(&index.filename);
+        self.save_metadata()?;
+        Ok(ExecuteResult::IndexDropped(index_name))
+    }
+}
+
+/// Add every row of `table` to a new, empty index on it
+fn populate_index(table: &mut Table, index: &mut Index) -> Result<(), String> {
+    for (row_id, row_data) in table.select_all() {
+        if let Some(value) = table.index_key(row_id, &row_data, &index.column_name) {
+            index.insert(&value, row_id)?;
         }
-        Err(format!("Index '{}' not found", index_name))
     }
+    Ok(())
 }
 
-// Standalone function to avoid borrow checker issues
-fn evaluate_where(
-    where_clause: &WhereClause,
-    id: u32,
-    row_data: &[u8],
-    col_info: &[(String, usize, usize)],
-    pk_col_name: &str,
-) -> bool {
-    if where_clause.conditions.is_empty() {
-        return true;
+/// The position in `table` of each column an INSERT gives values for: the
+/// listed columns, or every column in order
+fn insert_positions(table: &Table, columns: Option<&[String]>) -> Result<Vec<usize>, String> {
+    let Some(columns) = columns else {
+        return Ok((0..table.columns.len()).collect());
+    };
+    let mut positions = Vec::new();
+    for name in columns {
+        let pos = table
+            .columns
+            .iter()
+            .position(|c| &c.name == name)
+            .ok_or_else(|| format!("Column '{}' not found", name))?;
+        if positions.contains(&pos) {
+            return Err(format!("Column '{}' is given more than once", name));
+        }
+        positions.push(pos);
+    }
+    if !positions.contains(&0) {
+        return Err(format!(
+            "Column '{}' is the primary key and needs a value",
+            table.columns[0].name
+        ));
     }
+    Ok(positions)
+}
 
-    let mut results: Vec<bool> = Vec::new();
+/// Insert one row, with a value for every column, into `table` and its
+/// indexes, returning the row as stored
+fn insert_row(table: &mut Table, values: &[SqlValue]) -> Result<Vec<SqlValue>, String> {
+    let id = values[0].to_key().ok_or_else(|| {
+        format!(
+            "Invalid ID {}: the primary key must be an integer from 0 to {}",
+            values[0],
+            u32::MAX
+        )
+    })?;
+
+    let mut row_data = vec![0u8; table.row_size];
+    for (col, value) in table.columns.iter().zip(values).skip(1) {
+        let bytes = col.encode(value)?;
+        row_data[col.offset..col.offset + bytes.len()].copy_from_slice(&bytes);
+    }
 
-    for condition in &where_clause.conditions {
-        let col_value = if condition.column == pk_col_name {
-            // Primary key is stored as B-Tree key
-            id.to_string()
-        } else if let Some((_, size, offset)) = col_info
-            .iter()
-            .find(|(name, _, _)| name == &condition.column)
-        {
-            let data = &row_data[*offset..*offset + *size];
-            String::from_utf8_lossy(data)
-                .trim_matches(char::from(0))
-                .to_string()
-        } else {
+    // Check UNIQUE constraints on all indexes BEFORE inserting
+    let keys = index_keys(table, id, &row_data);
+    for index in table.indexes.values_mut() {
+        let Some(key) = keys.get(&index.name) else {
             continue;
         };
+        if index.unique && !index.find(key).is_empty() {
+            return Err(format!(
+                "UNIQUE constraint failed: column '{}' value '{}' already exists",
+                index.column_name, key
+            ));
+        }
+    }
 
-        let cond_value = match &condition.value {
-            Value::Integer(n) => n.to_string(),
-            Value::Text(s) => s.clone(),
-            Value::Identifier(s) => s.clone(),
-        };
-
-        let result = match condition.operator {
-            CompareOp::Equals => col_value == cond_value,
-            CompareOp::NotEquals => col_value != cond_value,
-            CompareOp::LessThan => col_value
-                .parse::<i64>()
-                .ok()
-                .and_then(|a| cond_value.parse::<i64>().ok().map(|b| a < b))
-                .unwrap_or(col_value < cond_value),
-            CompareOp::GreaterThan => col_value
-                .parse::<i64>()
-                .ok()
-                .and_then(|a| cond_value.parse::<i64>().ok().map(|b| a > b))
-                .unwrap_or(col_value > cond_value),
-            CompareOp::LessEquals => col_value
-                .parse::<i64>()
-                .ok()
-                .and_then(|a| cond_value.parse::<i64>().ok().map(|b| a <= b))
-                .unwrap_or(col_value <= cond_value),
-            CompareOp::GreaterEquals => col_value
-                .parse::<i64>()
-                .ok()
-                .and_then(|a| cond_value.parse::<i64>().ok().map(|b| a >= b))
-                .unwrap_or(col_value >= cond_value),
-        };
+    // Insert into main table
+    table.insert(id, &row_data)?;
 
-        results.push(result);
+    // Update all indexes with the new row
+    for index in table.indexes.values_mut() {
+        if let Some(key) = keys.get(&index.name) {
+            index.insert(key, id)?;
+        }
     }
 
-    if results.is_empty() {
-        return true;
+    Ok(table.row_values(id, &row_data))
+}
+
+/// The operator that reads a table's rows along an access path. The path
+/// only narrows the candidates; conditions still have to be applied on top.
+fn access_operator(table_name: &str, path: AccessPath) -> Box<dyn Operator> {
+    match path {
+        AccessPath::FullScan => Box::new(Scan::full(table_name)),
+        AccessPath::PrimaryKeyRange {
+            start,
+            end,
+            descending: false,
+        } => Box::new(Scan::new(table_name, start, end)),
+        AccessPath::PrimaryKeyRange {
+            start,
+            end,
+            descending: true,
+        } => Box::new(Scan::backwards(table_name, start, end)),
+        AccessPath::PrimaryKeyLookup { keys } => {
+            let keys = keys
+                .into_iter()
+                .map(|k| SqlValue::Integer(k as i64))
+                .collect();
+            Box::new(IndexScan::new(table_name, Lookup::Keys(keys)))
+        }
+        AccessPath::IndexLookup { index, values } => {
+            Box::new(IndexScan::new(table_name, Lookup::Values { index, values }))
+        }
+        AccessPath::IndexRange {
+            index,
+            lower,
+            upper,
+            order: None,
+        } => Box::new(IndexScan::new(
+            table_name,
+            Lookup::Range {
+                index,
+                lower,
+                upper,
+            },
+        )),
+        AccessPath::IndexRange {
+            index,
+            lower,
+            upper,
+            order: Some(descending),
+        } => Box::new(IndexScan::ordered(
+            table_name, index, lower, upper, descending,
+        )),
     }
+}
 
-    let mut final_result = results[0];
-    for (i, op) in where_clause.operators.iter().enumerate() {
-        if i + 1 < results.len() {
-            match op {
-                LogicalOp::And => final_result = final_result && results[i + 1],
-                LogicalOp::Or => final_result = final_result || results[i + 1],
+/// One EXPLAIN row for `operator`, followed by rows for its inputs
+fn explain_rows(operator: &dyn Operator, depth: usize, analyze: bool, out: &mut Vec<Vec<String>>) {
+    let (name, detail) = operator.describe();
+    let mut row = vec![
+        format!("{}{}", "  ".repeat(depth), name),
+        detail,
+        operator
+            .estimated_rows()
+            .map_or(String::new(), |rows| format!("{:.0}", rows)),
+    ];
+    if analyze {
+        match operator.actual() {
+            Some((rows, elapsed)) => {
+                row.push(rows.to_string());
+                row.push(format!("{:.3}", elapsed.as_secs_f64() * 1000.0));
             }
+            None => row.extend([String::new(), String::new()]),
         }
     }
+    out.push(row);
 
-    final_result
+    for child in operator.children() {
+        explain_rows(child, depth + 1, analyze, out);
+    }
 }
 
-// Helper function to get column value for JOIN condition
-fn get_column_value(
-    col_name: &str,
-    id: u32,
-    row_data: &[u8],
-    col_info: &[(String, usize, usize)],
-    pk_col_name: &str,
-) -> String {
-    if col_name == pk_col_name {
-        id.to_string()
-    } else if let Some((_, size, offset)) = col_info.iter().find(|(name, _, _)| name == col_name) {
-        let data = &row_data[*offset..*offset + *size];
-        String::from_utf8_lossy(data)
-            .trim_matches(char::from(0))
-            .to_string()
-    } else {
-        String::new()
+/// Box an operator behind a Profile carrying the planner's row estimate
+fn profiled(operator: impl Operator + 'static, estimate: Option<f64>) -> Box<dyn Operator> {
+    Box::new(Profile::new(Box::new(operator), estimate))
+}
+
+/// Apply a statement's LIMIT and OFFSET, if it has them
+fn limited(plan: Box<dyn Operator>, stmt: &SelectStmt) -> Box<dyn Operator> {
+    if stmt.limit.is_none() && stmt.offset.is_none() {
+        return plan;
+    }
+    let offset = stmt.offset.unwrap_or(0);
+    let estimate = plan.estimated_rows().map(|rows| {
+        let rows = (rows - offset as f64).max(0.0);
+        stmt.limit.map_or(rows, |limit| rows.min(limit as f64))
+    });
+    profiled(Limit::new(plan, stmt.limit, offset), estimate)
+}
+
+/// Whether `query` reads the table `name` in a FROM or JOIN, directly or
+/// through a derived table
+fn reads_table(query: &SelectStmt, name: &str) -> bool {
+    std::iter::once(&query.from)
+        .chain(query.joins.iter().map(|join| &join.table))
+        .any(|table| match &table.subquery {
+            Some(subquery) => reads_table(subquery, name),
+            None => table.name == name,
+        })
+        || query
+            .compound
+            .iter()
+            .any(|(_, block)| reads_table(block, name))
+}
+
+/// The error for query blocks of a compound SELECT that return different
+/// numbers of columns
+fn column_count_mismatch(operator: SetOperator) -> String {
+    format!(
+        "SELECTs to the left and right of {} do not have the same number of result columns",
+        operator.name()
+    )
+}
+
+/// Rows expected to pass a filter over `rows` input rows
+fn filtered(rows: Option<f64>) -> Option<f64> {
+    rows.map(|rows| rows * FILTER_FRACTION)
+}
+
+/// Combine conditions with AND; None when there are none
+fn and_all(conditions: &[&Expr]) -> Option<Expr> {
+    conditions
+        .iter()
+        .map(|c| (*c).clone())
+        .reduce(|left, right| Expr::Logical {
+            left: Box::new(left),
+            op: LogicalOp::And,
+            right: Box::new(right),
+        })
+}
+
+/// ORDER BY terms as expressions over the query's rows. A bare number
+/// refers to that select list entry.
+fn order_by_exprs(stmt: &SelectStmt) -> Result<Vec<(Expr, bool)>, String> {
+    stmt.order_by
+        .iter()
+        .map(|item| {
+            let expr = match &item.expr {
+                Expr::Literal(Value::Integer(n)) if !stmt.columns.is_empty() => stmt
+                    .columns
+                    .get((*n as usize).wrapping_sub(1))
+                    .map(|selected| selected.expr.clone())
+                    .ok_or_else(|| format!("ORDER BY term out of range: {}", n))?,
+                expr => replace_aliases(expr, &stmt.columns),
+            };
+            Ok((expr, item.descending))
+        })
+        .collect()
+}
+
+/// Replace bare names that match a select list alias with the aliased
+/// expression, so HAVING and ORDER BY can refer to `AS` names
+fn replace_aliases(expr: &Expr, items: &[SelectItem]) -> Expr {
+    let mut expr = expr.clone();
+    replace_aliases_in(&mut expr, items);
+    expr
+}
+
+fn replace_aliases_in(expr: &mut Expr, items: &[SelectItem]) {
+    match expr {
+        Expr::Column(ColumnRef {
+            table: None,
+            column,
+        }) => {
+            if let Some(item) = items
+                .iter()
+                .find(|item| item.alias.as_ref() == Some(column))
+            {
+                *expr = item.expr.clone();
+            }
+        }
+        // Aggregate and window arguments name table columns
+        Expr::Aggregate { .. } | Expr::Window { .. } => {}
+        _ => {
+            for operand in expr.operands_mut() {
+                replace_aliases_in(operand, items);
+            }
+        }
+    }
+}
+
+/// Collect the distinct aggregate calls in an expression, including calls
+/// of aggregates registered in `functions`. Aggregates in a subquery are
+/// computed by the subquery; those in the arguments of a window function
+/// are computed before it.
+fn collect_aggregates(expr: &Expr, functions: &FunctionRegistry, out: &mut Vec<Expr>) {
+    match expr {
+        Expr::Aggregate { .. } => {
+            if !out.contains(expr) {
+                out.push(expr.clone());
+            }
+        }
+        Expr::Function { name, args } if functions.aggregate(name, args.len()).is_some() => {
+            if !out.contains(expr) {
+                out.push(expr.clone());
+            }
+        }
+        _ => {
+            for expr in expr.operands() {
+                collect_aggregates(expr, functions, out);
+            }
+        }
     }
 }
 
@@ -954,8 +2041,120 @@ pub enum ExecuteResult {
     TransactionStarted,
     TransactionCommitted,
     TransactionRolledBack,
+    TablesAnalyzed(usize),
     Rows {
         headers: Vec<String>,
         rows: Vec<Vec<String>>,
     },
+    /// The rows of a RETURNING clause, with the result of the statement
+    /// that wrote them
+    Returning {
+        result: Box<ExecuteResult>,
+        headers: Vec<String>,
+        rows: Vec<Vec<String>>,
+    },
+}
+
+/// Collect the distinct window function calls in an expression, outside
+/// any subquery
+fn collect_windows(expr: &Expr, out: &mut Vec<Expr>) {
+    match expr {
+        Expr::Window { .. } => {
+            if !out.contains(expr) {
+                out.push(expr.clone());
+            }
+        }
+        Expr::Aggregate { .. } => {}
+        _ => {
+            for expr in expr.operands() {
+                collect_windows(expr, out);
+            }
+        }
+    }
+}
+
+/// Collect the subqueries of an expression, outside any aggregate or window
+/// function, and whether each must return a single column
+fn subqueries<'a>(expr: &'a Expr, out: &mut Vec<(&'a SelectStmt, bool)>) {
+    match expr {
+        Expr::Aggregate { .. } | Expr::Window { .. } => return,
+        Expr::Subquery(query) => out.push((query, true)),
+        Expr::Exists { query, .. } => out.push((query, false)),
+        _ => {}
+    }
+    for operand in expr.operands() {
+        subqueries(operand, out);
+    }
+    if let Expr::InSubquery { query, .. } = expr {
+        out.push((query, true));
+    }
+}
+
+/// One row an UPDATE changes: its key and row bytes before and after
+/// A bound RETURNING list: headers, and the expressions to evaluate
+/// against each written row, or None for `RETURNING *`
+struct Returning {
+    scope: Scope,
+    headers: Vec<String>,
+    exprs: Option<Vec<Expr>>,
+}
+
+struct RowChange {
+    id: u32,
+    new_id: u32,
+    old: Vec<u8>,
+    new: Vec<u8>,
+}
+
+/// An index entry an UPDATE moves, from the row's old key and value to its
+/// new ones
+struct IndexMove {
+    index: String,
+    id: u32,
+    new_id: u32,
+    old_key: SqlValue,
+    new_key: SqlValue,
+}
+
+/// The value each of a table's indexes files a row under, by index name
+fn index_keys(table: &Table, id: u32, row_data: &[u8]) -> HashMap<String, SqlValue> {
+    table
+        .indexes
+        .values()
+        .filter_map(|index| {
+            let key = table.index_key(id, row_data, &index.column_name)?;
+            Some((index.name.clone(), key))
+        })
+        .collect()
+}
+
+/// Reject names the parser reads as something other than a registered
+/// function
+fn check_function_name(name: &str) -> Result<(), String> {
+    let upper = name.to_uppercase();
+    if WindowFunc::from_name(&upper).is_some() || CONDITIONAL_FUNCTIONS.contains(&upper.as_str()) {
+        return Err(format!(
+            "Function '{}' is built in and can't be replaced",
+            upper
+        ));
+    }
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("Invalid function name '{}'", name));
+    }
+    Ok(())
+}
+
+/// Collect every function call in an expression, outside any subquery
+fn function_calls<'a>(expr: &'a Expr, out: &mut Vec<&'a Expr>) {
+    if let Expr::Function { .. } = expr {
+        out.push(expr);
+    }
+    for expr in expr.operands() {
+        function_calls(expr, out);
+    }
 }
@@ -0,0 +1,707 @@
+//! Functions callable from SQL, and the registry calls resolve in
+
+use crate::value::SqlValue;
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A scalar function, called with its evaluated arguments
+pub type ScalarFunction = Rc<dyn Fn(&[SqlValue]) -> Result<SqlValue, String>>;
+
+/// Adds one row's arguments to an aggregate's state
+type StepFunction = Box<dyn Fn(&mut dyn Any, &[SqlValue]) -> Result<(), String>>;
+
+/// Turns an aggregate's state into its value
+type FinalizeFunction = Box<dyn Fn(&dyn Any) -> Result<SqlValue, String>>;
+
+/// A user-defined aggregate. Each group starts from a fresh state, `step`
+/// folds every row's arguments into it and `finalize` turns it into the
+/// group's value.
+pub struct AggregateFunction {
+    init: Box<dyn Fn() -> Box<dyn Any>>,
+    step: StepFunction,
+    finalize: FinalizeFunction,
+}
+
+impl AggregateFunction {
+    /// The state of a group no row has been added to
+    pub fn init(&self) -> Box<dyn Any> {
+        (self.init)()
+    }
+
+    /// Add one row's arguments to a group's state
+    pub fn step(&self, state: &mut dyn Any, args: &[SqlValue]) -> Result<(), String> {
+        (self.step)(state, args)
+    }
+
+    /// The value of a group
+    pub fn finalize(&self, state: &dyn Any) -> Result<SqlValue, String> {
+        (self.finalize)(state)
+    }
+}
+
+/// Functions by upper-case name and number of arguments. A function
+/// registered without an arity takes any number of arguments; a call uses
+/// it when nothing is registered for its exact count.
+pub struct FunctionRegistry {
+    scalars: HashMap<(String, Option<usize>), ScalarFunction>,
+    aggregates: HashMap<(String, Option<usize>), Rc<AggregateFunction>>,
+}
+
+impl Default for FunctionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FunctionRegistry {
+    /// A registry holding the built-in functions
+    pub fn new() -> Self {
+        let mut registry = FunctionRegistry {
+            scalars: HashMap::new(),
+            aggregates: HashMap::new(),
+        };
+
+        // Strings
+        registry.register("UPPER", Some(1), |args| {
+            Ok(map_text(&args[0], |s| SqlValue::Text(s.to_uppercase())))
+        });
+        registry.register("LOWER", Some(1), |args| {
+            Ok(map_text(&args[0], |s| SqlValue::Text(s.to_lowercase())))
+        });
+        registry.register("LENGTH", Some(1), |args| {
+            Ok(map_text(&args[0], |s| {
+                SqlValue::Integer(s.chars().count() as i64)
+            }))
+        });
+        registry.register("SUBSTR", Some(2), substr);
+        registry.register("SUBSTR", Some(3), substr);
+        registry.register("TRIM", Some(1), trim);
+        registry.register("TRIM", Some(2), trim);
+        registry.register("REPLACE", Some(3), |args| {
+            let (Some(s), Some(from), Some(to)) = (text(&args[0]), text(&args[1]), text(&args[2]))
+            else {
+                return Ok(SqlValue::Null);
+            };
+            if from.is_empty() {
+                return Ok(SqlValue::Text(s));
+            }
+            Ok(SqlValue::Text(s.replace(&from, &to)))
+        });
+        registry.register("INSTR", Some(2), |args| {
+            let (Some(s), Some(sub)) = (text(&args[0]), text(&args[1])) else {
+                return Ok(SqlValue::Null);
+            };
+            // 1-based position in characters, or 0 when absent
+            let position = s.find(&sub).map_or(0, |i| s[..i].chars().count() + 1);
+            Ok(SqlValue::Integer(position as i64))
+        });
+        registry.register("PRINTF", None, printf);
+        registry.register("FORMAT", None, printf);
+
+        // Numbers
+        registry.register("ABS", Some(1), abs);
+        registry.register("ROUND", Some(1), round);
+        registry.register("ROUND", Some(2), round);
+        let state = Cell::new(RandomState::new().build_hasher().finish() | 1);
+        registry.register("RANDOM", Some(0), move |_| {
+            // xorshift64*
+            let mut x = state.get();
+            x ^= x >> 12;
+            x ^= x << 25;
+            x ^= x >> 27;
+            state.set(x);
+            Ok(SqlValue::Integer(
+                x.wrapping_mul(0x2545_F491_4F6C_DD1D) as i64
+            ))
+        });
+
+        // Dates: an unreadable time value or modifier gives NULL
+        registry.register("DATE", None, |args| Ok(formatted(moment(args), "%Y-%m-%d")));
+        registry.register("DATETIME", None, |args| {
+            Ok(formatted(moment(args), "%Y-%m-%d %H:%M:%S"))
+        });
+        registry.register("STRFTIME", None, |args| {
+            let format = match args.first().and_then(text) {
+                Some(format) => format,
+                None => return Ok(SqlValue::Null),
+            };
+            Ok(formatted(moment(&args[1..]), &format))
+        });
+        registry.register("JULIANDAY", None, |args| {
+            Ok(moment(args).map_or(SqlValue::Null, |time| SqlValue::Real(julian_day(time))))
+        });
+
+        registry
+    }
+
+    /// Add a function, replacing any registered under the same name and
+    /// arity. `arity` None accepts any number of arguments.
+    pub fn register(
+        &mut self,
+        name: &str,
+        arity: Option<usize>,
+        function: impl Fn(&[SqlValue]) -> Result<SqlValue, String> + 'static,
+    ) {
+        self.scalars
+            .insert((name.to_uppercase(), arity), Rc::new(function));
+    }
+
+    /// Add an aggregate, replacing any registered under the same name and
+    /// arity. Each group's state starts as `A::default()`.
+    pub fn register_aggregate<A: Default + 'static>(
+        &mut self,
+        name: &str,
+        arity: Option<usize>,
+        step: impl Fn(&mut A, &[SqlValue]) -> Result<(), String> + 'static,
+        finalize: impl Fn(&A) -> Result<SqlValue, String> + 'static,
+    ) {
+        let function = AggregateFunction {
+            init: Box::new(|| Box::new(A::default())),
+            step: Box::new(move |state, args| match state.downcast_mut::<A>() {
+                Some(state) => step(state, args),
+                None => Err("Aggregate state has the wrong type".to_string()),
+            }),
+            finalize: Box::new(move |state| match state.downcast_ref::<A>() {
+                Some(state) => finalize(state),
+                None => Err("Aggregate state has the wrong type".to_string()),
+            }),
+        };
+        self.aggregates
+            .insert((name.to_uppercase(), arity), Rc::new(function));
+    }
+
+    /// The aggregate a call of `name` with `args` arguments runs, if it is
+    /// one
+    pub fn aggregate(&self, name: &str, args: usize) -> Option<Rc<AggregateFunction>> {
+        let name = name.to_uppercase();
+        self.aggregates
+            .get(&(name.clone(), Some(args)))
+            .or_else(|| self.aggregates.get(&(name, None)))
+            .cloned()
+    }
+
+    /// Names of every registered function, sorted
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .scalars
+            .keys()
+            .chain(self.aggregates.keys())
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// The scalar function a call of `name` with `args` arguments runs
+    pub fn resolve(&self, name: &str, args: usize) -> Result<&ScalarFunction, String> {
+        let name = name.to_uppercase();
+        if let Some(function) = self
+            .scalars
+            .get(&(name.clone(), Some(args)))
+            .or_else(|| self.scalars.get(&(name.clone(), None)))
+        {
+            return Ok(function);
+        }
+        let mut known = self.scalars.keys().chain(self.aggregates.keys());
+        if known.any(|(n, _)| *n == name) {
+            Err(format!("Wrong number of arguments to function {}()", name))
+        } else {
+            Err(format!("Unknown function '{}'", name))
+        }
+    }
+}
+
+/// An argument as text; NULL has none
+fn text(value: &SqlValue) -> Option<String> {
+    match value {
+        SqlValue::Null => None,
+        value => Some(value.to_string()),
+    }
+}
+
+/// Apply `f` to an argument's text, passing NULL through
+fn map_text(value: &SqlValue, f: impl FnOnce(String) -> SqlValue) -> SqlValue {
+    text(value).map_or(SqlValue::Null, f)
+}
+
+fn type_error(function: &str, expected: &str, got: &SqlValue) -> String {
+    let got = match got {
+        SqlValue::Text(s) => format!("'{}'", s),
+        other => other.to_string(),
+    };
+    format!("{}() expects {}, got {}", function, expected, got)
+}
+
+/// An argument that must be an integer; NULL gives None
+fn integer_arg(value: &SqlValue, function: &str) -> Result<Option<i64>, String> {
+    match value {
+        SqlValue::Null => Ok(None),
+        SqlValue::Real(r) if r.fract() == 0.0 => Ok(Some(*r as i64)),
+        value => value
+            .as_integer()
+            .map(Some)
+            .ok_or_else(|| type_error(function, "an integer", value)),
+    }
+}
+
+/// An argument that must be a number; NULL gives None
+fn real_arg(value: &SqlValue, function: &str) -> Result<Option<f64>, String> {
+    match value {
+        SqlValue::Null => Ok(None),
+        value => value
+            .as_real()
+            .map(Some)
+            .ok_or_else(|| type_error(function, "a number", value)),
+    }
+}
+
+/// SUBSTR(s, start [, length]): `start` counts characters from 1, or back
+/// from the end when negative; a negative length takes the characters
+/// before `start`
+fn substr(args: &[SqlValue]) -> Result<SqlValue, String> {
+    let Some(s) = text(&args[0]) else {
+        return Ok(SqlValue::Null);
+    };
+    let Some(start) = integer_arg(&args[1], "SUBSTR")? else {
+        return Ok(SqlValue::Null);
+    };
+    let length = match args.get(2) {
+        Some(length) => match integer_arg(length, "SUBSTR")? {
+            Some(length) => Some(length),
+            None => return Ok(SqlValue::Null),
+        },
+        None => None,
+    };
+
+    let chars: Vec<char> = s.chars().collect();
+    let n = chars.len() as i64;
+    let begin = if start < 0 {
+        n.saturating_add(start)
+    } else {
+        start.saturating_sub(1)
+    };
+    let (begin, end) = match length {
+        Some(length) if length < 0 => (begin.saturating_add(length), begin),
+        Some(length) => (begin, begin.saturating_add(length)),
+        None => (begin, n),
+    };
+    let clamp = |i: i64| i.clamp(0, n) as usize;
+    Ok(SqlValue::Text(
+        chars[clamp(begin)..clamp(end)].iter().collect(),
+    ))
+}
+
+/// TRIM(s [, characters]): strip spaces, or the given characters, from
+/// both ends
+fn trim(args: &[SqlValue]) -> Result<SqlValue, String> {
+    let Some(s) = text(&args[0]) else {
+        return Ok(SqlValue::Null);
+    };
+    let strip = match args.get(1) {
+        Some(value) => match text(value) {
+            Some(strip) => strip,
+            None => return Ok(SqlValue::Null),
+        },
+        None => " ".to_string(),
+    };
+    Ok(SqlValue::Text(
+        s.trim_matches(|c| strip.contains(c)).to_string(),
+    ))
+}
+
+fn abs(args: &[SqlValue]) -> Result<SqlValue, String> {
+    match &args[0] {
+        SqlValue::Null => Ok(SqlValue::Null),
+        SqlValue::Real(r) => Ok(SqlValue::Real(r.abs())),
+        value => match value.as_integer() {
+            Some(n) => n
+                .checked_abs()
+                .map(SqlValue::Integer)
+                .ok_or_else(|| "Integer overflow in ABS()".to_string()),
+            None => Ok(SqlValue::Real(
+                real_arg(value, "ABS")?.unwrap_or_default().abs(),
+            )),
+        },
+    }
+}
+
+/// ROUND(x [, digits]): x rounded half away from zero to `digits` decimal
+/// places, as a real
+fn round(args: &[SqlValue]) -> Result<SqlValue, String> {
+    let Some(x) = real_arg(&args[0], "ROUND")? else {
+        return Ok(SqlValue::Null);
+    };
+    let digits = match args.get(1) {
+        Some(digits) => match integer_arg(digits, "ROUND")? {
+            Some(digits) => digits.clamp(0, 15),
+            None => return Ok(SqlValue::Null),
+        },
+        None => 0,
+    };
+    let scale = 10f64.powi(digits as i32);
+    Ok(SqlValue::Real((x * scale).round() / scale))
+}
+
+/// PRINTF(format, ...), also called FORMAT: substitute the arguments into
+/// `%[flags][width][.precision]` conversions d, i, f, s, x, X, o and c.
+/// `%%` is a literal percent sign; missing arguments count as NULL.
+fn printf(args: &[SqlValue]) -> Result<SqlValue, String> {
+    let Some(format) = args.first().and_then(text) else {
+        return Ok(SqlValue::Null);
+    };
+    let mut values = args.iter().skip(1);
+    let mut out = String::new();
+    let mut chars = format.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let mut flags = String::new();
+        while let Some(&flag) = chars.peek() {
+            if !"-+ 0".contains(flag) {
+                break;
+            }
+            flags.push(flag);
+            chars.next();
+        }
+        let width = printf_number(&mut chars, "width")?;
+        let mut precision = None;
+        if chars.peek() == Some(&'.') {
+            chars.next();
+            precision = Some(printf_number(&mut chars, "precision")?);
+        }
+
+        let conversion = chars
+            .next()
+            .ok_or("PRINTF() format ends inside a conversion")?;
+        if conversion == '%' {
+            out.push('%');
+            continue;
+        }
+        let value = values.next().unwrap_or(&SqlValue::Null);
+
+        // Numbers from NULL are 0
+        let integer = || -> Result<i64, String> {
+            match value {
+                SqlValue::Real(r) => Ok(*r as i64),
+                value => Ok(integer_arg(value, "PRINTF")?.unwrap_or(0)),
+            }
+        };
+        let (sign, body) = match conversion {
+            'd' | 'i' => {
+                let n = integer()?;
+                let sign = if n < 0 { "-" } else { "" };
+                (sign, n.unsigned_abs().to_string())
+            }
+            'f' => {
+                let r = real_arg(value, "PRINTF")?.unwrap_or(0.0);
+                let sign = if r.is_sign_negative() && r != 0.0 {
+                    "-"
+                } else {
+                    ""
+                };
+                (sign, format!("{:.*}", precision.unwrap_or(6), r.abs()))
+            }
+            'x' => ("", format!("{:x}", integer()?)),
+            'X' => ("", format!("{:X}", integer()?)),
+            'o' => ("", format!("{:o}", integer()?)),
+            'c' => (
+                "",
+                text(value)
+                    .and_then(|s| s.chars().next())
+                    .map_or(String::new(), String::from),
+            ),
+            's' => {
+                let s = text(value).unwrap_or_default();
+                match precision {
+                    Some(precision) => ("", s.chars().take(precision).collect()),
+                    None => ("", s),
+                }
+            }
+            other => return Err(format!("PRINTF() does not support %{}", other)),
+        };
+
+        let numeric = matches!(conversion, 'd' | 'i' | 'f');
+        let sign = match sign {
+            "" if numeric && flags.contains('+') => "+",
+            "" if numeric && flags.contains(' ') => " ",
+            sign => sign,
+        };
+        let len = sign.len() + body.chars().count();
+        let pad = width.saturating_sub(len);
+        if flags.contains('-') {
+            out.push_str(sign);
+            out.push_str(&body);
+            out.push_str(&" ".repeat(pad));
+        } else if flags.contains('0') && numeric {
+            out.push_str(sign);
+            out.push_str(&"0".repeat(pad));
+            out.push_str(&body);
+        } else {
+            out.push_str(&" ".repeat(pad));
+            out.push_str(sign);
+            out.push_str(&body);
+        }
+    }
+    Ok(SqlValue::Text(out))
+}
+
+/// Largest width or precision PRINTF() accepts, so a format can't ask for
+/// a gigabyte of padding
+const PRINTF_MAX_WIDTH: usize = 10_000;
+
+/// Read the digits of a width or precision
+fn printf_number(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    what: &str,
+) -> Result<usize, String> {
+    let mut number: usize = 0;
+    while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+        number = number
+            .checked_mul(10)
+            .and_then(|n| n.checked_add(digit as usize))
+            .filter(|&n| n <= PRINTF_MAX_WIDTH)
+            .ok_or_else(|| format!("PRINTF() {} is larger than {}", what, PRINTF_MAX_WIDTH))?;
+        chars.next();
+    }
+    Ok(number)
+}
+
+/// A moment in time, in milliseconds since 1970-01-01 00:00:00 UTC
+type Millis = i64;
+
+/// Moments must lie within 10,000 years of year 0 either way, which keeps
+/// the calendar arithmetic far from overflowing
+const MAX_YEAR: i64 = 10_000;
+
+/// `time`, if it lies within the supported range
+fn in_range(time: Millis) -> Option<Millis> {
+    let first = days_from_civil(-MAX_YEAR, 1, 1) * MS_PER_DAY;
+    let last = days_from_civil(MAX_YEAR, 1, 1) * MS_PER_DAY;
+    (first..last).contains(&time).then_some(time)
+}
+
+const MS_PER_DAY: i64 = 86_400_000;
+
+/// The Julian day number of the Unix epoch
+const UNIX_EPOCH_JULIAN_DAY: f64 = 2_440_587.5;
+
+fn now() -> Millis {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as i64)
+}
+
+fn julian_day(time: Millis) -> f64 {
+    time as f64 / MS_PER_DAY as f64 + UNIX_EPOCH_JULIAN_DAY
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+/// Days past the end of the month carry into the next.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The (year, month, day) `days` after 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// The moment a date function's arguments describe: a time value, then
+/// modifiers applied in turn. With no arguments it is now.
+fn moment(args: &[SqlValue]) -> Option<Millis> {
+    let Some(value) = args.first() else {
+        return Some(now());
+    };
+    let mut modifiers = args[1..]
+        .iter()
+        .map(|m| text(m).map(|m| m.trim().to_lowercase()))
+        .collect::<Option<Vec<_>>>()?;
+
+    // 'unixepoch' right after a number reads it as seconds since 1970
+    let mut time = if modifiers.first().is_some_and(|m| m == "unixepoch") {
+        modifiers.remove(0);
+        (value.as_real()? * 1000.0).round() as Millis
+    } else {
+        parse_time(value)?
+    };
+    time = in_range(time)?;
+    for modifier in &modifiers {
+        time = in_range(modify(time, modifier)?)?;
+    }
+    Some(time)
+}
+
+/// Read a time value: 'now', 'YYYY-MM-DD' optionally followed by
+/// ' HH:MM[:SS[.SSS]]' (or with a 'T' in place of the space), or a
+/// Julian day number
+fn parse_time(value: &SqlValue) -> Option<Millis> {
+    let julian = |day: f64| ((day - UNIX_EPOCH_JULIAN_DAY) * MS_PER_DAY as f64).round() as Millis;
+    let s = match value {
+        SqlValue::Null => return None,
+        SqlValue::Integer(n) => return Some(julian(*n as f64)),
+        SqlValue::Real(r) => return Some(julian(*r)),
+        SqlValue::Text(s) => s.trim(),
+    };
+    if s.eq_ignore_ascii_case("now") {
+        return Some(now());
+    }
+    if let Ok(day) = s.parse::<f64>() {
+        return Some(julian(day));
+    }
+
+    let (date, time) = match s.find([' ', 'T']) {
+        Some(i) => (&s[..i], Some(s[i + 1..].trim())),
+        None => (s, None),
+    };
+    let fields: Vec<&str> = date.split('-').collect();
+    let [year, month, day] = fields[..] else {
+        return None;
+    };
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+    let (year, month, day): (i64, i64, i64) =
+        (year.parse().ok()?, month.parse().ok()?, day.parse().ok()?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let mut millis = 0;
+    if let Some(time) = time {
+        let fields: Vec<&str> = time.split(':').collect();
+        let (hour, minute, second) = match fields[..] {
+            [hour, minute] => (hour, minute, "0"),
+            [hour, minute, second] => (hour, minute, second),
+            _ => return None,
+        };
+        let (hour, minute): (i64, i64) = (hour.parse().ok()?, minute.parse().ok()?);
+        let second: f64 = second.parse().ok()?;
+        if hour > 23 || minute > 59 || !(0.0..60.0).contains(&second) {
+            return None;
+        }
+        millis = hour * 3_600_000 + minute * 60_000 + (second * 1000.0).round() as i64;
+    }
+    Some(days_from_civil(year, month, day) * MS_PER_DAY + millis)
+}
+
+/// Apply one modifier: 'start of day', 'start of month', 'start of year',
+/// or '+N' / '-N' days, hours, minutes, seconds, months or years. None
+/// when the result would overflow.
+fn modify(time: Millis, modifier: &str) -> Option<Millis> {
+    let days = time.div_euclid(MS_PER_DAY);
+    let millis = time.rem_euclid(MS_PER_DAY);
+    let (year, month, day) = civil_from_days(days);
+    match modifier {
+        "start of day" => return Some(days * MS_PER_DAY),
+        "start of month" => return Some(days_from_civil(year, month, 1) * MS_PER_DAY),
+        "start of year" => return Some(days_from_civil(year, 1, 1) * MS_PER_DAY),
+        _ => {}
+    }
+
+    let mut words = modifier.split_whitespace();
+    let amount: f64 = words.next()?.parse().ok()?;
+    let unit = words.next()?;
+    if words.next().is_some() {
+        return None;
+    }
+    let scaled = |unit_ms: i64| {
+        // The cast saturates, so a huge amount overflows the add or leaves
+        // the supported range
+        let delta = (amount * unit_ms as f64).round() as Millis;
+        time.checked_add(delta)
+    };
+    match unit.trim_end_matches('s') {
+        "day" => scaled(MS_PER_DAY),
+        "hour" => scaled(3_600_000),
+        "minute" => scaled(60_000),
+        "second" => scaled(1000),
+        // Whole months keep the day, which may carry into the month after
+        unit @ ("month" | "year") if amount.fract() == 0.0 => {
+            let months = (amount as i64).checked_mul(if unit == "year" { 12 } else { 1 })?;
+            let total = (year * 12 + (month - 1)).checked_add(months)?;
+            if total.div_euclid(12).abs() > MAX_YEAR {
+                return None;
+            }
+            let days = days_from_civil(total.div_euclid(12), total.rem_euclid(12) + 1, day);
+            Some(days * MS_PER_DAY + millis)
+        }
+        _ => None,
+    }
+}
+
+/// Format a moment with strftime conversions: %Y %m %d %H %M %S, %f
+/// (seconds with milliseconds), %j (day of year), %J (Julian day), %s
+/// (Unix seconds), %w (weekday, Sunday is 0), %W (week of the year, weeks
+/// starting on Monday, with days before the first Monday in week 00) and
+/// %%. None when there is no moment or the format is unknown.
+fn formatted(time: Option<Millis>, format: &str) -> SqlValue {
+    time.and_then(|time| format_time(time, format))
+        .map_or(SqlValue::Null, SqlValue::Text)
+}
+
+fn format_time(time: Millis, format: &str) -> Option<String> {
+    let days = time.div_euclid(MS_PER_DAY);
+    let millis = time.rem_euclid(MS_PER_DAY);
+    let (year, month, day) = civil_from_days(days);
+    let (hour, minute, second) = (millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60);
+
+    let mut out = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let field = match chars.next()? {
+            'Y' => format!("{:04}", year),
+            'm' => format!("{:02}", month),
+            'd' => format!("{:02}", day),
+            'H' => format!("{:02}", hour),
+            'M' => format!("{:02}", minute),
+            'S' => format!("{:02}", second),
+            'f' => format!("{:02}.{:03}", second, millis % 1000),
+            'j' => format!("{:03}", days - days_from_civil(year, 1, 1) + 1),
+            'J' => SqlValue::Real(julian_day(time)).to_string(),
+            's' => time.div_euclid(1000).to_string(),
+            // 1970-01-01 was a Thursday
+            'w' => (days + 4).rem_euclid(7).to_string(),
+            'W' => {
+                let day_of_year = days - days_from_civil(year, 1, 1);
+                let days_since_monday = (days + 3).rem_euclid(7);
+                format!("{:02}", (day_of_year + 7 - days_since_monday) / 7)
+            }
+            '%' => "%".to_string(),
+            _ => return None,
+        };
+        out.push_str(&field);
+    }
+    Some(out)
+}
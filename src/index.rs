@@ -3,10 +3,13 @@
 //! This module implements secondary indexes using B-Trees for fast lookups
 //! on non-primary-key columns.
 
-use crate::btree::*;
-use crate::pager::Pager;
-use std::collections::HashMap;
-use std::ptr;
+use crate::btree::{BTree, BTreeKey, Cursor};
+use crate::pager::schema_cookie;
+use crate::value::{parse_number, SqlValue};
+use std::cmp::Ordering;
+
+/// Size of the type tag that starts every index key
+const INDEX_TAG_SIZE: usize = 1;
 
 /// Size of the row ID stored in index entries
 const INDEX_ROW_ID_SIZE: usize = 4;
@@ -14,52 +17,162 @@ const INDEX_ROW_ID_SIZE: usize = 4;
 /// Maximum key size for indexed values (truncated if longer)
 const INDEX_KEY_SIZE: usize = 64;
 
-/// Cell size in index B-Tree: key (64 bytes) + row_id (4 bytes)
-const INDEX_CELL_SIZE: usize = INDEX_KEY_SIZE + INDEX_ROW_ID_SIZE;
+// --- Index Key Type Tags ---
+const TAG_NULL: u8 = 0;
+const TAG_INTEGER: u8 = 1;
+const TAG_REAL: u8 = 2;
+const TAG_TEXT: u8 = 3;
+
+/// A range bound on indexed values: the value and whether it is inclusive
+pub type ValueBound<'a> = Option<(&'a SqlValue, bool)>;
+
+/// The B-Tree key of an index entry: the indexed value, then the row's
+/// primary key. Every entry is unique even when values repeat, and rows
+/// with the same value are kept in row ID order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexKey {
+    pub value: SqlValue,
+    pub row_id: u32,
+}
+
+impl Eq for IndexKey {}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Values in the order ORDER BY sorts a column in
+        self.value
+            .sort_order(&other.value)
+            .then(self.row_id.cmp(&other.row_id))
+    }
+}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl IndexKey {
+    /// The key for `value`, with text cut down to the first
+    /// `INDEX_KEY_SIZE` bytes
+    pub fn new(value: &SqlValue, row_id: u32) -> Self {
+        let value = match value {
+            SqlValue::Text(s) => {
+                let mut len = s.len().min(INDEX_KEY_SIZE);
+                while !s.is_char_boundary(len) {
+                    len -= 1;
+                }
+                SqlValue::Text(s[..len].to_string())
+            }
+            value => value.clone(),
+        };
+        IndexKey { value, row_id }
+    }
+
+    /// Whether the value may have been cut short. Text is cut at a
+    /// character boundary, so text that was can be up to 3 bytes shorter
+    /// than the limit.
+    pub fn truncated(&self) -> bool {
+        matches!(&self.value, SqlValue::Text(s) if s.len() + 3 >= INDEX_KEY_SIZE)
+    }
+}
+
+impl BTreeKey for IndexKey {
+    const SIZE: usize = INDEX_TAG_SIZE + INDEX_KEY_SIZE + INDEX_ROW_ID_SIZE;
+
+    fn write(&self, buf: &mut [u8]) {
+        let value = &mut buf[INDEX_TAG_SIZE..INDEX_TAG_SIZE + INDEX_KEY_SIZE];
+        value.fill(0);
+        buf[0] = match &self.value {
+            SqlValue::Null => TAG_NULL,
+            SqlValue::Integer(n) => {
+                value[..8].copy_from_slice(&n.to_le_bytes());
+                TAG_INTEGER
+            }
+            SqlValue::Real(r) => {
+                value[..8].copy_from_slice(&r.to_le_bytes());
+                TAG_REAL
+            }
+            SqlValue::Text(s) => {
+                value[..s.len()].copy_from_slice(s.as_bytes());
+                TAG_TEXT
+            }
+        };
+        buf[INDEX_TAG_SIZE + INDEX_KEY_SIZE..Self::SIZE]
+            .copy_from_slice(&self.row_id.to_le_bytes());
+    }
+
+    fn read(buf: &[u8]) -> Self {
+        let data = &buf[INDEX_TAG_SIZE..INDEX_TAG_SIZE + INDEX_KEY_SIZE];
+        let value = match buf[0] {
+            TAG_INTEGER => SqlValue::Integer(i64::from_le_bytes(data[..8].try_into().unwrap())),
+            TAG_REAL => SqlValue::Real(f64::from_le_bytes(data[..8].try_into().unwrap())),
+            TAG_TEXT => SqlValue::Text(
+                String::from_utf8_lossy(data)
+                    .trim_end_matches(char::from(0))
+                    .to_string(),
+            ),
+            _ => SqlValue::Null,
+        };
+        let row_id = u32::from_le_bytes(
+            buf[INDEX_TAG_SIZE + INDEX_KEY_SIZE..Self::SIZE]
+                .try_into()
+                .unwrap(),
+        );
+        IndexKey { value, row_id }
+    }
+}
+
+/// The stretches of text that can spell a number: starting with a sign, a
+/// point or a digit, or spelling infinity
+const NUMBER_SPELLINGS: [(&str, &str); 3] = [("+", ":"), ("I", "J"), ("i", "j")];
 
 /// Secondary index structure
 pub struct Index {
     pub name: String,
-    pub table_name: String,
     pub column_name: String,
     pub unique: bool,
-    pub pager: Pager,
-    pub root_page_num: u32,
+    pub filename: String,
+    /// Entries keyed by value and row ID, with no value bytes of their own
+    pub tree: BTree<IndexKey>,
 }
 
 impl Index {
-    /// Create a new secondary index
-    pub fn new(name: &str, table_name: &str, column_name: &str, unique: bool) -> Self {
-        let filename = format!("{}_{}.idx", table_name, name);
-        let mut pager = Pager::open(&filename).expect("Failed to open index file");
-
-        // Initialize root page as leaf node
-        if pager.num_pages == 0 {
-            let root_page = pager.get_page(0);
-            initialize_leaf_node(root_page);
-            set_node_root(root_page, true);
-            pager.num_pages = 1;
-            pager.flush(0);
-        }
-
-        Index {
+    /// Open the index stored in `filename`, creating an empty one if the
+    /// file doesn't exist yet
+    pub fn new(
+        filename: &str,
+        name: &str,
+        table_name: &str,
+        column_name: &str,
+        unique: bool,
+    ) -> Result<Self, String> {
+        // The file belongs to an index on this column, unique or not
+        let schema_cookie = schema_cookie(&format!("{}.{} {}", table_name, column_name, unique));
+
+        Ok(Index {
             name: name.to_string(),
-            table_name: table_name.to_string(),
             column_name: column_name.to_string(),
             unique,
-            pager,
-            root_page_num: 0,
-        }
+            filename: filename.to_string(),
+            tree: BTree::open(filename, 0, schema_cookie)?,
+        })
     }
 
     /// Insert a key-value pair into the index
     /// key_value: the indexed column value
     /// row_id: the primary key of the row
-    pub fn insert(&mut self, key_value: &str, row_id: u32) -> Result<(), String> {
+    pub fn insert(&mut self, key_value: &SqlValue, row_id: u32) -> Result<(), String> {
         // Check uniqueness constraint
         if self.unique {
-            let existing = self.find(key_value);
-            if !existing.is_empty() {
+            let key = IndexKey::new(key_value, 0);
+            let mut cursor = IndexCursor::seek(self, &key);
+            if cursor.next(self).is_some_and(|existing| {
+                existing
+                    .value
+                    .compare(&key.value)
+                    .is_some_and(|ord| ord.is_eq())
+            }) {
                 return Err(format!(
                     "UNIQUE constraint failed: {} already exists in index {}",
                     key_value, self.name
@@ -67,332 +180,164 @@ impl Index {
             }
         }
 
-        let leaf_page_num = self.find_leaf(key_value);
-        let page = self.pager.get_page(leaf_page_num as usize);
-        let num_cells = leaf_node_num_cells(page);
-        let max_cells = leaf_node_max_cells(INDEX_CELL_SIZE);
-
-        if num_cells as usize >= max_cells {
-            // Need to split - for simplicity, we'll just insert and handle overflow
-            // A full implementation would split like the main table B-Tree
-            self.split_and_insert(leaf_page_num, key_value, row_id);
-        } else {
-            // Find insertion position
-            let slot = self.find_slot(leaf_page_num, key_value);
-            self.leaf_node_insert(leaf_page_num, slot, key_value, row_id);
-        }
-
-        Ok(())
+        self.tree.insert(IndexKey::new(key_value, row_id), &[])
     }
 
     /// Delete an entry from the index
-    pub fn delete(&mut self, key_value: &str, row_id: u32) -> Result<(), String> {
-        let leaf_page_num = self.find_leaf(key_value);
-        let page = self.pager.get_page(leaf_page_num as usize);
-        let num_cells = leaf_node_num_cells(page);
-
-        // Find and remove the entry with matching key AND row_id
-        for i in 0..num_cells {
-            let (stored_key, stored_row_id) = self.read_cell(leaf_page_num, i);
-            if stored_key == key_value && stored_row_id == row_id {
-                // Shift remaining cells left
-                let page = self.pager.get_page(leaf_page_num as usize);
-                for j in i..num_cells - 1 {
-                    unsafe {
-                        let src = leaf_node_cell(page, j + 1, INDEX_CELL_SIZE);
-                        let dst = leaf_node_cell(page, j, INDEX_CELL_SIZE);
-                        ptr::copy(src, dst, INDEX_CELL_SIZE);
-                    }
-                }
-                set_leaf_node_num_cells(page, num_cells - 1);
-                self.pager.flush(leaf_page_num as usize);
-                return Ok(());
-            }
-        }
-
+    pub fn delete(&mut self, key_value: &SqlValue, row_id: u32) -> Result<(), String> {
+        self.tree.delete(&IndexKey::new(key_value, row_id));
         Ok(()) // Not found, that's okay
     }
 
-    /// Find all row IDs matching the given key value
-    pub fn find(&mut self, key_value: &str) -> Vec<u32> {
+    /// Find the row IDs whose values may equal `key_value` as SQL compares
+    /// them. Text equals text spelled the same way and a number it spells,
+    /// so besides the entries holding the value itself this checks the
+    /// numbers or the text that could spell one. Values cut short to fit
+    /// the key are kept when they might match.
+    pub fn find(&mut self, key_value: &SqlValue) -> Vec<u32> {
+        let bound = Some((key_value, true));
+        self.range(bound, bound)
+            .into_iter()
+            .map(|key| key.row_id)
+            .collect()
+    }
+
+    /// All entries in key order, following the leaf chain from the leftmost leaf
+    pub fn entries(&mut self) -> Vec<(SqlValue, u32)> {
         let mut results = Vec::new();
-        let leaf_page_num = self.find_leaf(key_value);
-        let page = self.pager.get_page(leaf_page_num as usize);
-        let num_cells = leaf_node_num_cells(page);
-
-        for i in 0..num_cells {
-            let (stored_key, row_id) = self.read_cell(leaf_page_num, i);
-            if stored_key == key_value {
-                results.push(row_id);
-            }
+        let mut cursor = IndexCursor::start(self);
+        while let Some(key) = cursor.next(self) {
+            results.push((key.value, key.row_id));
         }
-
         results
     }
 
-    /// Find the leaf node that should contain the given key
-    fn find_leaf(&mut self, key_value: &str) -> u32 {
-        let mut page_num = self.root_page_num;
-
-        loop {
-            let page = self.pager.get_page(page_num as usize);
-            if get_node_type(page) == NodeType::Leaf {
-                return page_num;
-            }
-
-            // Internal node - find child
-            let num_keys = internal_node_num_keys(page);
-            let mut child_num = num_keys;
-
-            // Read keys inline to avoid borrow issues
-            for i in 0..num_keys {
-                let offset = INTERNAL_NODE_HEADER_SIZE
-                    + (i as usize * (INTERNAL_NODE_CHILD_SIZE + INDEX_KEY_SIZE))
-                    + INTERNAL_NODE_CHILD_SIZE;
-                let key_bytes = &page[offset..offset + INDEX_KEY_SIZE];
-                let key_at_i = String::from_utf8_lossy(key_bytes)
-                    .trim_matches(char::from(0))
-                    .to_string();
-
-                if key_value <= &key_at_i {
-                    child_num = i;
-                    break;
-                }
-            }
-
-            page_num = internal_node_child(page, child_num);
+    /// The entries whose values may lie within the bounds, in index order.
+    /// Values compare with the bounds as SQL compares them, so the entries
+    /// come from two stretches of the index: the numbers, and the text.
+    /// Each is entered with a seek and left as soon as it passes the upper
+    /// bound; against a numeric upper bound only the text that can spell a
+    /// number is looked at. Values cut short to fit the key are kept when
+    /// they might match.
+    pub fn range(&mut self, lower: ValueBound, upper: ValueBound) -> Vec<IndexKey> {
+        let within = |value: &SqlValue| {
+            let check = |bound: ValueBound, wanted: Ordering| {
+                bound.is_none_or(|(bound, inclusive)| match value.compare(bound) {
+                    Some(ord) => ord == wanted || (inclusive && ord.is_eq()),
+                    None => false,
+                })
+            };
+            check(lower, Ordering::Greater) && check(upper, Ordering::Less)
+        };
+        // Text that doesn't spell a number sorts above every number
+        let as_number = |bound: ValueBound| match bound {
+            Some((SqlValue::Text(s), _)) => parse_number(s),
+            Some((value, _)) => Some(value.clone()),
+            None => None,
+        };
+        let is_text = |bound: ValueBound| matches!(bound, Some((SqlValue::Text(_), _)));
+        let mut results = Vec::new();
+        // Nothing compares with NULL
+        if [lower, upper]
+            .iter()
+            .any(|bound| matches!(bound, Some((SqlValue::Null, _))))
+        {
+            return results;
         }
-    }
 
-    /// Find the slot where a key should be inserted
-    fn find_slot(&mut self, page_num: u32, key_value: &str) -> u32 {
-        let page = self.pager.get_page(page_num as usize);
-        let num_cells = leaf_node_num_cells(page);
-
-        // Collect keys to compare
-        let mut keys: Vec<String> = Vec::new();
-        for i in 0..num_cells {
-            let offset = LEAF_NODE_HEADER_SIZE + (i as usize * INDEX_CELL_SIZE);
-            let key_bytes = &page[offset..offset + INDEX_KEY_SIZE];
-            let key = String::from_utf8_lossy(key_bytes)
-                .trim_matches(char::from(0))
-                .to_string();
-            keys.push(key);
+        // Numbers, unless the lower bound is text that sorts above them
+        let numbers_start = match (lower, as_number(lower)) {
+            (None, _) => Some(SqlValue::Real(f64::NEG_INFINITY)),
+            (_, Some(number)) => Some(number),
+            (Some(_), None) => None,
+        };
+        if let Some(number) = numbers_start {
+            let last = as_number(upper);
+            self.scan(&IndexKey::new(&number, 0), &mut results, |key| {
+                let past = match &last {
+                    Some(last) => key.value.compare(last).is_none_or(|ord| ord.is_gt()),
+                    None => false,
+                };
+                (!matches!(key.value, SqlValue::Text(_)) && !past).then(|| within(&key.value))
+            });
         }
 
-        for (i, stored_key) in keys.iter().enumerate() {
-            if key_value <= stored_key {
-                return i as u32;
+        // Text compares byte by byte with text bounds and is read as a
+        // number against numeric ones
+        if upper.is_some() && !is_text(upper) {
+            for (first, last) in NUMBER_SPELLINGS {
+                let (first, last) = (SqlValue::Text(first.into()), SqlValue::Text(last.into()));
+                self.scan(&IndexKey::new(&first, 0), &mut results, |key| {
+                    (key.value.sort_order(&last).is_lt())
+                        .then(|| key.truncated() || within(&key.value))
+                });
             }
+        } else {
+            let start = match lower {
+                Some((SqlValue::Text(s), _)) => SqlValue::Text(s.clone()),
+                _ => SqlValue::Text(String::new()),
+            };
+            self.scan(&IndexKey::new(&start, 0), &mut results, |key| {
+                let past = match (upper, &key.value) {
+                    (Some((bound, _)), value) => value.sort_order(bound).is_gt(),
+                    (None, _) => false,
+                };
+                (!past).then(|| key.truncated() || within(&key.value))
+            });
         }
 
-        num_cells
-    }
-
-    /// Read a cell from the index leaf node
-    fn read_cell(&mut self, page_num: u32, cell_num: u32) -> (String, u32) {
-        let page = self.pager.get_page(page_num as usize);
-        let offset = LEAF_NODE_HEADER_SIZE + (cell_num as usize * INDEX_CELL_SIZE);
-
-        // Read key (first INDEX_KEY_SIZE bytes)
-        let key_bytes = &page[offset..offset + INDEX_KEY_SIZE];
-        let key = String::from_utf8_lossy(key_bytes)
-            .trim_matches(char::from(0))
-            .to_string();
-
-        // Read row_id (next 4 bytes)
-        let row_id = unsafe {
-            ptr::read_unaligned(page.as_ptr().add(offset + INDEX_KEY_SIZE) as *const u32)
-        };
-
-        (key, row_id)
-    }
-
-    /// Read internal node key
-    fn read_internal_key(&mut self, page_num: u32, key_num: u32) -> String {
-        let page = self.pager.get_page(page_num as usize);
-        let offset = INTERNAL_NODE_HEADER_SIZE
-            + (key_num as usize * (INTERNAL_NODE_CHILD_SIZE + INDEX_KEY_SIZE))
-            + INTERNAL_NODE_CHILD_SIZE;
-
-        let key_bytes = &page[offset..offset + INDEX_KEY_SIZE];
-        String::from_utf8_lossy(key_bytes)
-            .trim_matches(char::from(0))
-            .to_string()
+        results
     }
 
-    /// Insert into a leaf node
-    fn leaf_node_insert(&mut self, page_num: u32, slot: u32, key_value: &str, row_id: u32) {
-        let page = self.pager.get_page(page_num as usize);
-        let num_cells = leaf_node_num_cells(page);
-
-        // Shift cells to make room
-        if slot < num_cells {
-            for i in (slot..num_cells).rev() {
-                unsafe {
-                    let src = leaf_node_cell(page, i, INDEX_CELL_SIZE);
-                    let dst = leaf_node_cell(page, i + 1, INDEX_CELL_SIZE);
-                    ptr::copy(src, dst, INDEX_CELL_SIZE);
-                }
+    /// Walk the index from `start`, adding the entries `visit` keeps, until
+    /// it returns None
+    fn scan(
+        &mut self,
+        start: &IndexKey,
+        results: &mut Vec<IndexKey>,
+        visit: impl Fn(&IndexKey) -> Option<bool>,
+    ) {
+        let mut cursor = IndexCursor::seek(self, start);
+        while let Some(key) = cursor.next(self) {
+            match visit(&key) {
+                Some(true) => results.push(key),
+                Some(false) => {}
+                None => break,
             }
         }
-
-        // Write the new cell
-        let cell_ptr = leaf_node_cell(page, slot, INDEX_CELL_SIZE);
-        unsafe {
-            // Clear the cell first
-            ptr::write_bytes(cell_ptr, 0, INDEX_CELL_SIZE);
-
-            // Write key (truncated to INDEX_KEY_SIZE)
-            let key_bytes = key_value.as_bytes();
-            let copy_len = key_bytes.len().min(INDEX_KEY_SIZE);
-            ptr::copy_nonoverlapping(key_bytes.as_ptr(), cell_ptr, copy_len);
-
-            // Write row_id
-            ptr::write_unaligned(cell_ptr.add(INDEX_KEY_SIZE) as *mut u32, row_id);
-        }
-
-        set_leaf_node_num_cells(page, num_cells + 1);
-        self.pager.flush(page_num as usize);
     }
+}
 
-    /// Split a full leaf node and insert (simplified version)
-    fn split_and_insert(&mut self, old_page_num: u32, key_value: &str, row_id: u32) {
-        // Create new page
-        let new_page_num = self.pager.num_pages;
-        self.pager.num_pages += 1;
-
-        let new_page = self.pager.get_page(new_page_num as usize);
-        initialize_leaf_node(new_page);
-
-        // Get old page data
-        let old_page = self.pager.get_page(old_page_num as usize);
-        let num_cells = leaf_node_num_cells(old_page);
-        let split_point = num_cells / 2;
-
-        // Move half the cells to new page
-        for i in split_point..num_cells {
-            let (key, rid) = self.read_cell(old_page_num, i);
-            let new_page = self.pager.get_page(new_page_num as usize);
-            let new_slot = i - split_point;
-
-            let cell_ptr = leaf_node_cell(new_page, new_slot, INDEX_CELL_SIZE);
-            unsafe {
-                ptr::write_bytes(cell_ptr, 0, INDEX_CELL_SIZE);
-                let key_bytes = key.as_bytes();
-                let copy_len = key_bytes.len().min(INDEX_KEY_SIZE);
-                ptr::copy_nonoverlapping(key_bytes.as_ptr(), cell_ptr, copy_len);
-                ptr::write_unaligned(cell_ptr.add(INDEX_KEY_SIZE) as *mut u32, rid);
-            }
-        }
-
-        // Update cell counts
-        let new_page = self.pager.get_page(new_page_num as usize);
-        set_leaf_node_num_cells(new_page, num_cells - split_point);
-
-        let old_page = self.pager.get_page(old_page_num as usize);
-        set_leaf_node_num_cells(old_page, split_point);
-
-        // Link leaves
-        let old_page = self.pager.get_page(old_page_num as usize);
-        let old_next = leaf_node_next_leaf(old_page);
-        set_leaf_node_next_leaf(old_page, new_page_num);
-
-        let new_page = self.pager.get_page(new_page_num as usize);
-        set_leaf_node_next_leaf(new_page, old_next);
-
-        // Decide which page to insert into
-        let (mid_key, _) = self.read_cell(new_page_num, 0);
-        if key_value < &mid_key {
-            let slot = self.find_slot(old_page_num, key_value);
-            self.leaf_node_insert(old_page_num, slot, key_value, row_id);
-        } else {
-            let slot = self.find_slot(new_page_num, key_value);
-            self.leaf_node_insert(new_page_num, slot, key_value, row_id);
-        }
-
-        self.pager.flush(old_page_num as usize);
-        self.pager.flush(new_page_num as usize);
+/// A position among an index's entries, in index order. Like the B-Tree
+/// cursor it wraps, it follows the leaf chain, so a walk crosses leaves
+/// however many entries share a value.
+pub struct IndexCursor {
+    cursor: Cursor,
+}
 
-        // If this was the root, create a new root
-        let old_page = self.pager.get_page(old_page_num as usize);
-        if is_node_root(old_page) {
-            self.create_new_root(old_page_num, &mid_key, new_page_num);
+impl IndexCursor {
+    /// Position at the first entry of the index
+    pub fn start(index: &mut Index) -> Self {
+        IndexCursor {
+            cursor: Cursor::start(&mut index.tree),
         }
     }
 
-    /// Create a new root after splitting
-    fn create_new_root(&mut self, left_child: u32, split_key: &str, right_child: u32) {
-        let new_root_num = self.pager.num_pages;
-        self.pager.num_pages += 1;
-
-        let new_root = self.pager.get_page(new_root_num as usize);
-        initialize_internal_node(new_root);
-        set_node_root(new_root, true);
-        set_internal_node_num_keys(new_root, 1);
-
-        // Set left child
-        set_internal_node_child(new_root, 0, left_child);
-
-        // Set key (store as bytes)
-        let key_offset = INTERNAL_NODE_HEADER_SIZE + INTERNAL_NODE_CHILD_SIZE;
-        unsafe {
-            ptr::write_bytes(new_root.as_mut_ptr().add(key_offset), 0, INDEX_KEY_SIZE);
-            let key_bytes = split_key.as_bytes();
-            let copy_len = key_bytes.len().min(INDEX_KEY_SIZE);
-            ptr::copy_nonoverlapping(
-                key_bytes.as_ptr(),
-                new_root.as_mut_ptr().add(key_offset),
-                copy_len,
-            );
+    /// Position at the first entry at or after `key`; use row ID 0 to
+    /// start at the first entry for a value
+    pub fn seek(index: &mut Index, key: &IndexKey) -> Self {
+        IndexCursor {
+            cursor: Cursor::seek(&mut index.tree, key),
         }
-
-        // Set right child
-        set_internal_node_right_child(new_root, right_child);
-
-        // Update old root
-        let old_root = self.pager.get_page(self.root_page_num as usize);
-        set_node_root(old_root, false);
-        set_parent_pointer(old_root, new_root_num);
-
-        // Update right child parent
-        let right_page = self.pager.get_page(right_child as usize);
-        set_parent_pointer(right_page, new_root_num);
-
-        self.root_page_num = new_root_num;
-
-        self.pager.flush(new_root_num as usize);
-        self.pager.flush(left_child as usize);
-        self.pager.flush(right_child as usize);
     }
 
-    /// Rebuild the index from existing table data
-    pub fn rebuild(&mut self, rows: &[(u32, String)]) -> Result<(), String> {
-        // Clear existing index
-        let filename = format!("{}_{}.idx", self.table_name, self.name);
-        let _ = std::fs::remove_file(&filename);
-        self.pager = Pager::open(&filename).expect("Failed to open index file");
-        let root_page = self.pager.get_page(0);
-        initialize_leaf_node(root_page);
-        set_node_root(root_page, true);
-        self.pager.num_pages = 1;
-        self.root_page_num = 0;
-
-        // Insert all rows
-        for (row_id, key_value) in rows {
-            self.insert(key_value, *row_id)?;
+    /// The entry under the cursor, stepping past it; None at the end
+    pub fn next(&mut self, index: &mut Index) -> Option<IndexKey> {
+        if self.cursor.end_of_table {
+            return None;
         }
-
-        Ok(())
+        let key = self.cursor.key(&mut index.tree);
+        self.cursor.next(&mut index.tree);
+        Some(key)
     }
 }
-
-/// Metadata about indexes for a table, stored separately
-pub struct IndexMetadata {
-    pub indexes: HashMap<String, IndexInfo>,
-}
-
-pub struct IndexInfo {
-    pub column_name: String,
-    pub unique: bool,
-}
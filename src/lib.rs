@@ -0,0 +1,19 @@
+//! RSQL: a disk-backed SQL database engine.
+//!
+//! The REPL in `main.rs` is one user of this library; an application can
+//! embed the engine the same way: tokenize and parse SQL with
+//! [`tokenizer::Tokenizer`] and [`parser::Parser`], run the statements on an
+//! [`executor::Executor`], and register its own SQL functions there.
+
+pub mod btree;
+pub mod eval;
+pub mod executor;
+pub mod functions;
+pub mod index;
+pub mod operator;
+pub mod optimizer;
+pub mod pager;
+pub mod parser;
+pub mod table;
+pub mod tokenizer;
+pub mod value;
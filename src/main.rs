@@ -1,19 +1,13 @@
-mod btree;
 mod completer;
-mod executor;
-mod index;
-mod pager;
-mod parser;
-mod table;
-mod tokenizer;
 
 use colored::Colorize;
 use completer::SqlCompleter;
-use executor::{ExecuteResult, Executor};
-use parser::Parser;
+use rsql::executor::{ExecuteResult, Executor};
+use rsql::parser::{self, Parser};
+use rsql::table;
+use rsql::tokenizer::Tokenizer;
 use rustyline::error::ReadlineError;
 use rustyline::{Config, Editor};
-use tokenizer::Tokenizer;
 
 fn main() {
     let mut executor = Executor::new();
@@ -25,7 +19,9 @@ fn main() {
         .build();
 
     let mut rl = Editor::with_config(config).expect("Failed to create editor");
-    rl.set_helper(Some(SqlCompleter::new()));
+    let mut completer = SqlCompleter::new();
+    completer.set_functions(executor.functions.names());
+    rl.set_helper(Some(completer));
 
     // Load history
     let history_path: std::path::PathBuf = dirs::home_dir()
@@ -132,7 +128,7 @@ fn main() {
 
     // Flush all tables
     for table in executor.tables.values_mut() {
-        table.pager.flush_all();
+        table.tree.flush_all();
     }
 }
 
@@ -147,7 +143,7 @@ fn handle_meta_command(
             // Save history before exiting
             let _ = rl.save_history(history_path);
             for table in executor.tables.values_mut() {
-                table.pager.flush_all();
+                table.tree.flush_all();
             }
             println!("{}", "Goodbye!".green());
             std::process::exit(0);
@@ -319,6 +315,13 @@ fn print_result(result: ExecuteResult) {
         ExecuteResult::TransactionRolledBack => {
             println!("{} Transaction rolled back.", "⟲".yellow().bold());
         }
+        ExecuteResult::TablesAnalyzed(count) => {
+            println!(
+                "{} {} table(s) analyzed.",
+                "✓".green().bold(),
+                count.to_string().cyan()
+            );
+        }
         ExecuteResult::Rows { headers, rows } => {
             if rows.is_empty() {
                 println!("{}", "(empty result)".dimmed());
@@ -367,5 +370,13 @@ fn print_result(result: ExecuteResult) {
                 println!("└{}┘", separator.replace('┼', "┴"));
             }
         }
+        ExecuteResult::Returning {
+            result,
+            headers,
+            rows,
+        } => {
+            print_result(ExecuteResult::Rows { headers, rows });
+            print_result(*result);
+        }
     }
 }
@@ -0,0 +1,1631 @@
+//! Physical query operators
+//!
+//! A query plan is a tree of operators that rows are pulled through one at
+//! a time: `open` prepares an operator (and its inputs), each `next` call
+//! returns the next row or None once it is exhausted, and `close` releases
+//! whatever it buffered. Statements build a plan and drain it; new features
+//! add operators instead of touching the statement handlers.
+
+use crate::btree::Cursor;
+use crate::eval::{evaluate, Scope};
+use crate::functions::{AggregateFunction, FunctionRegistry};
+use crate::index::{Index, IndexKey, ValueBound};
+use crate::parser::{
+    AggregateFunc, Expr, FrameBound, JoinType, SetOperator, WindowFunc, WindowSpec,
+};
+use crate::table::Table;
+use crate::value::SqlValue;
+use std::any::Any;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+pub type Row = Vec<SqlValue>;
+
+/// One end of a range: the bound value and whether it is inclusive
+pub type Bound = (SqlValue, bool);
+
+/// What operators need from the executor while running
+pub struct ExecContext<'a> {
+    pub tables: &'a mut HashMap<String, Table>,
+    /// Scalar functions expressions may call
+    pub functions: &'a FunctionRegistry,
+    /// Rows of the queries enclosing a running correlated subquery,
+    /// innermost last
+    pub outer: Vec<Row>,
+}
+
+impl<'a> ExecContext<'a> {
+    pub fn new(tables: &'a mut HashMap<String, Table>, functions: &'a FunctionRegistry) -> Self {
+        ExecContext {
+            tables,
+            functions,
+            outer: Vec::new(),
+        }
+    }
+
+    fn table(&mut self, name: &str) -> Result<&mut Table, String> {
+        self.tables
+            .get_mut(name)
+            .ok_or_else(|| format!("Table '{}' not found", name))
+    }
+}
+
+pub trait Operator {
+    fn open(&mut self, ctx: &mut ExecContext) -> Result<(), String>;
+
+    fn next(&mut self, ctx: &mut ExecContext) -> Result<Option<Row>, String>;
+
+    fn close(&mut self);
+
+    /// Restart the operator so that it only produces rows whose lookup
+    /// column equals `key`. Index nested-loop joins use this to probe the
+    /// inner table once per outer row.
+    fn rescan(&mut self, _ctx: &mut ExecContext, _key: &SqlValue) -> Result<(), String> {
+        Err("Operator does not support rescans".to_string())
+    }
+
+    /// The operator's name and what it works on, as EXPLAIN shows them
+    fn describe(&self) -> (&'static str, String);
+
+    /// The operators this one pulls rows from
+    fn children(&self) -> Vec<&dyn Operator> {
+        Vec::new()
+    }
+
+    /// How many rows the planner expected this operator to produce
+    fn estimated_rows(&self) -> Option<f64> {
+        None
+    }
+
+    /// Rows produced and time spent, inputs included, once the plan has run
+    fn actual(&self) -> Option<(usize, Duration)> {
+        None
+    }
+}
+
+/// A row's values in a form that compares equal exactly when the rows
+/// hold the same values, NULLs included
+pub fn row_key(row: &Row) -> Vec<Option<String>> {
+    row.iter().map(|value| value.hash_key()).collect()
+}
+
+/// Run a plan to completion and collect its rows
+pub fn collect_rows(plan: &mut dyn Operator, ctx: &mut ExecContext) -> Result<Vec<Row>, String> {
+    plan.open(ctx)?;
+    let mut rows = Vec::new();
+    let result = loop {
+        match plan.next(ctx) {
+            Ok(Some(row)) => rows.push(row),
+            Ok(None) => break Ok(rows),
+            Err(e) => break Err(e),
+        }
+    };
+    plan.close();
+    result
+}
+
+/// Wrap an operator to record the planner's row estimate for it, and the
+/// rows it produces and time it takes when run; EXPLAIN reads both back.
+/// Everything else passes straight through to the wrapped operator.
+pub struct Profile {
+    input: Box<dyn Operator>,
+    estimate: Option<f64>,
+    rows: usize,
+    elapsed: Duration,
+}
+
+impl Profile {
+    pub fn new(input: Box<dyn Operator>, estimate: Option<f64>) -> Self {
+        Profile {
+            input,
+            estimate,
+            rows: 0,
+            elapsed: Duration::ZERO,
+        }
+    }
+}
+
+impl Operator for Profile {
+    fn open(&mut self, ctx: &mut ExecContext) -> Result<(), String> {
+        let started = Instant::now();
+        let result = self.input.open(ctx);
+        self.elapsed += started.elapsed();
+        result
+    }
+
+    fn next(&mut self, ctx: &mut ExecContext) -> Result<Option<Row>, String> {
+        let started = Instant::now();
+        let row = self.input.next(ctx);
+        self.elapsed += started.elapsed();
+        if let Ok(Some(_)) = row {
+            self.rows += 1;
+        }
+        row
+    }
+
+    fn close(&mut self) {
+        self.input.close();
+    }
+
+    fn rescan(&mut self, ctx: &mut ExecContext, key: &SqlValue) -> Result<(), String> {
+        let started = Instant::now();
+        let result = self.input.rescan(ctx, key);
+        self.elapsed += started.elapsed();
+        result
+    }
+
+    fn describe(&self) -> (&'static str, String) {
+        self.input.describe()
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        self.input.children()
+    }
+
+    fn estimated_rows(&self) -> Option<f64> {
+        self.estimate
+    }
+
+    fn actual(&self) -> Option<(usize, Duration)> {
+        Some((self.rows, self.elapsed))
+    }
+}
+
+/// Walk the table B-Tree leaves for keys in `start..=end`
+pub struct Scan {
+    table: String,
+    start: u32,
+    end: u32,
+    /// Walk from `end` back to `start` instead
+    descending: bool,
+    cursor: Option<Cursor>,
+}
+
+impl Scan {
+    pub fn new(table: &str, start: u32, end: u32) -> Self {
+        Scan {
+            table: table.to_string(),
+            start,
+            end,
+            descending: false,
+            cursor: None,
+        }
+    }
+
+    /// Every row of the table
+    pub fn full(table: &str) -> Self {
+        Scan::new(table, 0, u32::MAX)
+    }
+
+    /// The rows with keys in `start..=end`, from the last key back
+    pub fn backwards(table: &str, start: u32, end: u32) -> Self {
+        Scan {
+            descending: true,
+            ..Scan::new(table, start, end)
+        }
+    }
+}
+
+impl Operator for Scan {
+    fn open(&mut self, ctx: &mut ExecContext) -> Result<(), String> {
+        let table = ctx.table(&self.table)?;
+        let tree = &mut table.tree;
+        self.cursor = (self.start <= self.end).then(|| {
+            if !self.descending {
+                return Cursor::seek(tree, &self.start);
+            }
+            // The last key at or before the end
+            let mut cursor = Cursor::seek(tree, &self.end);
+            if cursor.end_of_table {
+                cursor = Cursor::end(tree);
+            } else if cursor.key(tree) > self.end {
+                cursor.prev(tree);
+            }
+            cursor
+        });
+        Ok(())
+    }
+
+    fn next(&mut self, ctx: &mut ExecContext) -> Result<Option<Row>, String> {
+        let table = ctx.table(&self.table)?;
+        let cursor = match self.cursor.as_mut() {
+            Some(cursor) if !cursor.end_of_table => cursor,
+            _ => return Ok(None),
+        };
+
+        let id = cursor.key(&mut table.tree);
+        if id > self.end || id < self.start {
+            self.cursor = None;
+            return Ok(None);
+        }
+        let row_data = cursor.value(&mut table.tree);
+        if self.descending {
+            cursor.prev(&mut table.tree);
+        } else {
+            cursor.next(&mut table.tree);
+        }
+        Ok(Some(table.row_values(id, &row_data)))
+    }
+
+    fn close(&mut self) {
+        self.cursor = None;
+    }
+
+    fn describe(&self) -> (&'static str, String) {
+        let mut notes = Vec::new();
+        match (self.start, self.end) {
+            (0, u32::MAX) => {}
+            (start, u32::MAX) => notes.push(format!("primary key >= {}", start)),
+            (start, end) => notes.push(format!("primary key {}..={}", start, end)),
+        }
+        if self.descending {
+            notes.push("in descending order".to_string());
+        }
+        let detail = match notes.is_empty() {
+            true => self.table.clone(),
+            false => format!("{} ({})", self.table, notes.join(", ")),
+        };
+        ("Scan", detail)
+    }
+}
+
+/// Read rows computed before the query started, such as those of a common
+/// table expression
+pub struct MaterializedScan {
+    name: String,
+    rows: Rc<Vec<Row>>,
+    position: usize,
+}
+
+impl MaterializedScan {
+    pub fn new(name: &str, rows: Rc<Vec<Row>>) -> Self {
+        MaterializedScan {
+            name: name.to_string(),
+            rows,
+            position: 0,
+        }
+    }
+}
+
+impl Operator for MaterializedScan {
+    fn open(&mut self, _ctx: &mut ExecContext) -> Result<(), String> {
+        self.position = 0;
+        Ok(())
+    }
+
+    fn next(&mut self, _ctx: &mut ExecContext) -> Result<Option<Row>, String> {
+        let row = self.rows.get(self.position).cloned();
+        self.position += 1;
+        Ok(row)
+    }
+
+    fn close(&mut self) {}
+
+    fn describe(&self) -> (&'static str, String) {
+        ("MaterializedScan", self.name.clone())
+    }
+}
+
+/// Which rows an IndexScan asks for
+pub enum Lookup {
+    /// Rows whose primary key equals one of the values
+    Keys(Vec<SqlValue>),
+    /// Rows whose value in `index` equals one of the values
+    Values {
+        index: String,
+        values: Vec<SqlValue>,
+    },
+    /// Rows whose value in `index` lies within the bounds
+    Range {
+        index: String,
+        lower: Option<Bound>,
+        upper: Option<Bound>,
+    },
+}
+
+/// Fetch rows by id through the table's primary key or through a
+/// secondary index, in primary-key order unless asked for index order
+pub struct IndexScan {
+    table: String,
+    lookup: Lookup,
+    /// Some(descending) to return rows in index order
+    order: Option<bool>,
+    ids: VecDeque<u32>,
+    /// Set once a join has rescanned this scan with its own keys
+    probed: bool,
+}
+
+impl IndexScan {
+    pub fn new(table: &str, lookup: Lookup) -> Self {
+        IndexScan {
+            table: table.to_string(),
+            lookup,
+            order: None,
+            ids: VecDeque::new(),
+            probed: false,
+        }
+    }
+
+    /// Walk `index` within the bounds, returning rows in the index's order
+    /// (or its reverse) so that a sort on the indexed column isn't needed
+    pub fn ordered(
+        table: &str,
+        index: String,
+        lower: Option<Bound>,
+        upper: Option<Bound>,
+        descending: bool,
+    ) -> Self {
+        IndexScan {
+            order: Some(descending),
+            ..IndexScan::new(
+                table,
+                Lookup::Range {
+                    index,
+                    lower,
+                    upper,
+                },
+            )
+        }
+    }
+}
+
+impl Operator for IndexScan {
+    fn open(&mut self, ctx: &mut ExecContext) -> Result<(), String> {
+        let table = ctx.table(&self.table)?;
+        let mut ids: Vec<u32> = match &self.lookup {
+            Lookup::Keys(values) => values.iter().filter_map(SqlValue::to_key).collect(),
+            Lookup::Values { index, values } => {
+                let index = table_index(table, index)?;
+                values.iter().flat_map(|value| index.find(value)).collect()
+            }
+            Lookup::Range {
+                index,
+                lower,
+                upper,
+            } => {
+                fn bound(bound: &Option<Bound>) -> ValueBound<'_> {
+                    bound.as_ref().map(|(value, inclusive)| (value, *inclusive))
+                }
+                let index = table_index(table, index)?;
+                let entries = index.range(bound(lower), bound(upper));
+                match self.order {
+                    Some(descending) => {
+                        let column = index.column_name.clone();
+                        in_index_order(table, &column, entries, descending)
+                    }
+                    None => entries.into_iter().map(|key| key.row_id).collect(),
+                }
+            }
+        };
+
+        if self.order.is_none() {
+            ids.sort_unstable();
+            ids.dedup();
+        }
+        self.ids = ids.into();
+        Ok(())
+    }
+
+    fn next(&mut self, ctx: &mut ExecContext) -> Result<Option<Row>, String> {
+        let table = ctx.table(&self.table)?;
+        while let Some(id) = self.ids.pop_front() {
+            if let Some(row_data) = table.get(id) {
+                return Ok(Some(table.row_values(id, &row_data)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn close(&mut self) {
+        self.ids.clear();
+    }
+
+    fn rescan(&mut self, ctx: &mut ExecContext, key: &SqlValue) -> Result<(), String> {
+        let values = vec![key.clone()];
+        self.lookup = match &self.lookup {
+            Lookup::Keys(_) => Lookup::Keys(values),
+            Lookup::Values { index, .. } | Lookup::Range { index, .. } => Lookup::Values {
+                index: index.clone(),
+                values,
+            },
+        };
+        self.probed = true;
+        self.open(ctx)
+    }
+
+    fn describe(&self) -> (&'static str, String) {
+        let via = match &self.lookup {
+            Lookup::Keys(_) => "primary key".to_string(),
+            Lookup::Values { index, .. } | Lookup::Range { index, .. } => {
+                format!("index {}", index)
+            }
+        };
+        let keys = match &self.lookup {
+            _ if self.probed => "= join key".to_string(),
+            Lookup::Keys(values) | Lookup::Values { values, .. } if values.is_empty() => {
+                "= join key".to_string()
+            }
+            Lookup::Keys(values) | Lookup::Values { values, .. } => {
+                let values: Vec<String> = values.iter().map(literal).collect();
+                format!("= {}", values.join(", "))
+            }
+            Lookup::Range { lower, upper, .. } => {
+                let mut bounds = Vec::new();
+                if let Some((value, inclusive)) = lower {
+                    let op = if *inclusive { ">=" } else { ">" };
+                    bounds.push(format!("{} {}", op, literal(value)));
+                }
+                if let Some((value, inclusive)) = upper {
+                    let op = if *inclusive { "<=" } else { "<" };
+                    bounds.push(format!("{} {}", op, literal(value)));
+                }
+                bounds.join(" and ")
+            }
+        };
+        let keys = match self.order {
+            Some(false) if keys.is_empty() => "in order".to_string(),
+            Some(true) if keys.is_empty() => "in descending order".to_string(),
+            Some(false) => format!("{}, in order", keys),
+            Some(true) => format!("{}, in descending order", keys),
+            None => keys,
+        };
+        (
+            "IndexScan",
+            format!("{} using {} ({})", self.table, via, keys),
+        )
+    }
+}
+
+/// The secondary index of `table` called `name`
+fn table_index<'a>(table: &'a mut Table, name: &str) -> Result<&'a mut Index, String> {
+    table
+        .indexes
+        .get_mut(name)
+        .ok_or_else(|| format!("Index '{}' not found", name))
+}
+
+/// The row ids of index entries, in the order ORDER BY on the indexed
+/// column would sort their rows. Values cut short to fit the index key tie
+/// with each other, so those are put in order by the values in the rows;
+/// descending, equal values keep the row order a stable sort leaves them in.
+fn in_index_order(
+    table: &mut Table,
+    column: &str,
+    mut entries: Vec<IndexKey>,
+    descending: bool,
+) -> Vec<u32> {
+    for run in entries.chunk_by_mut(|a, b| a.truncated() && a.value == b.value) {
+        if run.len() > 1 {
+            for key in run.iter_mut() {
+                if let Some(row_data) = table.get(key.row_id) {
+                    key.value = table
+                        .index_key(key.row_id, &row_data, column)
+                        .unwrap_or(SqlValue::Null);
+                }
+            }
+            run.sort_by(|a, b| a.value.sort_order(&b.value));
+        }
+    }
+    if descending {
+        entries.reverse();
+        for run in entries.chunk_by_mut(|a, b| a.value.sort_order(&b.value).is_eq()) {
+            run.reverse();
+        }
+    }
+    entries.into_iter().map(|key| key.row_id).collect()
+}
+
+/// A value as it would be written in SQL
+fn literal(value: &SqlValue) -> String {
+    match value {
+        SqlValue::Text(s) => format!("'{}'", s),
+        other => other.to_string(),
+    }
+}
+
+/// Comma-separated expressions
+fn expr_list(exprs: &[Expr]) -> String {
+    exprs
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Pass on the rows for which a condition is true
+pub struct Filter {
+    input: Box<dyn Operator>,
+    predicate: Expr,
+    scope: Scope,
+}
+
+impl Filter {
+    pub fn new(input: Box<dyn Operator>, predicate: Expr, scope: Scope) -> Self {
+        Filter {
+            input,
+            predicate,
+            scope,
+        }
+    }
+}
+
+impl Operator for Filter {
+    fn open(&mut self, ctx: &mut ExecContext) -> Result<(), String> {
+        self.input.open(ctx)
+    }
+
+    fn next(&mut self, ctx: &mut ExecContext) -> Result<Option<Row>, String> {
+        while let Some(row) = self.input.next(ctx)? {
+            if evaluate(&self.predicate, &row, &self.scope, ctx)?.is_true() {
+                return Ok(Some(row));
+            }
+        }
+        Ok(None)
+    }
+
+    fn close(&mut self) {
+        self.input.close();
+    }
+
+    fn rescan(&mut self, ctx: &mut ExecContext, key: &SqlValue) -> Result<(), String> {
+        self.input.rescan(ctx, key)
+    }
+
+    fn describe(&self) -> (&'static str, String) {
+        ("Filter", self.predicate.to_string())
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+}
+
+/// Compute the select list for each row
+pub struct Project {
+    input: Box<dyn Operator>,
+    exprs: Vec<Expr>,
+    scope: Scope,
+}
+
+impl Project {
+    pub fn new(input: Box<dyn Operator>, exprs: Vec<Expr>, scope: Scope) -> Self {
+        Project {
+            input,
+            exprs,
+            scope,
+        }
+    }
+}
+
+impl Operator for Project {
+    fn open(&mut self, ctx: &mut ExecContext) -> Result<(), String> {
+        self.input.open(ctx)
+    }
+
+    fn next(&mut self, ctx: &mut ExecContext) -> Result<Option<Row>, String> {
+        match self.input.next(ctx)? {
+            Some(row) => self
+                .exprs
+                .iter()
+                .map(|expr| evaluate(expr, &row, &self.scope, ctx))
+                .collect::<Result<Row, String>>()
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn close(&mut self) {
+        self.input.close();
+    }
+
+    fn describe(&self) -> (&'static str, String) {
+        ("Project", expr_list(&self.exprs))
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+}
+
+/// Order rows by a list of `(expression, descending)` keys. NULLs sort
+/// first, as the smallest values.
+pub struct Sort {
+    input: Box<dyn Operator>,
+    keys: Vec<(Expr, bool)>,
+    scope: Scope,
+    rows: VecDeque<Row>,
+}
+
+impl Sort {
+    pub fn new(input: Box<dyn Operator>, keys: Vec<(Expr, bool)>, scope: Scope) -> Self {
+        Sort {
+            input,
+            keys,
+            scope,
+            rows: VecDeque::new(),
+        }
+    }
+}
+
+impl Operator for Sort {
+    fn open(&mut self, ctx: &mut ExecContext) -> Result<(), String> {
+        // Sorting needs every row, so the whole input is read here
+        self.input.open(ctx)?;
+        let mut keyed = Vec::new();
+        while let Some(row) = self.input.next(ctx)? {
+            let key = self
+                .keys
+                .iter()
+                .map(|(expr, _)| evaluate(expr, &row, &self.scope, ctx))
+                .collect::<Result<Vec<_>, String>>()?;
+            keyed.push((key, row));
+        }
+        self.input.close();
+
+        let descending: Vec<bool> = self.keys.iter().map(|(_, d)| *d).collect();
+        keyed.sort_by(|(a, _), (b, _)| compare_keys(a, b, &descending));
+        self.rows = keyed.into_iter().map(|(_, row)| row).collect();
+        Ok(())
+    }
+
+    fn next(&mut self, _ctx: &mut ExecContext) -> Result<Option<Row>, String> {
+        Ok(self.rows.pop_front())
+    }
+
+    fn close(&mut self) {
+        self.rows.clear();
+    }
+
+    fn describe(&self) -> (&'static str, String) {
+        let keys: Vec<String> = self
+            .keys
+            .iter()
+            .map(|(expr, descending)| {
+                format!("{} {}", expr, if *descending { "DESC" } else { "ASC" })
+            })
+            .collect();
+        ("Sort", keys.join(", "))
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+}
+
+/// Compare two sort keys term by term, reversing the descending terms
+fn compare_keys(a: &[SqlValue], b: &[SqlValue], descending: &[bool]) -> Ordering {
+    for ((a, b), descending) in a.iter().zip(b).zip(descending) {
+        let ord = a.sort_order(b);
+        if ord != Ordering::Equal {
+            return if *descending { ord.reverse() } else { ord };
+        }
+    }
+    Ordering::Equal
+}
+
+/// Skip `offset` rows, then pass on at most `limit` rows. The input is not
+/// pulled any further once the limit is reached.
+pub struct Limit {
+    input: Box<dyn Operator>,
+    limit: Option<usize>,
+    offset: usize,
+    produced: usize,
+}
+
+impl Limit {
+    pub fn new(input: Box<dyn Operator>, limit: Option<usize>, offset: usize) -> Self {
+        Limit {
+            input,
+            limit,
+            offset,
+            produced: 0,
+        }
+    }
+}
+
+impl Operator for Limit {
+    fn open(&mut self, ctx: &mut ExecContext) -> Result<(), String> {
+        self.produced = 0;
+        self.input.open(ctx)?;
+        for _ in 0..self.offset {
+            if self.input.next(ctx)?.is_none() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn next(&mut self, ctx: &mut ExecContext) -> Result<Option<Row>, String> {
+        if self.limit.is_some_and(|limit| self.produced >= limit) {
+            return Ok(None);
+        }
+        let row = self.input.next(ctx)?;
+        if row.is_some() {
+            self.produced += 1;
+        }
+        Ok(row)
+    }
+
+    fn close(&mut self) {
+        self.input.close();
+    }
+
+    fn describe(&self) -> (&'static str, String) {
+        let mut detail = match self.limit {
+            Some(limit) => limit.to_string(),
+            None => "all".to_string(),
+        };
+        if self.offset > 0 {
+            detail.push_str(&format!(" offset {}", self.offset));
+        }
+        ("Limit", detail)
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+}
+
+/// Group rows by the `group_by` expressions and compute aggregates per
+/// group. Each output row is the first input row of its group followed by
+/// one value per aggregate; without GROUP BY all rows form one group, which
+/// exists even when the input is empty.
+pub struct Aggregate {
+    input: Box<dyn Operator>,
+    group_by: Vec<Expr>,
+    aggregates: Vec<Expr>,
+    scope: Scope,
+    output: VecDeque<Row>,
+}
+
+impl Aggregate {
+    pub fn new(
+        input: Box<dyn Operator>,
+        group_by: Vec<Expr>,
+        aggregates: Vec<Expr>,
+        scope: Scope,
+    ) -> Self {
+        Aggregate {
+            input,
+            group_by,
+            aggregates,
+            scope,
+            output: VecDeque::new(),
+        }
+    }
+
+    fn accumulators(&self, functions: &FunctionRegistry) -> Result<Vec<GroupState>, String> {
+        self.aggregates
+            .iter()
+            .map(|expr| match expr {
+                Expr::Aggregate { func, .. } => Ok(GroupState::Builtin(Accumulator::new(*func))),
+                Expr::Function { name, args } => match functions.aggregate(name, args.len()) {
+                    Some(function) => {
+                        let state = function.init();
+                        Ok(GroupState::User(function, state))
+                    }
+                    None => Err(format!("Unknown aggregate function {}", expr)),
+                },
+                _ => unreachable!("only aggregates are accumulated"),
+            })
+            .collect()
+    }
+}
+
+/// Running state of one aggregate call over one group: a built-in
+/// aggregate, or one registered with the executor
+enum GroupState {
+    Builtin(Accumulator),
+    User(Rc<AggregateFunction>, Box<dyn Any>),
+}
+
+impl GroupState {
+    /// Add a row to the group, computing the aggregate's arguments from it
+    fn step(
+        &mut self,
+        expr: &Expr,
+        row: &[SqlValue],
+        scope: &Scope,
+        ctx: &mut ExecContext,
+    ) -> Result<(), String> {
+        match (self, expr) {
+            (GroupState::Builtin(acc), Expr::Aggregate { arg: Some(arg), .. }) => {
+                acc.step(evaluate(arg, row, scope, ctx)?);
+            }
+            // COUNT(*) counts every row
+            (GroupState::Builtin(acc), _) => acc.step(SqlValue::Integer(1)),
+            (GroupState::User(function, state), Expr::Function { args, .. }) => {
+                let values = args
+                    .iter()
+                    .map(|arg| evaluate(arg, row, scope, ctx))
+                    .collect::<Result<Vec<_>, _>>()?;
+                function.step(state.as_mut(), &values)?;
+            }
+            (GroupState::User(..), _) => unreachable!("user aggregates are function calls"),
+        }
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<SqlValue, String> {
+        match self {
+            GroupState::Builtin(acc) => Ok(acc.finish()),
+            GroupState::User(function, state) => function.finalize(state.as_ref()),
+        }
+    }
+}
+
+impl Operator for Aggregate {
+    fn open(&mut self, ctx: &mut ExecContext) -> Result<(), String> {
+        self.input.open(ctx)?;
+
+        // Groups in order of first appearance; NULL keys group together
+        let functions = ctx.functions;
+        let mut positions: HashMap<Vec<Option<String>>, usize> = HashMap::new();
+        let mut groups: Vec<(Row, Vec<GroupState>)> = Vec::new();
+
+        while let Some(row) = self.input.next(ctx)? {
+            let mut key = Vec::with_capacity(self.group_by.len());
+            for expr in &self.group_by {
+                key.push(evaluate(expr, &row, &self.scope, ctx)?.hash_key());
+            }
+
+            let group = match positions.get(&key) {
+                Some(&group) => group,
+                None => {
+                    positions.insert(key, groups.len());
+                    groups.push((row.clone(), self.accumulators(functions)?));
+                    groups.len() - 1
+                }
+            };
+
+            for (expr, state) in self.aggregates.iter().zip(&mut groups[group].1) {
+                state.step(expr, &row, &self.scope, ctx)?;
+            }
+        }
+        self.input.close();
+
+        if groups.is_empty() && self.group_by.is_empty() {
+            groups.push((
+                vec![SqlValue::Null; self.scope.width()],
+                self.accumulators(functions)?,
+            ));
+        }
+
+        self.output.clear();
+        for (mut row, states) in groups {
+            for state in &states {
+                row.push(state.finish()?);
+            }
+            self.output.push_back(row);
+        }
+        Ok(())
+    }
+
+    fn next(&mut self, _ctx: &mut ExecContext) -> Result<Option<Row>, String> {
+        Ok(self.output.pop_front())
+    }
+
+    fn close(&mut self) {
+        self.output.clear();
+    }
+
+    fn describe(&self) -> (&'static str, String) {
+        let mut detail = expr_list(&self.aggregates);
+        if !self.group_by.is_empty() {
+            detail = format!("{} group by {}", detail, expr_list(&self.group_by));
+        }
+        ("Aggregate", detail.trim_start().to_string())
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+}
+
+/// Compute window functions. The whole input is read first; for each
+/// window function the rows are split by its PARTITION BY and ordered by
+/// its ORDER BY, and every row is given its value. Rows come out in input
+/// order, each followed by one value per window function.
+pub struct Window {
+    input: Box<dyn Operator>,
+    windows: Vec<Expr>,
+    scope: Scope,
+    output: VecDeque<Row>,
+}
+
+impl Window {
+    pub fn new(input: Box<dyn Operator>, windows: Vec<Expr>, scope: Scope) -> Self {
+        Window {
+            input,
+            windows,
+            scope,
+            output: VecDeque::new(),
+        }
+    }
+}
+
+impl Operator for Window {
+    fn open(&mut self, ctx: &mut ExecContext) -> Result<(), String> {
+        self.input.open(ctx)?;
+        let mut rows = Vec::new();
+        while let Some(row) = self.input.next(ctx)? {
+            rows.push(row);
+        }
+        self.input.close();
+
+        let mut values: Vec<Row> = vec![Vec::with_capacity(self.windows.len()); rows.len()];
+        for window in &self.windows {
+            let (func, args, over) = match window {
+                Expr::Window { func, args, over } => (*func, args, over),
+                _ => unreachable!("only window functions are computed"),
+            };
+            for partition in partitions(over, &rows, &self.scope, ctx)? {
+                let results = window_values(func, args, over, &partition, &rows, &self.scope, ctx)?;
+                for ((i, _), value) in partition.iter().zip(results) {
+                    values[*i].push(value);
+                }
+            }
+        }
+
+        self.output = rows
+            .into_iter()
+            .zip(values)
+            .map(|(mut row, values)| {
+                row.extend(values);
+                row
+            })
+            .collect();
+        Ok(())
+    }
+
+    fn next(&mut self, _ctx: &mut ExecContext) -> Result<Option<Row>, String> {
+        Ok(self.output.pop_front())
+    }
+
+    fn close(&mut self) {
+        self.output.clear();
+    }
+
+    fn describe(&self) -> (&'static str, String) {
+        ("Window", expr_list(&self.windows))
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+}
+
+/// A partition as the positions of its rows in window order, each with the
+/// row's ORDER BY values
+type Partition = Vec<(usize, Vec<SqlValue>)>;
+
+/// Split rows into the partitions of a window, in order of first
+/// appearance. Rows the window orders the same keep their input order.
+fn partitions(
+    over: &WindowSpec,
+    rows: &[Row],
+    scope: &Scope,
+    ctx: &mut ExecContext,
+) -> Result<Vec<Partition>, String> {
+    // NULL partition keys group together, as in GROUP BY
+    let mut positions: HashMap<Vec<Option<String>>, usize> = HashMap::new();
+    let mut partitions: Vec<Partition> = Vec::new();
+    for (i, row) in rows.iter().enumerate() {
+        let mut key = Vec::with_capacity(over.partition_by.len());
+        for expr in &over.partition_by {
+            key.push(evaluate(expr, row, scope, ctx)?.hash_key());
+        }
+        let order = over
+            .order_by
+            .iter()
+            .map(|item| evaluate(&item.expr, row, scope, ctx))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let partition = *positions.entry(key).or_insert_with(|| {
+            partitions.push(Vec::new());
+            partitions.len() - 1
+        });
+        partitions[partition].push((i, order));
+    }
+
+    let descending: Vec<bool> = over.order_by.iter().map(|item| item.descending).collect();
+    for partition in &mut partitions {
+        partition.sort_by(|(_, a), (_, b)| compare_keys(a, b, &descending));
+    }
+    Ok(partitions)
+}
+
+/// The value of a window function for each row of one ordered partition
+fn window_values(
+    func: WindowFunc,
+    args: &[Expr],
+    over: &WindowSpec,
+    partition: &Partition,
+    rows: &[Row],
+    scope: &Scope,
+    ctx: &mut ExecContext,
+) -> Result<Vec<SqlValue>, String> {
+    let n = partition.len();
+    let row = |j: usize| &rows[partition[j].0];
+
+    // Rows with equal ORDER BY values are peers: they share a rank, and a
+    // frameless aggregate sees all of them. Each row's peer group runs
+    // from first_peer[j] to last_peer[j].
+    let peers = |a: usize, b: usize| {
+        let (a, b) = (&partition[a].1, &partition[b].1);
+        a.iter().zip(b).all(|(a, b)| a.sort_order(b).is_eq())
+    };
+    let mut first_peer: Vec<usize> = (0..n).collect();
+    for j in 1..n {
+        if peers(j - 1, j) {
+            first_peer[j] = first_peer[j - 1];
+        }
+    }
+    let mut last_peer: Vec<usize> = (0..n).collect();
+    for j in (1..n).rev() {
+        if peers(j - 1, j) {
+            last_peer[j - 1] = last_peer[j];
+        }
+    }
+
+    let mut out = Vec::with_capacity(n);
+    match func {
+        WindowFunc::RowNumber => out.extend((1..=n).map(|j| SqlValue::Integer(j as i64))),
+        WindowFunc::Rank => out.extend(first_peer.iter().map(|&p| SqlValue::Integer(p as i64 + 1))),
+        WindowFunc::DenseRank => {
+            let mut rank = 0;
+            for (j, &first) in first_peer.iter().enumerate() {
+                if first == j {
+                    rank += 1;
+                }
+                out.push(SqlValue::Integer(rank));
+            }
+        }
+        WindowFunc::Lag | WindowFunc::Lead => {
+            for j in 0..n {
+                let offset = match args.get(1) {
+                    Some(expr) => match evaluate(expr, row(j), scope, ctx)?.as_integer() {
+                        Some(offset) if offset >= 0 => offset as usize,
+                        _ => {
+                            return Err(format!(
+                                "{}() offset must be a non-negative integer",
+                                func.name()
+                            ))
+                        }
+                    },
+                    None => 1,
+                };
+                let target = match func {
+                    WindowFunc::Lag => j.checked_sub(offset),
+                    _ => j.checked_add(offset).filter(|&t| t < n),
+                };
+                out.push(match (target, args.get(2)) {
+                    (Some(t), _) => evaluate(&args[0], row(t), scope, ctx)?,
+                    (None, Some(default)) => evaluate(default, row(j), scope, ctx)?,
+                    (None, None) => SqlValue::Null,
+                });
+            }
+        }
+        WindowFunc::Aggregate(func) => {
+            let mut inputs = Vec::with_capacity(n);
+            for j in 0..n {
+                inputs.push(match args.first() {
+                    Some(arg) => evaluate(arg, row(j), scope, ctx)?,
+                    None => SqlValue::Integer(1), // COUNT(*) counts every row
+                });
+            }
+            // Each row's frame, as the range of partition rows it covers
+            let frame = |j: usize| match &over.frame {
+                None if over.order_by.is_empty() => (0, n),
+                None => (0, last_peer[j] + 1),
+                Some(frame) => (
+                    frame_edge(frame.start, j, n, false),
+                    frame_edge(frame.end, j, n, true),
+                ),
+            };
+
+            // A frame that starts at the partition's first row only grows,
+            // so one running accumulator serves every row
+            let running = over
+                .frame
+                .as_ref()
+                .is_none_or(|frame| frame.start == FrameBound::UnboundedPreceding);
+            let mut acc = Accumulator::new(func);
+            let mut stepped = 0;
+            for j in 0..n {
+                let (start, end) = frame(j);
+                if running {
+                    for input in &inputs[stepped..end.max(stepped)] {
+                        acc.step(input.clone());
+                    }
+                    stepped = stepped.max(end);
+                    out.push(acc.finish());
+                } else {
+                    let mut acc = Accumulator::new(func);
+                    for input in &inputs[start.min(end)..end] {
+                        acc.step(input.clone());
+                    }
+                    out.push(acc.finish());
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Where a frame bound falls for the row at `j` of a partition of `n`
+/// rows: the first row of the frame for a start, one past its last row for
+/// an end
+fn frame_edge(bound: FrameBound, j: usize, n: usize, end: bool) -> usize {
+    let j = j + end as usize;
+    let edge = match bound {
+        FrameBound::UnboundedPreceding => 0,
+        FrameBound::Preceding(k) => j.saturating_sub(k),
+        FrameBound::CurrentRow => j,
+        FrameBound::Following(k) => j.saturating_add(k),
+        FrameBound::UnboundedFollowing => n,
+    };
+    edge.min(n)
+}
+
+/// Running state of one aggregate over one group. NULL inputs are ignored.
+struct Accumulator {
+    func: AggregateFunc,
+    count: i64,
+    int_sum: Option<i64>,
+    real_sum: f64,
+    best: SqlValue,
+}
+
+impl Accumulator {
+    fn new(func: AggregateFunc) -> Self {
+        Accumulator {
+            func,
+            count: 0,
+            int_sum: Some(0),
+            real_sum: 0.0,
+            best: SqlValue::Null,
+        }
+    }
+
+    fn step(&mut self, value: SqlValue) {
+        if value == SqlValue::Null {
+            return;
+        }
+        self.count += 1;
+
+        match self.func {
+            AggregateFunc::Count => {}
+            AggregateFunc::Sum | AggregateFunc::Avg => {
+                // Stay exact while every input is an integer; text that
+                // isn't a number adds nothing
+                match value.as_integer() {
+                    Some(n) => self.int_sum = self.int_sum.and_then(|sum| sum.checked_add(n)),
+                    None => self.int_sum = None,
+                }
+                self.real_sum += value.as_real().unwrap_or(0.0);
+            }
+            AggregateFunc::Min | AggregateFunc::Max => {
+                let replace = match self.best.compare(&value) {
+                    None => true,
+                    Some(ord) if self.func == AggregateFunc::Min => ord.is_gt(),
+                    Some(ord) => ord.is_lt(),
+                };
+                if replace {
+                    self.best = value;
+                }
+            }
+        }
+    }
+
+    fn finish(&self) -> SqlValue {
+        match self.func {
+            AggregateFunc::Count => SqlValue::Integer(self.count),
+            _ if self.count == 0 => SqlValue::Null,
+            AggregateFunc::Sum => match self.int_sum {
+                Some(sum) => SqlValue::Integer(sum),
+                None => SqlValue::Real(self.real_sum),
+            },
+            AggregateFunc::Avg => SqlValue::Real(self.real_sum / self.count as f64),
+            AggregateFunc::Min | AggregateFunc::Max => self.best.clone(),
+        }
+    }
+}
+
+/// The part of a join both join operators share: the join type, the ON
+/// condition and the scope it is evaluated in, with `left_width` columns
+/// from the left input followed by `right_width` from the right
+pub struct JoinSpec {
+    pub join_type: JoinType,
+    pub on: Option<Expr>,
+    pub scope: Scope,
+    pub left_width: usize,
+    pub right_width: usize,
+}
+
+impl JoinSpec {
+    /// The join type and condition, for EXPLAIN
+    fn describe(&self) -> String {
+        let join_type = format!("{:?}", self.join_type).to_uppercase();
+        match &self.on {
+            Some(on) => format!("{} on {}", join_type, on),
+            None => join_type,
+        }
+    }
+}
+
+/// Join bookkeeping: buffered right rows, which of them found a partner,
+/// and joined rows waiting to be returned
+struct JoinState {
+    spec: JoinSpec,
+    right_rows: Vec<Row>,
+    right_matched: Vec<bool>,
+    pending: VecDeque<Row>,
+    left_done: bool,
+}
+
+impl JoinState {
+    fn new(spec: JoinSpec) -> Self {
+        JoinState {
+            spec,
+            right_rows: Vec::new(),
+            right_matched: Vec::new(),
+            pending: VecDeque::new(),
+            left_done: false,
+        }
+    }
+
+    fn reset(&mut self, right_rows: Vec<Row>) {
+        self.right_matched = vec![false; right_rows.len()];
+        self.right_rows = right_rows;
+        self.pending.clear();
+        self.left_done = false;
+    }
+
+    fn matches(&self, row: &[SqlValue], ctx: &mut ExecContext) -> Result<bool, String> {
+        match self.spec.on {
+            Some(ref on) => Ok(evaluate(on, row, &self.spec.scope, ctx)?.is_true()),
+            None => Ok(true),
+        }
+    }
+
+    /// Join a left row with the buffered right rows at `candidates`
+    fn join_buffered(
+        &mut self,
+        left_row: &Row,
+        candidates: impl IntoIterator<Item = usize>,
+        ctx: &mut ExecContext,
+    ) -> Result<(), String> {
+        let mut left_matched = false;
+        for i in candidates {
+            let row: Row = left_row
+                .iter()
+                .chain(&self.right_rows[i])
+                .cloned()
+                .collect();
+            if self.matches(&row, ctx)? {
+                left_matched = true;
+                self.right_matched[i] = true;
+                self.pending.push_back(row);
+            }
+        }
+        self.pad_left(left_row, left_matched);
+        Ok(())
+    }
+
+    /// Join a left row with right rows fetched just for it
+    fn join_fetched(
+        &mut self,
+        left_row: &Row,
+        right_rows: Vec<Row>,
+        ctx: &mut ExecContext,
+    ) -> Result<(), String> {
+        let mut left_matched = false;
+        for right_row in right_rows {
+            let row: Row = left_row.iter().cloned().chain(right_row).collect();
+            if self.matches(&row, ctx)? {
+                left_matched = true;
+                self.pending.push_back(row);
+            }
+        }
+        self.pad_left(left_row, left_matched);
+        Ok(())
+    }
+
+    /// Unmatched left rows are padded with NULLs for LEFT/FULL
+    fn pad_left(&mut self, left_row: &Row, matched: bool) {
+        if !matched && matches!(self.spec.join_type, JoinType::Left | JoinType::Full) {
+            let mut row = left_row.clone();
+            row.resize(self.spec.left_width + self.spec.right_width, SqlValue::Null);
+            self.pending.push_back(row);
+        }
+    }
+
+    /// Once the left input is exhausted, unmatched right rows are padded
+    /// with NULLs for RIGHT/FULL
+    fn finish(&mut self) {
+        self.left_done = true;
+        if matches!(self.spec.join_type, JoinType::Right | JoinType::Full) {
+            for (right_row, matched) in self.right_rows.iter().zip(&self.right_matched) {
+                if !matched {
+                    let mut row = vec![SqlValue::Null; self.spec.left_width];
+                    row.extend(right_row.iter().cloned());
+                    self.pending.push_back(row);
+                }
+            }
+        }
+    }
+}
+
+/// Buckets the right input by its join column, then probes with each left
+/// row. `left_key` and `right_key` are column positions within each input.
+pub struct HashJoin {
+    left: Box<dyn Operator>,
+    right: Box<dyn Operator>,
+    left_key: usize,
+    right_key: usize,
+    buckets: HashMap<String, Vec<usize>>,
+    state: JoinState,
+}
+
+impl HashJoin {
+    pub fn new(
+        left: Box<dyn Operator>,
+        right: Box<dyn Operator>,
+        left_key: usize,
+        right_key: usize,
+        spec: JoinSpec,
+    ) -> Self {
+        HashJoin {
+            left,
+            right,
+            left_key,
+            right_key,
+            buckets: HashMap::new(),
+            state: JoinState::new(spec),
+        }
+    }
+}
+
+impl Operator for HashJoin {
+    fn open(&mut self, ctx: &mut ExecContext) -> Result<(), String> {
+        let right_rows = collect_rows(self.right.as_mut(), ctx)?;
+        self.buckets.clear();
+        for (i, row) in right_rows.iter().enumerate() {
+            if let Some(key) = row[self.right_key].join_key() {
+                self.buckets.entry(key).or_default().push(i);
+            }
+        }
+        self.state.reset(right_rows);
+        self.left.open(ctx)
+    }
+
+    fn next(&mut self, ctx: &mut ExecContext) -> Result<Option<Row>, String> {
+        loop {
+            if let Some(row) = self.state.pending.pop_front() {
+                return Ok(Some(row));
+            }
+            if self.state.left_done {
+                return Ok(None);
+            }
+            match self.left.next(ctx)? {
+                Some(left_row) => {
+                    let candidates = left_row[self.left_key]
+                        .join_key()
+                        .and_then(|key| self.buckets.get(&key))
+                        .map_or(&[][..], |v| v.as_slice());
+                    self.state
+                        .join_buffered(&left_row, candidates.iter().copied(), ctx)?;
+                }
+                None => self.state.finish(),
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        self.left.close();
+        self.buckets.clear();
+        self.state.reset(Vec::new());
+    }
+
+    fn describe(&self) -> (&'static str, String) {
+        ("HashJoin", self.state.spec.describe())
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.left.as_ref(), self.right.as_ref()]
+    }
+}
+
+/// Pairs each left row with the right rows. Without a probe column the
+/// right input is read once and every pair is tried; with one, the right
+/// input is rescanned for each left row with that column's value, which
+/// turns it into an index nested-loop join.
+pub struct NestedLoopJoin {
+    left: Box<dyn Operator>,
+    right: Box<dyn Operator>,
+    probe: Option<usize>,
+    state: JoinState,
+}
+
+impl NestedLoopJoin {
+    pub fn new(
+        left: Box<dyn Operator>,
+        right: Box<dyn Operator>,
+        probe: Option<usize>,
+        spec: JoinSpec,
+    ) -> Self {
+        NestedLoopJoin {
+            left,
+            right,
+            probe,
+            state: JoinState::new(spec),
+        }
+    }
+}
+
+impl Operator for NestedLoopJoin {
+    fn open(&mut self, ctx: &mut ExecContext) -> Result<(), String> {
+        let right_rows = match self.probe {
+            Some(_) => Vec::new(),
+            None => collect_rows(self.right.as_mut(), ctx)?,
+        };
+        self.state.reset(right_rows);
+        self.left.open(ctx)
+    }
+
+    fn next(&mut self, ctx: &mut ExecContext) -> Result<Option<Row>, String> {
+        loop {
+            if let Some(row) = self.state.pending.pop_front() {
+                return Ok(Some(row));
+            }
+            if self.state.left_done {
+                return Ok(None);
+            }
+            let left_row = match self.left.next(ctx)? {
+                Some(row) => row,
+                None => {
+                    self.state.finish();
+                    continue;
+                }
+            };
+
+            match self.probe {
+                Some(pos) => {
+                    self.right.rescan(ctx, &left_row[pos])?;
+                    let mut fetched = Vec::new();
+                    while let Some(row) = self.right.next(ctx)? {
+                        fetched.push(row);
+                    }
+                    self.state.join_fetched(&left_row, fetched, ctx)?;
+                }
+                None => {
+                    let all = 0..self.state.right_rows.len();
+                    self.state.join_buffered(&left_row, all, ctx)?;
+                }
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        self.left.close();
+        self.right.close();
+        self.state.reset(Vec::new());
+    }
+
+    fn describe(&self) -> (&'static str, String) {
+        let name = match self.probe {
+            Some(_) => "IndexNestedLoopJoin",
+            None => "NestedLoopJoin",
+        };
+        (name, self.state.spec.describe())
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.left.as_ref(), self.right.as_ref()]
+    }
+}
+
+/// Combine the rows of two query blocks. UNION ALL passes on the left rows
+/// and then the right ones, and UNION drops rows it has already passed on.
+/// INTERSECT and EXCEPT first hash every right row, then pass on each
+/// distinct left row that is, or is not, among them.
+pub struct SetOperation {
+    operator: SetOperator,
+    left: Box<dyn Operator>,
+    right: Box<dyn Operator>,
+    right_rows: HashSet<Vec<Option<String>>>,
+    seen: HashSet<Vec<Option<String>>>,
+    left_done: bool,
+}
+
+impl SetOperation {
+    pub fn new(operator: SetOperator, left: Box<dyn Operator>, right: Box<dyn Operator>) -> Self {
+        SetOperation {
+            operator,
+            left,
+            right,
+            right_rows: HashSet::new(),
+            seen: HashSet::new(),
+            left_done: false,
+        }
+    }
+}
+
+impl Operator for SetOperation {
+    fn open(&mut self, ctx: &mut ExecContext) -> Result<(), String> {
+        self.seen.clear();
+        self.right_rows.clear();
+        self.left_done = false;
+        self.left.open(ctx)?;
+        match self.operator {
+            SetOperator::Union | SetOperator::UnionAll => self.right.open(ctx),
+            SetOperator::Intersect | SetOperator::Except => {
+                let rows = collect_rows(self.right.as_mut(), ctx)?;
+                self.right_rows = rows.iter().map(row_key).collect();
+                Ok(())
+            }
+        }
+    }
+
+    fn next(&mut self, ctx: &mut ExecContext) -> Result<Option<Row>, String> {
+        loop {
+            let row = if !self.left_done {
+                match self.left.next(ctx)? {
+                    Some(row) => row,
+                    None => {
+                        self.left_done = true;
+                        continue;
+                    }
+                }
+            } else {
+                match self.operator {
+                    SetOperator::Union | SetOperator::UnionAll => match self.right.next(ctx)? {
+                        Some(row) => row,
+                        None => return Ok(None),
+                    },
+                    SetOperator::Intersect | SetOperator::Except => return Ok(None),
+                }
+            };
+
+            let keep = match self.operator {
+                SetOperator::UnionAll => true,
+                SetOperator::Union => self.seen.insert(row_key(&row)),
+                SetOperator::Intersect => {
+                    let key = row_key(&row);
+                    self.right_rows.contains(&key) && self.seen.insert(key)
+                }
+                SetOperator::Except => {
+                    let key = row_key(&row);
+                    !self.right_rows.contains(&key) && self.seen.insert(key)
+                }
+            };
+            if keep {
+                return Ok(Some(row));
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        self.left.close();
+        self.right.close();
+        self.seen.clear();
+        self.right_rows.clear();
+    }
+
+    fn describe(&self) -> (&'static str, String) {
+        let name = match self.operator {
+            SetOperator::Union => "Union",
+            SetOperator::UnionAll => "UnionAll",
+            SetOperator::Intersect => "Intersect",
+            SetOperator::Except => "Except",
+        };
+        (name, String::new())
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.left.as_ref(), self.right.as_ref()]
+    }
+}
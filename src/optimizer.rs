@@ -0,0 +1,797 @@
+//! Cost-based choice of access paths and join algorithms
+//!
+//! Costs are rough page/row counts. They come from the statistics ANALYZE
+//! stores in `stats.json` next to the metadata, and from fixed guesses for
+//! tables that haven't been analyzed.
+
+use crate::btree::Cursor;
+use crate::eval::Scope;
+use crate::operator::Bound;
+use crate::parser::*;
+use crate::table::{DataType, Table};
+use crate::value::SqlValue;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Rows assumed for a table that hasn't been analyzed
+const DEFAULT_ROWS: f64 = 1000.0;
+/// Rows assumed to share a value of a non-unique column
+const DEFAULT_ROWS_PER_VALUE: f64 = 10.0;
+/// Keys per B-Tree page, used to estimate tree depth
+const FANOUT: f64 = 100.0;
+/// Fraction of rows assumed to pass a range with one bound, and with two
+const ONE_BOUND_FRACTION: f64 = 0.25;
+const TWO_BOUND_FRACTION: f64 = 0.1;
+/// Fraction of rows assumed to pass a condition nothing is known about
+pub const FILTER_FRACTION: f64 = 0.25;
+/// Cost of reading one secondary index entry, relative to a table row
+const INDEX_ENTRY_COST: f64 = 0.5;
+
+/// What ANALYZE records about a table
+#[derive(Debug, Clone, Default)]
+pub struct TableStats {
+    pub rows: u64,
+    pub min_key: u32,
+    pub max_key: u32,
+    /// Distinct values per secondary index, by index name
+    pub index_distinct: HashMap<String, u64>,
+}
+
+/// Count a table's rows, its key range and the distinct values of each index
+pub fn analyze(table: &mut Table) -> TableStats {
+    let mut stats = TableStats::default();
+
+    let mut cursor = Cursor::start(&mut table.tree);
+    while !cursor.end_of_table {
+        let key = cursor.key(&mut table.tree);
+        if stats.rows == 0 {
+            stats.min_key = key;
+        }
+        stats.max_key = key;
+        stats.rows += 1;
+        cursor.next(&mut table.tree);
+    }
+
+    for (name, index) in table.indexes.iter_mut() {
+        let keys: HashSet<String> = index
+            .entries()
+            .into_iter()
+            .filter_map(|(key, _)| key.hash_key())
+            .collect();
+        stats.index_distinct.insert(name.clone(), keys.len() as u64);
+    }
+
+    stats
+}
+
+/// Read the statistics saved by ANALYZE, if any
+pub fn load_stats(db_path: &Path) -> HashMap<String, TableStats> {
+    let mut stats: HashMap<String, TableStats> = HashMap::new();
+    let content = match std::fs::read_to_string(db_path.join("stats.json")) {
+        Ok(content) => content,
+        Err(_) => return stats,
+    };
+
+    // Format: {"tables":[{"table":"t","rows":3,"min_key":1,"max_key":3}],
+    //          "indexes":[{"table":"t","index":"i","distinct":2}]}
+    for object in content.split('{').skip(1) {
+        let object = object.split('}').next().unwrap_or("");
+        let table = match json_field(object, "table") {
+            Some(table) => table.to_string(),
+            None => continue,
+        };
+        let number = |key: &str| json_field(object, key).and_then(|v| v.parse::<u64>().ok());
+        let entry = stats.entry(table).or_default();
+
+        match json_field(object, "index") {
+            Some(index) => {
+                let distinct = number("distinct").unwrap_or(0);
+                entry.index_distinct.insert(index.to_string(), distinct);
+            }
+            None => {
+                entry.rows = number("rows").unwrap_or(0);
+                entry.min_key = number("min_key").unwrap_or(0) as u32;
+                entry.max_key = number("max_key").unwrap_or(0) as u32;
+            }
+        }
+    }
+
+    stats
+}
+
+/// The value of `"key":value` or `"key":"value"` in a flat JSON object
+pub fn json_field<'a>(object: &'a str, key: &str) -> Option<&'a str> {
+    let pattern = format!("\"{}\":", key);
+    let rest = &object[object.find(&pattern)? + pattern.len()..];
+    match rest.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next(),
+        None => rest.split(',').next().map(str::trim),
+    }
+}
+
+/// The flat objects in the array `"key":[{...},...]` of a JSON object
+pub fn json_objects<'a>(object: &'a str, key: &str) -> Vec<&'a str> {
+    let pattern = format!("\"{}\":[", key);
+    let Some(start) = object.find(&pattern) else {
+        return Vec::new();
+    };
+    let array = &object[start + pattern.len()..];
+    let array = &array[..array.find(']').unwrap_or(array.len())];
+    array
+        .split('{')
+        .skip(1)
+        .map(|object| object.split('}').next().unwrap_or(""))
+        .collect()
+}
+
+/// The length of the JSON object or array `text` starts with, up to and
+/// including its closing bracket
+pub fn matching_bracket(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' | '[' => depth += 1,
+            '}' | ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+pub fn save_stats(db_path: &Path, stats: &HashMap<String, TableStats>) -> Result<(), String> {
+    let mut tables = Vec::new();
+    let mut indexes = Vec::new();
+    for (name, table) in stats {
+        tables.push(format!(
+            "{{\"table\":\"{}\",\"rows\":{},\"min_key\":{},\"max_key\":{}}}",
+            name, table.rows, table.min_key, table.max_key
+        ));
+        for (index, distinct) in &table.index_distinct {
+            indexes.push(format!(
+                "{{\"table\":\"{}\",\"index\":\"{}\",\"distinct\":{}}}",
+                name, index, distinct
+            ));
+        }
+    }
+
+    let json = format!(
+        "{{\"tables\":[{}],\"indexes\":[{}]}}",
+        tables.join(","),
+        indexes.join(",")
+    );
+    std::fs::write(db_path.join("stats.json"), json)
+        .map_err(|e| format!("Failed to save statistics: {}", e))
+}
+
+/// Estimated work to produce a result and the number of rows in it
+#[derive(Debug, Clone, Copy)]
+pub struct Estimate {
+    pub cost: f64,
+    pub rows: f64,
+}
+
+/// Estimates for one table, from its statistics when it has been analyzed.
+/// A derived table has no B-Tree, only the row count its query expects.
+pub struct CostModel<'a> {
+    table: Option<&'a Table>,
+    stats: Option<&'a TableStats>,
+    derived_rows: Option<f64>,
+}
+
+impl<'a> CostModel<'a> {
+    pub fn new(table: &'a Table, stats: Option<&'a TableStats>) -> Self {
+        CostModel {
+            table: Some(table),
+            stats,
+            derived_rows: None,
+        }
+    }
+
+    /// The model of a derived table whose query expects `rows` rows
+    pub fn derived(rows: Option<f64>) -> Self {
+        CostModel {
+            table: None,
+            stats: None,
+            derived_rows: Some(rows.unwrap_or(DEFAULT_ROWS)),
+        }
+    }
+
+    pub fn rows(&self) -> f64 {
+        match self.derived_rows {
+            Some(rows) => rows,
+            None => self.stats.map_or(DEFAULT_ROWS, |s| s.rows as f64),
+        }
+    }
+
+    /// Pages read to descend the B-Tree to one key
+    fn seek_cost(&self) -> f64 {
+        1.0 + self.rows().max(1.0).log(FANOUT)
+    }
+
+    /// Expected number of distinct values in a column
+    fn distinct(&self, pos: usize) -> f64 {
+        let rows = self.rows();
+        let table = match self.table {
+            Some(table) => table,
+            None => return (rows / DEFAULT_ROWS_PER_VALUE).max(1.0),
+        };
+        let column = &table.columns[pos].name;
+        let index = table.indexes.values().find(|i| &i.column_name == column);
+
+        let distinct = match index {
+            _ if pos == 0 => rows,
+            Some(index) => match self.stats.and_then(|s| s.index_distinct.get(&index.name)) {
+                Some(&distinct) => distinct as f64,
+                None if index.unique => rows,
+                None => rows / DEFAULT_ROWS_PER_VALUE,
+            },
+            None => rows / DEFAULT_ROWS_PER_VALUE,
+        };
+        distinct.max(1.0)
+    }
+
+    /// Expected rows sharing one value of a column
+    fn rows_per_value(&self, pos: usize) -> f64 {
+        (self.rows() / self.distinct(pos)).max(1.0)
+    }
+
+    /// Fraction of the rows whose key lies in `start..=end`
+    fn key_fraction(&self, start: u32, end: u32, bounds: (bool, bool)) -> f64 {
+        if start > end {
+            return 0.0;
+        }
+        match self.stats {
+            Some(s) if s.rows > 0 => {
+                let lo = start.max(s.min_key) as f64;
+                let hi = end.min(s.max_key) as f64;
+                let span = (s.max_key - s.min_key) as f64 + 1.0;
+                ((hi - lo + 1.0) / span).clamp(0.0, 1.0)
+            }
+            Some(_) => 0.0,
+            None => range_fraction(bounds),
+        }
+    }
+
+    /// Estimate for reading the table through `path`
+    pub fn estimate(&self, path: &AccessPath) -> Estimate {
+        let rows = self.rows();
+        let seek = self.seek_cost();
+        let position = |index: &str| {
+            self.table.map_or(0, |table| {
+                let column = &table.indexes[index].column_name;
+                table
+                    .columns
+                    .iter()
+                    .position(|c| &c.name == column)
+                    .unwrap_or(0)
+            })
+        };
+
+        match path {
+            AccessPath::FullScan => Estimate { cost: rows, rows },
+            AccessPath::PrimaryKeyLookup { keys } => Estimate {
+                cost: keys.len() as f64 * seek,
+                rows: (keys.len() as f64).min(rows),
+            },
+            AccessPath::PrimaryKeyRange { start, end, .. } => {
+                let bounds = (*start > 0, *end < u32::MAX);
+                let matched = rows * self.key_fraction(*start, *end, bounds);
+                Estimate {
+                    cost: seek + matched,
+                    rows: matched,
+                }
+            }
+            AccessPath::IndexLookup { index, values } => {
+                let matched = values.len() as f64 * self.rows_per_value(position(index));
+                Estimate {
+                    cost: values.len() as f64 * seek + matched * seek,
+                    rows: matched,
+                }
+            }
+            AccessPath::IndexRange { lower, upper, .. } => {
+                // One seek to the lower bound, then the entries up to the
+                // upper bound are walked and each match is fetched
+                let matched = rows * range_fraction((lower.is_some(), upper.is_some()));
+                Estimate {
+                    cost: seek + matched * INDEX_ENTRY_COST + matched * seek,
+                    rows: matched,
+                }
+            }
+        }
+    }
+}
+
+fn range_fraction((lower, upper): (bool, bool)) -> f64 {
+    match (lower, upper) {
+        (true, true) => TWO_BOUND_FRACTION,
+        (false, false) => 1.0,
+        _ => ONE_BOUND_FRACTION,
+    }
+}
+
+/// How the rows of a single table are read
+pub enum AccessPath {
+    /// Walk every leaf of the table B-Tree
+    FullScan,
+    /// Point lookups in the table B-Tree (`pk = v`, `pk IN (...)`)
+    PrimaryKeyLookup { keys: Vec<u32> },
+    /// Walk the table B-Tree leaves for keys in `start..=end`, from the
+    /// last key back if `descending`
+    PrimaryKeyRange {
+        start: u32,
+        end: u32,
+        descending: bool,
+    },
+    /// Look up each value in a secondary index (`col = v`, `col IN (...)`)
+    IndexLookup {
+        index: String,
+        values: Vec<SqlValue>,
+    },
+    /// Walk a secondary index from the lower bound to the upper one
+    IndexRange {
+        index: String,
+        lower: Option<Bound>,
+        upper: Option<Bound>,
+        /// Some(descending) to return rows in index order rather than by id
+        order: Option<bool>,
+    },
+}
+
+/// An ORDER BY on a single column of a table, which an index on that
+/// column may be able to answer without a sort
+pub struct ColumnOrder {
+    /// Position of the column in the table
+    pub column: usize,
+    pub descending: bool,
+    /// Whether a LIMIT follows, so only the first rows will be fetched
+    pub limited: bool,
+}
+
+/// Make `path` return the table's rows in the order `order` asks for, if the
+/// primary key or an index on the column allows it, and say whether it does. A range over
+/// that index is walked in index order instead of by row id. A full scan
+/// becomes a walk of the whole index only under a LIMIT: fetching every
+/// row through the index costs more than sorting them, but fetching the
+/// first few doesn't.
+pub fn order_access_path(model: &CostModel, path: &mut AccessPath, order: &ColumnOrder) -> bool {
+    // The table B-Tree itself is in primary key order, and can be walked
+    // from either end
+    if model.table.is_some() && order.column == 0 {
+        return match path {
+            AccessPath::FullScan => {
+                if order.descending {
+                    *path = AccessPath::PrimaryKeyRange {
+                        start: 0,
+                        end: u32::MAX,
+                        descending: true,
+                    };
+                }
+                true
+            }
+            AccessPath::PrimaryKeyRange { descending, .. } => {
+                *descending = order.descending;
+                true
+            }
+            AccessPath::PrimaryKeyLookup { .. } => !order.descending,
+            _ => false,
+        };
+    }
+    let Some(wanted) = model.table.and_then(|table| index_on(table, order.column)) else {
+        return false;
+    };
+    match path {
+        AccessPath::IndexRange {
+            index,
+            order: path_order,
+            ..
+        } if *index == wanted => {
+            *path_order = Some(order.descending);
+            true
+        }
+        AccessPath::FullScan if order.limited => {
+            *path = AccessPath::IndexRange {
+                index: wanted,
+                lower: None,
+                upper: None,
+                order: Some(order.descending),
+            };
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The name of an index on the column at `pos`, if the table has one
+fn index_on(table: &Table, pos: usize) -> Option<String> {
+    let column = &table.columns[pos].name;
+    table
+        .indexes
+        .values()
+        .find(|i| &i.column_name == column)
+        .map(|i| i.name.clone())
+}
+
+/// Pick the cheapest way to read a table given the ANDed terms of
+/// `where_clause`: a full scan, a primary-key lookup or range, or a lookup
+/// or range in one of its secondary indexes
+pub fn choose_access_path(
+    model: &CostModel,
+    scope: &Scope,
+    where_clause: Option<&Expr>,
+) -> (AccessPath, Estimate) {
+    let mut terms = Vec::new();
+    if let Some(where_clause) = where_clause {
+        conjuncts(where_clause, &mut terms);
+    }
+
+    // A derived table can only be read in full
+    let table = match model.table {
+        Some(table) => table,
+        None => {
+            let mut estimate = model.estimate(&AccessPath::FullScan);
+            estimate.rows *= FILTER_FRACTION.powi(terms.len() as i32);
+            return (AccessPath::FullScan, estimate);
+        }
+    };
+
+    // Collect the equality/IN values and range bounds each column is restricted to
+    let mut lookups: Vec<(usize, Vec<SqlValue>)> = Vec::new();
+    let mut ranges: Vec<(usize, Option<Bound>, Option<Bound>)> = Vec::new();
+    let mut add_bound = |pos: usize, lower: Option<Bound>, upper: Option<Bound>| match ranges
+        .iter_mut()
+        .find(|(p, _, _)| *p == pos)
+    {
+        Some((_, l, u)) => {
+            *l = lower.or(l.take());
+            *u = upper.or(u.take());
+        }
+        None => ranges.push((pos, lower, upper)),
+    };
+
+    for term in &terms {
+        match term {
+            Expr::Compare { left, op, right } => {
+                let (pos, op, value) = match column_compared_to_literal(left, op, right, scope) {
+                    Some(found) => found,
+                    None => continue,
+                };
+                match op {
+                    CompareOp::Equals => lookups.push((pos, vec![value])),
+                    CompareOp::GreaterThan => add_bound(pos, Some((value, false)), None),
+                    CompareOp::GreaterEquals => add_bound(pos, Some((value, true)), None),
+                    CompareOp::LessThan => add_bound(pos, None, Some((value, false))),
+                    CompareOp::LessEquals => add_bound(pos, None, Some((value, true))),
+                    CompareOp::NotEquals => {}
+                }
+            }
+            Expr::InList {
+                expr,
+                list,
+                negated: false,
+            } => {
+                let pos = match expr.as_ref() {
+                    Expr::Column(col) => scope.resolve(col).ok(),
+                    _ => None,
+                };
+                let values: Option<Vec<SqlValue>> = list.iter().map(literal_value).collect();
+                if let (Some(pos), Some(values)) = (pos, values) {
+                    lookups.push((pos, values));
+                }
+            }
+            Expr::Between {
+                expr,
+                low,
+                high,
+                negated: false,
+            } => {
+                let pos = match expr.as_ref() {
+                    Expr::Column(col) => scope.resolve(col).ok(),
+                    _ => None,
+                };
+                if let (Some(pos), Some(low), Some(high)) =
+                    (pos, literal_value(low), literal_value(high))
+                {
+                    add_bound(pos, Some((low, true)), Some((high, true)));
+                }
+            }
+            Expr::Like {
+                expr,
+                pattern,
+                negated: false,
+            } => {
+                // A TEXT column's index keeps text in byte order
+                let pos = match expr.as_ref() {
+                    Expr::Column(col) => scope.resolve(col).ok(),
+                    _ => None,
+                }
+                .filter(|&pos| matches!(table.columns[pos].data_type, DataType::Text(_)));
+                if let (Some(pos), Some(SqlValue::Text(pattern))) = (pos, literal_value(pattern)) {
+                    if let Some((lower, upper)) = like_range(&pattern) {
+                        add_bound(pos, Some(lower), upper);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Candidates in order of preference when their costs tie. The first
+    // column is the primary key.
+    let mut candidates = Vec::new();
+    for (pos, values) in lookups {
+        if pos == 0 {
+            let mut keys: Vec<u32> = values.iter().filter_map(SqlValue::to_key).collect();
+            keys.sort_unstable();
+            keys.dedup();
+            candidates.insert(0, AccessPath::PrimaryKeyLookup { keys });
+        } else if let Some(index) = index_on(table, pos) {
+            candidates.push(AccessPath::IndexLookup { index, values });
+        }
+    }
+    for (pos, lower, upper) in ranges {
+        if pos == 0 {
+            if let Some((start, end)) = key_range(&lower, &upper) {
+                candidates.push(AccessPath::PrimaryKeyRange {
+                    start,
+                    end,
+                    descending: false,
+                });
+            }
+        } else if let Some(index) = index_on(table, pos) {
+            candidates.push(AccessPath::IndexRange {
+                index,
+                lower,
+                upper,
+                order: None,
+            });
+        }
+    }
+    candidates.push(AccessPath::FullScan);
+
+    let mut best: Option<(AccessPath, Estimate)> = None;
+    for path in candidates {
+        let estimate = model.estimate(&path);
+        if best.as_ref().is_none_or(|(_, b)| estimate.cost < b.cost) {
+            best = Some((path, estimate));
+        }
+    }
+    let (path, mut estimate) = best.expect("a full scan is always possible");
+
+    // Terms the access path doesn't answer still filter its rows
+    let answered = !matches!(path, AccessPath::FullScan) as usize;
+    let filters = terms.len().saturating_sub(answered);
+    estimate.rows *= FILTER_FRACTION.powi(filters as i32);
+    (path, estimate)
+}
+
+/// Turn range bounds on the primary key into an inclusive key range.
+/// Returns None when a bound isn't numeric, since then it can't be mapped
+/// onto the B-Tree key order.
+fn key_range(lower: &Option<Bound>, upper: &Option<Bound>) -> Option<(u32, u32)> {
+    let numeric = SqlValue::as_integer;
+
+    let start = match lower {
+        Some((value, inclusive)) => numeric(value)? + if *inclusive { 0 } else { 1 },
+        None => 0,
+    };
+    let end = match upper {
+        Some((value, inclusive)) => numeric(value)? - if *inclusive { 0 } else { 1 },
+        None => u32::MAX as i64,
+    };
+
+    // An empty range is still a valid (if trivial) scan
+    if end < 0 || start > u32::MAX as i64 || start > end {
+        return Some((1, 0));
+    }
+    Some((start.max(0) as u32, end.min(u32::MAX as i64) as u32))
+}
+
+/// The index range holding every value a LIKE pattern can match: from the
+/// literal prefix before its first wildcard up to the first string past
+/// everything that starts with it
+fn like_range(pattern: &str) -> Option<(Bound, Option<Bound>)> {
+    let prefix: String = pattern
+        .chars()
+        .take_while(|c| !matches!(c, '%' | '_'))
+        .collect();
+    if prefix.is_empty() {
+        return None;
+    }
+
+    // Bump the last character that has a successor, dropping any after it
+    let mut next = prefix.clone();
+    let upper = loop {
+        let Some(last) = next.pop() else {
+            break None;
+        };
+        if let Some(bumped) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            next.push(bumped);
+            break Some((SqlValue::Text(next), false));
+        }
+    };
+    Some(((SqlValue::Text(prefix), true), upper))
+}
+
+/// Flatten the ANDed terms of a condition
+pub fn conjuncts<'a>(expr: &'a Expr, out: &mut Vec<&'a Expr>) {
+    match expr {
+        Expr::Logical {
+            left,
+            op: LogicalOp::And,
+            right,
+        } => {
+            conjuncts(left, out);
+            conjuncts(right, out);
+        }
+        _ => out.push(expr),
+    }
+}
+
+fn literal_value(expr: &Expr) -> Option<SqlValue> {
+    match expr {
+        Expr::Literal(value) => Some(SqlValue::from(value)),
+        _ => None,
+    }
+}
+
+/// Match `column op literal` or `literal op column`, returning the column's
+/// row position, the operator as seen from the column's side and the literal
+fn column_compared_to_literal(
+    left: &Expr,
+    op: &CompareOp,
+    right: &Expr,
+    scope: &Scope,
+) -> Option<(usize, CompareOp, SqlValue)> {
+    match (left, right) {
+        (Expr::Column(col), other) => {
+            Some((scope.resolve(col).ok()?, op.clone(), literal_value(other)?))
+        }
+        (other, Expr::Column(col)) => Some((
+            scope.resolve(col).ok()?,
+            op.flipped(),
+            literal_value(other)?,
+        )),
+        _ => None,
+    }
+}
+
+/// How a single JOIN is evaluated. Positions are row offsets: `left` within
+/// the rows joined so far, `right` within the joined table.
+pub enum JoinStrategy {
+    /// Compare every pair of rows
+    NestedLoop,
+    /// Bucket the right table by its join column, then probe with each left row
+    Hash { left: usize, right: usize },
+    /// Look up the right table's B-Tree by primary key for each left row
+    PrimaryKeyLookup { left: usize },
+    /// Look up a secondary index on the right table for each left row
+    IndexLookup { left: usize, index: String },
+}
+
+/// Pick the cheapest way to join `left_rows` rows with the table last added
+/// to `scope`, whose own rows are read at the cost of `right`
+pub fn choose_join_strategy(
+    join_type: &JoinType,
+    on: Option<&Expr>,
+    scope: &Scope,
+    left_width: usize,
+    model: &CostModel,
+    left_rows: f64,
+    right: Estimate,
+) -> (JoinStrategy, Estimate) {
+    let mut candidates = Vec::new();
+    // Share of the right table its own conditions keep
+    let kept = right.rows / model.rows().max(1.0);
+
+    match on.and_then(|on| equi_join_columns(on, scope, left_width)) {
+        Some((left, right_pos)) => {
+            let seek = model.seek_cost();
+            let per_value = model.rows_per_value(right_pos);
+
+            // Lookups only visit matching right rows, so they can't produce
+            // the unmatched right rows RIGHT and FULL joins need
+            if let (Some(table), JoinType::Inner | JoinType::Left) = (model.table, join_type) {
+                let column = &table.columns[right_pos].name;
+                if right_pos == 0 {
+                    candidates.push((
+                        JoinStrategy::PrimaryKeyLookup { left },
+                        Estimate {
+                            cost: left_rows * seek,
+                            rows: left_rows * kept,
+                        },
+                    ));
+                } else if let Some(index) =
+                    table.indexes.values().find(|i| &i.column_name == column)
+                {
+                    candidates.push((
+                        JoinStrategy::IndexLookup {
+                            left,
+                            index: index.name.clone(),
+                        },
+                        Estimate {
+                            cost: left_rows * (seek + per_value * seek),
+                            rows: left_rows * per_value * kept,
+                        },
+                    ));
+                }
+            }
+
+            candidates.push((
+                JoinStrategy::Hash {
+                    left,
+                    right: right_pos,
+                },
+                Estimate {
+                    cost: right.cost + right.rows + left_rows,
+                    rows: left_rows * right.rows / model.distinct(right_pos),
+                },
+            ));
+        }
+        None => {
+            let filtered = if on.is_some() { FILTER_FRACTION } else { 1.0 };
+            candidates.push((
+                JoinStrategy::NestedLoop,
+                Estimate {
+                    cost: right.cost + left_rows * right.rows,
+                    rows: left_rows * right.rows * filtered,
+                },
+            ));
+        }
+    }
+
+    let (strategy, mut estimate) = candidates
+        .into_iter()
+        .reduce(|best, next| {
+            if next.1.cost < best.1.cost {
+                next
+            } else {
+                best
+            }
+        })
+        .expect("every join has a strategy");
+
+    // Outer joins keep their unmatched rows
+    if matches!(join_type, JoinType::Left | JoinType::Full) {
+        estimate.rows = estimate.rows.max(left_rows);
+    }
+    if matches!(join_type, JoinType::Right | JoinType::Full) {
+        estimate.rows = estimate.rows.max(right.rows);
+    }
+    (strategy, estimate)
+}
+
+/// Find a `left_col = right_col` term among the ANDed parts of an ON condition
+fn equi_join_columns(on: &Expr, scope: &Scope, left_width: usize) -> Option<(usize, usize)> {
+    match on {
+        Expr::Logical {
+            left,
+            op: LogicalOp::And,
+            right,
+        } => equi_join_columns(left, scope, left_width)
+            .or_else(|| equi_join_columns(right, scope, left_width)),
+        Expr::Compare {
+            left,
+            op: CompareOp::Equals,
+            right,
+        } => match (left.as_ref(), right.as_ref()) {
+            (Expr::Column(a), Expr::Column(b)) => {
+                let a = scope.resolve(a).ok()?;
+                let b = scope.resolve(b).ok()?;
+                if a < left_width && b >= left_width {
+                    Some((a, b - left_width))
+                } else if b < left_width && a >= left_width {
+                    Some((b, a - left_width))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
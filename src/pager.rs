@@ -3,7 +3,54 @@ use std::io;
 use std::os::unix::fs::FileExt;
 
 pub const PAGE_SIZE: usize = 4096;
-pub const TABLE_MAX_PAGES: usize = 100;
+
+/// Page 0 of every table and index file holds a `FileHeader`; the B-Tree's
+/// pages come after it
+pub const HEADER_PAGE: u32 = 0;
+
+/// Version of the on-disk layout, bumped when it changes incompatibly
+pub const FORMAT_VERSION: u32 = 4;
+
+// --- File Header Layout ---
+const HEADER_MAGIC: &[u8; 16] = b"rsql btree file\0";
+const HEADER_VERSION_OFFSET: usize = 16;
+const HEADER_ROOT_PAGE_OFFSET: usize = 20;
+const HEADER_PAGE_COUNT_OFFSET: usize = 24;
+const HEADER_SCHEMA_COOKIE_OFFSET: usize = 28;
+const HEADER_FIRST_FREE_PAGE_OFFSET: usize = 32;
+
+/// What a file records about the B-Tree stored in it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileHeader {
+    pub root_page: u32,
+    pub page_count: u32,
+    pub format_version: u32,
+    /// Fingerprint of the schema the file was created for, so a file can't
+    /// be opened as a table or index it doesn't belong to
+    pub schema_cookie: u32,
+    /// Head of the list of pages freed by merges, or 0 if there are none
+    pub first_free_page: u32,
+}
+
+/// Whether `filename` holds pages written before files had a header, or
+/// in an older format version
+pub fn is_outdated_file(filename: &str) -> bool {
+    let mut header = [0u8; HEADER_VERSION_OFFSET + 4];
+    let read = match File::open(filename).and_then(|file| file.read_at(&mut header, 0)) {
+        Ok(read) => read,
+        Err(_) => return false,
+    };
+    let version = u32::from_le_bytes(header[HEADER_VERSION_OFFSET..].try_into().unwrap());
+    read > 0 && (&header[..HEADER_MAGIC.len()] != HEADER_MAGIC || version < FORMAT_VERSION)
+}
+
+/// The schema cookie of a table or index, from a description of its schema
+pub fn schema_cookie(schema: &str) -> u32 {
+    // FNV-1a: stable across runs and platforms, unlike the std hasher
+    schema.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
 
 pub struct Pager {
     pub file: File,
@@ -18,23 +65,23 @@ impl Pager {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(filename)?;
         let file_length = file.metadata()?.len();
         let num_pages = (file_length / PAGE_SIZE as u64) as u32;
-        let mut pages = Vec::with_capacity(TABLE_MAX_PAGES);
-        for _ in 0..TABLE_MAX_PAGES {
-            pages.push(None);
-        }
 
         Ok(Pager {
             file,
             file_length,
             num_pages,
-            pages,
+            pages: Vec::new(),
         })
     }
 
     pub fn get_page(&mut self, page_num: usize) -> &mut [u8; PAGE_SIZE] {
+        if page_num >= self.pages.len() {
+            self.pages.resize_with(page_num + 1, || None);
+        }
         if self.pages[page_num].is_none() {
             let mut page = Box::new([0u8; PAGE_SIZE]);
             let offset = (page_num * PAGE_SIZE) as u64;
@@ -50,7 +97,7 @@ impl Pager {
     }
 
     pub fn flush(&mut self, page_num: usize) {
-        if let Some(page) = &self.pages[page_num] {
+        if let Some(Some(page)) = self.pages.get(page_num) {
             let offset = (page_num * PAGE_SIZE) as u64;
             self.file
                 .write_at(&**page, offset)
@@ -58,6 +105,54 @@ impl Pager {
         }
     }
 
+    /// Read the header from page 0. A new, empty file has none yet.
+    pub fn read_header(&mut self) -> Option<FileHeader> {
+        if self.file_length == 0 {
+            return None;
+        }
+        let page = self.get_page(HEADER_PAGE as usize);
+        if &page[..HEADER_MAGIC.len()] != HEADER_MAGIC {
+            return None;
+        }
+        let field =
+            |offset: usize| u32::from_le_bytes(page[offset..offset + 4].try_into().unwrap());
+        Some(FileHeader {
+            root_page: field(HEADER_ROOT_PAGE_OFFSET),
+            page_count: field(HEADER_PAGE_COUNT_OFFSET),
+            format_version: field(HEADER_VERSION_OFFSET),
+            schema_cookie: field(HEADER_SCHEMA_COOKIE_OFFSET),
+            first_free_page: field(HEADER_FIRST_FREE_PAGE_OFFSET),
+        })
+    }
+
+    /// Write a header into page 0. Like any other page, it reaches the disk
+    /// when page 0 is flushed.
+    pub fn write_header(&mut self, header: &FileHeader) {
+        let page = self.get_page(HEADER_PAGE as usize);
+        page.fill(0);
+        page[..HEADER_MAGIC.len()].copy_from_slice(HEADER_MAGIC);
+        for (offset, value) in [
+            (HEADER_VERSION_OFFSET, header.format_version),
+            (HEADER_ROOT_PAGE_OFFSET, header.root_page),
+            (HEADER_PAGE_COUNT_OFFSET, header.page_count),
+            (HEADER_SCHEMA_COOKIE_OFFSET, header.schema_cookie),
+            (HEADER_FIRST_FREE_PAGE_OFFSET, header.first_free_page),
+        ] {
+            page[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    /// Drop every cached page, including unflushed changes, and go back to
+    /// the file as it is on disk. Returns the header found there.
+    pub fn discard_changes(&mut self) -> Option<FileHeader> {
+        self.pages.clear();
+        self.file_length = self.file.metadata().map(|m| m.len()).unwrap_or(0);
+        self.num_pages = (self.file_length / PAGE_SIZE as u64) as u32;
+        let header = self.read_header()?;
+        self.num_pages = header.page_count;
+        Some(header)
+    }
+
     /// Flush all dirty pages to disk
     pub fn flush_all(&mut self) {
         for i in 0..self.num_pages as usize {
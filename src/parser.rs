@@ -9,7 +9,7 @@ pub enum Statement {
     CreateDatabase(String),
     Connect(String),
     Insert(InsertStmt),
-    Select(SelectStmt),
+    Select(Box<SelectStmt>),
     Delete(DeleteStmt),
     Update(UpdateStmt),
     DropTable(String),
@@ -17,6 +17,34 @@ pub enum Statement {
     Begin,
     Commit,
     Rollback,
+    Analyze(Option<String>),
+    /// EXPLAIN [QUERY PLAN | ANALYZE] statement
+    Explain {
+        analyze: bool,
+        statement: Box<Statement>,
+    },
+    /// WITH ... followed by a SELECT, INSERT, UPDATE or DELETE
+    With {
+        with: WithClause,
+        statement: Box<Statement>,
+    },
+}
+
+/// `WITH [RECURSIVE] cte, ...` in front of a statement
+#[derive(Debug, Clone)]
+pub struct WithClause {
+    pub recursive: bool,
+    pub ctes: Vec<Cte>,
+}
+
+/// One common table expression: `name [(columns)] AS (query)`. Under
+/// WITH RECURSIVE, a query ending in `UNION [ALL] SELECT ...` that reads
+/// `name` repeats that last block over the rows the previous round added.
+#[derive(Debug, Clone)]
+pub struct Cte {
+    pub name: String,
+    pub columns: Option<Vec<String>>,
+    pub query: SelectStmt,
 }
 
 #[derive(Debug, Clone)]
@@ -48,71 +76,652 @@ pub enum SqlType {
 #[derive(Debug, Clone)]
 pub struct InsertStmt {
     pub table_name: String,
+    /// The columns the values are for, or None for every column in order
     pub columns: Option<Vec<String>>,
     pub values: Vec<Value>,
+    /// `INSERT ... SELECT`: the rows of a query, in place of VALUES
+    pub select: Option<Box<SelectStmt>>,
+    /// `RETURNING` items, empty for `RETURNING *`
+    pub returning: Option<Vec<SelectItem>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Integer(i64),
     Text(String),
     Identifier(String),
+    Null,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct SelectStmt {
-    pub columns: Vec<String>, // Empty = *, otherwise column names
-    pub table_name: String,
+    pub columns: Vec<SelectItem>, // Empty = *, otherwise expressions
+    pub from: TableRef,
     pub joins: Vec<JoinClause>,
-    pub where_clause: Option<WhereClause>,
+    pub where_clause: Option<Expr>,
+    pub group_by: Vec<Expr>,
+    pub having: Option<Expr>,
+    pub order_by: Vec<OrderByItem>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    /// Further query blocks combined with this one, left to right. The
+    /// ORDER BY, LIMIT and OFFSET above then apply to the combined rows.
+    pub compound: Vec<(SetOperator, SelectStmt)>,
 }
 
-#[derive(Debug, Clone)]
+/// How a compound SELECT combines the rows of two query blocks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOperator {
+    Union,
+    UnionAll,
+    Intersect,
+    Except,
+}
+
+impl SetOperator {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SetOperator::Union => "UNION",
+            SetOperator::UnionAll => "UNION ALL",
+            SetOperator::Intersect => "INTERSECT",
+            SetOperator::Except => "EXCEPT",
+        }
+    }
+}
+
+/// One entry of a select list: an expression with an optional alias
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectItem {
+    pub expr: Expr,
+    pub alias: Option<String>,
+}
+
+impl SelectItem {
+    /// Column header for this item: the alias, or the expression as written
+    pub fn header(&self) -> String {
+        match &self.alias {
+            Some(alias) => alias.clone(),
+            None => self.expr.to_string(),
+        }
+    }
+}
+
+/// One ORDER BY term
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderByItem {
+    pub expr: Expr,
+    pub descending: bool,
+}
+
+impl std::fmt::Display for OrderByItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.descending {
+            true => write!(f, "{} DESC", self.expr),
+            false => write!(f, "{}", self.expr),
+        }
+    }
+}
+
+/// A table in a FROM or JOIN clause, optionally renamed with an alias.
+/// A derived table `(SELECT ...) AS t` carries its query, with the alias
+/// as its name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableRef {
+    pub name: String,
+    pub alias: Option<String>,
+    pub subquery: Option<Box<SelectStmt>>,
+}
+
+impl TableRef {
+    /// The name columns of this table are qualified with (alias if present)
+    pub fn binding(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// A column reference, optionally qualified: `column` or `table.column`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnRef {
+    pub table: Option<String>,
+    pub column: String,
+}
+
+impl std::fmt::Display for ColumnRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.table {
+            Some(table) => write!(f, "{}.{}", table, self.column),
+            None => write!(f, "{}", self.column),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct JoinClause {
     pub join_type: JoinType,
-    pub table_name: String,
-    pub left_column: String,
-    pub right_column: String,
+    pub table: TableRef,
+    pub on: Option<Expr>, // None for CROSS JOIN and comma-separated FROM lists
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum JoinType {
     Inner,
+    Left,
+    Right,
+    Full,
+    Cross,
 }
 
 #[derive(Debug, Clone)]
 pub struct DeleteStmt {
     pub table_name: String,
-    pub where_clause: Option<WhereClause>,
+    pub where_clause: Option<Expr>,
+    /// `RETURNING` items, empty for `RETURNING *`
+    pub returning: Option<Vec<SelectItem>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct UpdateStmt {
     pub table_name: String,
-    pub assignments: Vec<(String, Value)>,
-    pub where_clause: Option<WhereClause>,
+    /// Columns and the expressions giving their new values, evaluated
+    /// against each row's current values
+    pub assignments: Vec<(String, Expr)>,
+    pub where_clause: Option<Expr>,
+    /// `RETURNING` items, empty for `RETURNING *`
+    pub returning: Option<Vec<SelectItem>>,
 }
 
-#[derive(Debug, Clone)]
-pub struct WhereClause {
-    pub conditions: Vec<Condition>,
-    pub operators: Vec<LogicalOp>,
+/// Expression tree used by select lists, WHERE, JOIN ... ON, GROUP BY,
+/// HAVING and ORDER BY
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Column(ColumnRef),
+    Literal(Value),
+    Compare {
+        left: Box<Expr>,
+        op: CompareOp,
+        right: Box<Expr>,
+    },
+    /// `left + right`, `left - right`, `left * right`, `left / right` or
+    /// `left % right`
+    Arithmetic {
+        left: Box<Expr>,
+        op: ArithmeticOp,
+        right: Box<Expr>,
+    },
+    /// `-expr`
+    Negate(Box<Expr>),
+    Logical {
+        left: Box<Expr>,
+        op: LogicalOp,
+        right: Box<Expr>,
+    },
+    IsNull {
+        expr: Box<Expr>,
+        negated: bool,
+    },
+    InList {
+        expr: Box<Expr>,
+        list: Vec<Expr>,
+        negated: bool,
+    },
+    Between {
+        expr: Box<Expr>,
+        low: Box<Expr>,
+        high: Box<Expr>,
+        negated: bool,
+    },
+    /// `expr [NOT] LIKE pattern`, where `%` in the pattern matches any run
+    /// of characters and `_` any single one. Case matters.
+    Like {
+        expr: Box<Expr>,
+        pattern: Box<Expr>,
+        negated: bool,
+    },
+    /// COUNT/SUM/AVG/MIN/MAX over a group; `arg` is None for COUNT(*)
+    Aggregate {
+        func: AggregateFunc,
+        arg: Option<Box<Expr>>,
+    },
+    /// `(SELECT ...)` used as a value: the first column of its first row,
+    /// or NULL when it returns no rows
+    Subquery(Box<SelectStmt>),
+    /// `expr [NOT] IN (SELECT ...)`
+    InSubquery {
+        expr: Box<Expr>,
+        query: Box<SelectStmt>,
+        negated: bool,
+    },
+    /// `[NOT] EXISTS (SELECT ...)`
+    Exists {
+        query: Box<SelectStmt>,
+        negated: bool,
+    },
+    /// `CASE [operand] WHEN ... THEN ... [ELSE ...] END`. With an operand,
+    /// the first WHEN value equal to it picks the result; without, the
+    /// first true WHEN condition does. No match and no ELSE gives NULL.
+    Case {
+        operand: Option<Box<Expr>>,
+        branches: Vec<(Expr, Expr)>,
+        else_result: Option<Box<Expr>>,
+    },
+    /// A call of a scalar function by its upper-case name
+    Function {
+        name: String,
+        args: Vec<Expr>,
+    },
+    /// `func(args) OVER (...)`, computed for each row over the rows of its
+    /// partition; `args` is empty for COUNT(*)
+    Window {
+        func: WindowFunc,
+        args: Vec<Expr>,
+        over: WindowSpec,
+    },
 }
 
-#[derive(Debug, Clone)]
+impl Expr {
+    /// The expressions this one is computed from, in the scope it is
+    /// evaluated in: everything but the body of a subquery. Walkers recurse
+    /// through this, so a new variant only has to be added here.
+    pub fn operands(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Column(_) | Expr::Literal(_) | Expr::Subquery(_) | Expr::Exists { .. } => {
+                Vec::new()
+            }
+            Expr::Compare { left, right, .. }
+            | Expr::Arithmetic { left, right, .. }
+            | Expr::Logical { left, right, .. } => vec![left, right],
+            Expr::Negate(expr) | Expr::IsNull { expr, .. } | Expr::InSubquery { expr, .. } => {
+                vec![expr]
+            }
+            Expr::InList { expr, list, .. } => std::iter::once(&**expr).chain(list).collect(),
+            Expr::Between {
+                expr, low, high, ..
+            } => vec![expr, low, high],
+            Expr::Like { expr, pattern, .. } => vec![expr, pattern],
+            Expr::Aggregate { arg, .. } => arg.as_deref().into_iter().collect(),
+            Expr::Case {
+                operand,
+                branches,
+                else_result,
+            } => operand
+                .as_deref()
+                .into_iter()
+                .chain(branches.iter().flat_map(|(when, then)| [when, then]))
+                .chain(else_result.as_deref())
+                .collect(),
+            Expr::Function { args, .. } => args.iter().collect(),
+            Expr::Window { args, over, .. } => args
+                .iter()
+                .chain(&over.partition_by)
+                .chain(over.order_by.iter().map(|item| &item.expr))
+                .collect(),
+        }
+    }
+
+    /// `operands`, to be changed in place
+    pub fn operands_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Expr::Column(_) | Expr::Literal(_) | Expr::Subquery(_) | Expr::Exists { .. } => {
+                Vec::new()
+            }
+            Expr::Compare { left, right, .. }
+            | Expr::Arithmetic { left, right, .. }
+            | Expr::Logical { left, right, .. } => vec![left, right],
+            Expr::Negate(expr) | Expr::IsNull { expr, .. } | Expr::InSubquery { expr, .. } => {
+                vec![expr]
+            }
+            Expr::InList { expr, list, .. } => std::iter::once(&mut **expr).chain(list).collect(),
+            Expr::Between {
+                expr, low, high, ..
+            } => vec![expr, low, high],
+            Expr::Like { expr, pattern, .. } => vec![expr, pattern],
+            Expr::Aggregate { arg, .. } => arg.as_deref_mut().into_iter().collect(),
+            Expr::Case {
+                operand,
+                branches,
+                else_result,
+            } => operand
+                .as_deref_mut()
+                .into_iter()
+                .chain(branches.iter_mut().flat_map(|(when, then)| [when, then]))
+                .chain(else_result.as_deref_mut())
+                .collect(),
+            Expr::Function { args, .. } => args.iter_mut().collect(),
+            Expr::Window { args, over, .. } => args
+                .iter_mut()
+                .chain(&mut over.partition_by)
+                .chain(over.order_by.iter_mut().map(|item| &mut item.expr))
+                .collect(),
+        }
+    }
+}
+
+/// The OVER clause of a window function
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowSpec {
+    pub partition_by: Vec<Expr>,
+    pub order_by: Vec<OrderByItem>,
+    /// Rows an aggregate covers. Without a frame it covers the partition up
+    /// to the current row and the rows ordered the same, or the whole
+    /// partition when there is no ORDER BY.
+    pub frame: Option<WindowFrame>,
+}
+
+/// `ROWS BETWEEN start AND end`, relative to the current row
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowFrame {
+    pub start: FrameBound,
+    pub end: FrameBound,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameBound {
+    UnboundedPreceding,
+    Preceding(usize),
+    CurrentRow,
+    Following(usize),
+    UnboundedFollowing,
+}
+
+impl std::fmt::Display for WindowSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if !self.partition_by.is_empty() {
+            let exprs: Vec<String> = self.partition_by.iter().map(|e| e.to_string()).collect();
+            parts.push(format!("PARTITION BY {}", exprs.join(", ")));
+        }
+        if !self.order_by.is_empty() {
+            let terms: Vec<String> = self.order_by.iter().map(|t| t.to_string()).collect();
+            parts.push(format!("ORDER BY {}", terms.join(", ")));
+        }
+        if let Some(frame) = &self.frame {
+            parts.push(format!("ROWS BETWEEN {} AND {}", frame.start, frame.end));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+impl std::fmt::Display for FrameBound {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FrameBound::UnboundedPreceding => write!(f, "UNBOUNDED PRECEDING"),
+            FrameBound::Preceding(n) => write!(f, "{} PRECEDING", n),
+            FrameBound::CurrentRow => write!(f, "CURRENT ROW"),
+            FrameBound::Following(n) => write!(f, "{} FOLLOWING", n),
+            FrameBound::UnboundedFollowing => write!(f, "UNBOUNDED FOLLOWING"),
+        }
+    }
+}
+
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let not = |negated: &bool| if *negated { "NOT " } else { "" };
+        match self {
+            Expr::Column(col) => write!(f, "{}", col),
+            Expr::Literal(Value::Integer(n)) => write!(f, "{}", n),
+            Expr::Literal(Value::Text(s)) => write!(f, "'{}'", s),
+            Expr::Literal(Value::Identifier(s)) => write!(f, "{}", s),
+            Expr::Literal(Value::Null) => write!(f, "NULL"),
+            Expr::Compare { left, op, right } => write!(f, "{} {} {}", left, op, right),
+            Expr::Arithmetic { left, op, right } => {
+                // Operands that bind looser need parentheses, as does a
+                // right operand that binds the same: a - (b - c)
+                let show = |f: &mut std::fmt::Formatter, e: &Expr, right: bool| match e {
+                    Expr::Arithmetic { op: inner, .. }
+                        if inner.precedence() < op.precedence()
+                            || (right && inner.precedence() == op.precedence()) =>
+                    {
+                        write!(f, "({})", e)
+                    }
+                    _ => write!(f, "{}", e),
+                };
+                show(f, left, false)?;
+                write!(f, " {} ", op)?;
+                show(f, right, true)
+            }
+            Expr::Negate(expr) => match **expr {
+                Expr::Arithmetic { .. } | Expr::Negate(_) => write!(f, "-({})", expr),
+                _ => write!(f, "-{}", expr),
+            },
+            Expr::Logical { left, op, right } => {
+                // OR binds looser than AND, so it needs parentheses inside one
+                let show = |f: &mut std::fmt::Formatter, e: &Expr| match (op, e) {
+                    (
+                        LogicalOp::And,
+                        Expr::Logical {
+                            op: LogicalOp::Or, ..
+                        },
+                    ) => write!(f, "({})", e),
+                    _ => write!(f, "{}", e),
+                };
+                show(f, left)?;
+                match op {
+                    LogicalOp::And => write!(f, " AND ")?,
+                    LogicalOp::Or => write!(f, " OR ")?,
+                }
+                show(f, right)
+            }
+            Expr::IsNull { expr, negated } => write!(f, "{} IS {}NULL", expr, not(negated)),
+            Expr::InList {
+                expr,
+                list,
+                negated,
+            } => {
+                let items: Vec<String> = list.iter().map(|e| e.to_string()).collect();
+                write!(f, "{} {}IN ({})", expr, not(negated), items.join(", "))
+            }
+            Expr::Between {
+                expr,
+                low,
+                high,
+                negated,
+            } => write!(f, "{} {}BETWEEN {} AND {}", expr, not(negated), low, high),
+            Expr::Like {
+                expr,
+                pattern,
+                negated,
+            } => write!(f, "{} {}LIKE {}", expr, not(negated), pattern),
+            Expr::Aggregate { func, arg } => match arg {
+                Some(arg) => write!(f, "{}({})", func.name(), arg),
+                None => write!(f, "{}(*)", func.name()),
+            },
+            Expr::Subquery(query) => write!(f, "({})", query),
+            Expr::InSubquery {
+                expr,
+                query,
+                negated,
+            } => write!(f, "{} {}IN ({})", expr, not(negated), query),
+            Expr::Exists { query, negated } => write!(f, "{}EXISTS ({})", not(negated), query),
+            Expr::Case {
+                operand,
+                branches,
+                else_result,
+            } => {
+                write!(f, "CASE")?;
+                if let Some(operand) = operand {
+                    write!(f, " {}", operand)?;
+                }
+                for (when, then) in branches {
+                    write!(f, " WHEN {} THEN {}", when, then)?;
+                }
+                if let Some(else_result) = else_result {
+                    write!(f, " ELSE {}", else_result)?;
+                }
+                write!(f, " END")
+            }
+            Expr::Function { name, args } => {
+                let args: Vec<String> = args.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}({})", name, args.join(", "))
+            }
+            Expr::Window { func, args, over } => {
+                let args: Vec<String> = args.iter().map(|e| e.to_string()).collect();
+                let args = match func {
+                    WindowFunc::Aggregate(AggregateFunc::Count) if args.is_empty() => "*".into(),
+                    _ => args.join(", "),
+                };
+                write!(f, "{}({}) OVER ({})", func.name(), args, over)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for SelectStmt {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let list = |exprs: &[Expr]| {
+            exprs
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        write!(f, "SELECT ")?;
+        if self.columns.is_empty() {
+            write!(f, "*")?;
+        }
+        for (i, item) in self.columns.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", item.expr)?;
+            if let Some(alias) = &item.alias {
+                write!(f, " AS {}", alias)?;
+            }
+        }
+
+        write!(f, " FROM {}", self.from)?;
+        for join in &self.joins {
+            match (&join.join_type, &join.on) {
+                (JoinType::Cross, _) => write!(f, ", {}", join.table)?,
+                (join_type, on) => {
+                    let keyword = match join_type {
+                        JoinType::Left => "LEFT JOIN",
+                        JoinType::Right => "RIGHT JOIN",
+                        JoinType::Full => "FULL JOIN",
+                        _ => "JOIN",
+                    };
+                    write!(f, " {} {}", keyword, join.table)?;
+                    if let Some(on) = on {
+                        write!(f, " ON {}", on)?;
+                    }
+                }
+            }
+        }
+
+        if let Some(where_clause) = &self.where_clause {
+            write!(f, " WHERE {}", where_clause)?;
+        }
+        if !self.group_by.is_empty() {
+            write!(f, " GROUP BY {}", list(&self.group_by))?;
+        }
+        if let Some(having) = &self.having {
+            write!(f, " HAVING {}", having)?;
+        }
+        for (operator, query) in &self.compound {
+            write!(f, " {} {}", operator.name(), query)?;
+        }
+        if !self.order_by.is_empty() {
+            let terms: Vec<String> = self.order_by.iter().map(|t| t.to_string()).collect();
+            write!(f, " ORDER BY {}", terms.join(", "))?;
+        }
+        if let Some(limit) = self.limit {
+            write!(f, " LIMIT {}", limit)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " OFFSET {}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for TableRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.subquery {
+            Some(query) => write!(f, "({})", query)?,
+            None => write!(f, "{}", self.name)?,
+        }
+        match &self.alias {
+            Some(alias) => write!(f, " AS {}", alias),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggregateFunc {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+impl AggregateFunc {
+    pub fn from_name(name: &str) -> Option<AggregateFunc> {
+        match name.to_uppercase().as_str() {
+            "COUNT" => Some(AggregateFunc::Count),
+            "SUM" => Some(AggregateFunc::Sum),
+            "AVG" => Some(AggregateFunc::Avg),
+            "MIN" => Some(AggregateFunc::Min),
+            "MAX" => Some(AggregateFunc::Max),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AggregateFunc::Count => "COUNT",
+            AggregateFunc::Sum => "SUM",
+            AggregateFunc::Avg => "AVG",
+            AggregateFunc::Min => "MIN",
+            AggregateFunc::Max => "MAX",
+        }
+    }
+}
+
+/// Functions that can be computed over a window: the ranking and offset
+/// functions, which only exist as window functions, and the aggregates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WindowFunc {
+    RowNumber,
+    Rank,
+    DenseRank,
+    Lag,
+    Lead,
+    Aggregate(AggregateFunc),
+}
+
+impl WindowFunc {
+    pub fn from_name(name: &str) -> Option<WindowFunc> {
+        match name.to_uppercase().as_str() {
+            "ROW_NUMBER" => Some(WindowFunc::RowNumber),
+            "RANK" => Some(WindowFunc::Rank),
+            "DENSE_RANK" => Some(WindowFunc::DenseRank),
+            "LAG" => Some(WindowFunc::Lag),
+            "LEAD" => Some(WindowFunc::Lead),
+            _ => AggregateFunc::from_name(name).map(WindowFunc::Aggregate),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            WindowFunc::RowNumber => "ROW_NUMBER",
+            WindowFunc::Rank => "RANK",
+            WindowFunc::DenseRank => "DENSE_RANK",
+            WindowFunc::Lag => "LAG",
+            WindowFunc::Lead => "LEAD",
+            WindowFunc::Aggregate(func) => func.name(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum LogicalOp {
     And,
     Or,
 }
 
-#[derive(Debug, Clone)]
-pub struct Condition {
-    pub column: String,
-    pub operator: CompareOp,
-    pub value: Value,
-}
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum CompareOp {
     Equals,
     NotEquals,
@@ -122,6 +731,65 @@ pub enum CompareOp {
     GreaterEquals,
 }
 
+impl CompareOp {
+    /// The operator with its operands swapped: `a < b` is `b > a`
+    pub fn flipped(&self) -> CompareOp {
+        match self {
+            CompareOp::LessThan => CompareOp::GreaterThan,
+            CompareOp::GreaterThan => CompareOp::LessThan,
+            CompareOp::LessEquals => CompareOp::GreaterEquals,
+            CompareOp::GreaterEquals => CompareOp::LessEquals,
+            other => other.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArithmeticOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+impl ArithmeticOp {
+    /// How tightly the operator binds: `*`, `/` and `%` before `+` and `-`
+    pub fn precedence(&self) -> u8 {
+        match self {
+            ArithmeticOp::Add | ArithmeticOp::Subtract => 1,
+            ArithmeticOp::Multiply | ArithmeticOp::Divide | ArithmeticOp::Remainder => 2,
+        }
+    }
+}
+
+impl std::fmt::Display for ArithmeticOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let symbol = match self {
+            ArithmeticOp::Add => "+",
+            ArithmeticOp::Subtract => "-",
+            ArithmeticOp::Multiply => "*",
+            ArithmeticOp::Divide => "/",
+            ArithmeticOp::Remainder => "%",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+impl std::fmt::Display for CompareOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let symbol = match self {
+            CompareOp::Equals => "=",
+            CompareOp::NotEquals => "!=",
+            CompareOp::LessThan => "<",
+            CompareOp::GreaterThan => ">",
+            CompareOp::LessEquals => "<=",
+            CompareOp::GreaterEquals => ">=",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
 pub struct Parser {
     tokens: Vec<Token>,
     pos: usize,
@@ -158,7 +826,33 @@ impl Parser {
         }
     }
 
+    /// Whether the next token is `word`, a keyword that isn't reserved and
+    /// so arrives as an identifier
+    fn at_word(&self, word: &str) -> bool {
+        matches!(self.peek(), Token::Identifier(name) if name.eq_ignore_ascii_case(word))
+    }
+
+    fn expect_word(&mut self, word: &str) -> Result<(), String> {
+        match self.advance() {
+            Token::Identifier(name) if name.eq_ignore_ascii_case(word) => Ok(()),
+            other => Err(format!("Expected {}, got {:?}", word.to_uppercase(), other)),
+        }
+    }
+
+    /// Parse one statement, optionally followed by semicolons. Anything
+    /// after it is an error rather than being silently dropped.
     pub fn parse(&mut self) -> Result<Statement, String> {
+        let statement = self.parse_statement()?;
+        while *self.peek() == Token::Semicolon {
+            self.advance();
+        }
+        match self.peek() {
+            Token::Eof => Ok(statement),
+            other => Err(format!("Unexpected token: {:?}", other)),
+        }
+    }
+
+    fn parse_statement(&mut self) -> Result<Statement, String> {
         match self.peek() {
             Token::Create => self.parse_create(),
             Token::Insert => self.parse_insert(),
@@ -178,6 +872,16 @@ impl Parser {
                 self.advance();
                 Ok(Statement::Rollback)
             }
+            Token::Analyze => {
+                self.advance();
+                let table_name = match self.peek() {
+                    Token::Identifier(_) => Some(self.expect_identifier()?),
+                    _ => None,
+                };
+                Ok(Statement::Analyze(table_name))
+            }
+            Token::Explain => self.parse_explain(),
+            Token::With => self.parse_with(),
             Token::Connect => {
                 self.advance();
                 let db_name = self.expect_identifier()?;
@@ -187,6 +891,106 @@ impl Parser {
         }
     }
 
+    fn parse_explain(&mut self) -> Result<Statement, String> {
+        self.expect(Token::Explain)?;
+
+        // QUERY PLAN is optional and not reserved, so it arrives as identifiers
+        let analyze = match self.peek().clone() {
+            Token::Analyze => {
+                self.advance();
+                true
+            }
+            _ if self.at_word("query") => {
+                self.advance();
+                self.expect_word("plan")?;
+                false
+            }
+            _ => false,
+        };
+
+        let statement = match self.peek() {
+            Token::Select => self.parse_select()?,
+            Token::Update => self.parse_update()?,
+            Token::Delete => self.parse_delete()?,
+            Token::With => self.parse_with()?,
+            other => {
+                return Err(format!(
+                    "EXPLAIN expects SELECT, UPDATE or DELETE, got {:?}",
+                    other
+                ))
+            }
+        };
+
+        Ok(Statement::Explain {
+            analyze,
+            statement: Box::new(statement),
+        })
+    }
+
+    fn parse_with(&mut self) -> Result<Statement, String> {
+        self.expect(Token::With)?;
+        let recursive = *self.peek() == Token::Recursive;
+        if recursive {
+            self.advance();
+        }
+
+        let mut ctes = Vec::new();
+        loop {
+            ctes.push(self.parse_cte()?);
+            if *self.peek() == Token::Comma {
+                self.advance();
+            } else {
+                break;
+            }
+        }
+
+        let statement = match self.peek() {
+            Token::Select => self.parse_select()?,
+            Token::Insert => self.parse_insert()?,
+            Token::Update => self.parse_update()?,
+            Token::Delete => self.parse_delete()?,
+            other => {
+                return Err(format!(
+                    "Expected SELECT, INSERT, UPDATE or DELETE after WITH, got {:?}",
+                    other
+                ))
+            }
+        };
+
+        Ok(Statement::With {
+            with: WithClause { recursive, ctes },
+            statement: Box::new(statement),
+        })
+    }
+
+    /// Parse `name [(columns)] AS (SELECT ... [UNION [ALL] SELECT ...])`
+    fn parse_cte(&mut self) -> Result<Cte, String> {
+        let name = self.expect_identifier()?;
+        let columns = if *self.peek() == Token::LeftParen {
+            self.advance();
+            let mut columns = vec![self.expect_identifier()?];
+            while *self.peek() == Token::Comma {
+                self.advance();
+                columns.push(self.expect_identifier()?);
+            }
+            self.expect(Token::RightParen)?;
+            Some(columns)
+        } else {
+            None
+        };
+
+        self.expect(Token::As)?;
+        self.expect(Token::LeftParen)?;
+        let query = self.parse_select_stmt()?;
+        self.expect(Token::RightParen)?;
+
+        Ok(Cte {
+            name,
+            columns,
+            query,
+        })
+    }
+
     fn parse_create(&mut self) -> Result<Statement, String> {
         self.advance(); // consume CREATE
 
@@ -287,6 +1091,16 @@ impl Parser {
             None
         };
 
+        if *self.peek() == Token::Select {
+            return Ok(Statement::Insert(InsertStmt {
+                table_name,
+                columns,
+                values: Vec::new(),
+                select: Some(Box::new(self.parse_select_stmt()?)),
+                returning: self.parse_returning()?,
+            }));
+        }
+
         self.expect(Token::Values)?;
         self.expect(Token::LeftParen)?;
 
@@ -296,6 +1110,7 @@ impl Parser {
                 Token::Number(n) => Value::Integer(n),
                 Token::StringLiteral(s) => Value::Text(s),
                 Token::Identifier(s) => Value::Identifier(s),
+                Token::Null => Value::Null,
                 other => return Err(format!("Expected value, got {:?}", other)),
             };
             values.push(value);
@@ -316,67 +1131,309 @@ impl Parser {
             table_name,
             columns,
             values,
+            select: None,
+            returning: self.parse_returning()?,
         }))
     }
 
     fn parse_select(&mut self) -> Result<Statement, String> {
-        self.advance(); // consume SELECT
+        Ok(Statement::Select(Box::new(self.parse_select_stmt()?)))
+    }
 
-        let columns = if *self.peek() == Token::Asterisk {
-            self.advance();
-            Vec::new() // * means all columns
-        } else {
-            let mut cols = Vec::new();
-            loop {
-                cols.push(self.expect_identifier()?);
-                if *self.peek() == Token::Comma {
+    /// Parse a SELECT, which may also be a subquery: one or more query
+    /// blocks joined by UNION [ALL], INTERSECT or EXCEPT, then ORDER BY and
+    /// LIMIT for the whole
+    fn parse_select_stmt(&mut self) -> Result<SelectStmt, String> {
+        let mut stmt = self.parse_select_core()?;
+        loop {
+            let operator = match self.peek() {
+                Token::Union => {
                     self.advance();
-                } else {
-                    break;
+                    if *self.peek() == Token::All {
+                        self.advance();
+                        SetOperator::UnionAll
+                    } else {
+                        SetOperator::Union
+                    }
                 }
-            }
-            cols
-        };
+                Token::Intersect => {
+                    self.advance();
+                    SetOperator::Intersect
+                }
+                Token::Except => {
+                    self.advance();
+                    SetOperator::Except
+                }
+                _ => break,
+            };
+            stmt.compound.push((operator, self.parse_select_core()?));
+        }
 
-        self.expect(Token::From)?;
-        let table_name = self.expect_identifier()?;
+        if *self.peek() == Token::Order {
+            stmt.order_by = self.parse_order_by()?;
+        }
 
-        // Parse JOINs
-        let mut joins = Vec::new();
-        while *self.peek() == Token::Inner || *self.peek() == Token::Join {
-            // Handle optional INNER keyword
-            if *self.peek() == Token::Inner {
+        if *self.peek() == Token::Limit {
+            self.advance();
+            stmt.limit = Some(self.expect_count()?);
+            if *self.peek() == Token::Offset {
                 self.advance();
+                stmt.offset = Some(self.expect_count()?);
             }
-            self.expect(Token::Join)?;
-
-            let join_table = self.expect_identifier()?;
-            self.expect(Token::On)?;
+        }
 
-            let left_column = self.expect_identifier()?;
-            self.expect(Token::Equals)?;
-            let right_column = self.expect_identifier()?;
+        Ok(stmt)
+    }
 
-            joins.push(JoinClause {
-                join_type: JoinType::Inner,
-                table_name: join_table,
-                left_column,
-                right_column,
-            });
+    /// Parse a select list: `*`, which gives no items, or
+    /// `expr [[AS] alias], ...`
+    fn parse_select_items(&mut self) -> Result<Vec<SelectItem>, String> {
+        if *self.peek() == Token::Asterisk {
+            self.advance();
+            return Ok(Vec::new()); // * means all columns
         }
-
-        let where_clause = if *self.peek() == Token::Where {
-            Some(self.parse_where()?)
-        } else {
+        let mut cols = Vec::new();
+        loop {
+            let expr = self.parse_expr()?;
+            let alias = match self.peek() {
+                Token::As => {
+                    self.advance();
+                    Some(self.expect_identifier()?)
+                }
+                Token::Identifier(_) if !self.at_word("RETURNING") => {
+                    Some(self.expect_identifier()?)
+                }
+                _ => None,
+            };
+            cols.push(SelectItem { expr, alias });
+            if *self.peek() == Token::Comma {
+                self.advance();
+            } else {
+                break;
+            }
+        }
+        Ok(cols)
+    }
+
+    /// Parse an optional `RETURNING *` or `RETURNING expr [[AS] alias], ...`
+    /// at the end of INSERT, UPDATE or DELETE
+    fn parse_returning(&mut self) -> Result<Option<Vec<SelectItem>>, String> {
+        if !self.at_word("RETURNING") {
+            return Ok(None);
+        }
+        self.advance();
+        Ok(Some(self.parse_select_items()?))
+    }
+
+    /// Parse one query block: SELECT through HAVING
+    fn parse_select_core(&mut self) -> Result<SelectStmt, String> {
+        self.expect(Token::Select)?;
+
+        let columns = self.parse_select_items()?;
+
+        self.expect(Token::From)?;
+        let from = self.parse_table_ref()?;
+
+        // Parse JOINs and comma-separated tables
+        let mut joins = Vec::new();
+        loop {
+            let join_type = match self.peek() {
+                Token::Comma => {
+                    self.advance();
+                    joins.push(JoinClause {
+                        join_type: JoinType::Cross,
+                        table: self.parse_table_ref()?,
+                        on: None,
+                    });
+                    continue;
+                }
+                Token::Join => JoinType::Inner,
+                Token::Inner => {
+                    self.advance();
+                    JoinType::Inner
+                }
+                Token::Cross => {
+                    self.advance();
+                    JoinType::Cross
+                }
+                Token::Left | Token::Right | Token::Full => {
+                    let join_type = match self.advance() {
+                        Token::Left => JoinType::Left,
+                        Token::Right => JoinType::Right,
+                        _ => JoinType::Full,
+                    };
+                    // Optional OUTER keyword
+                    if *self.peek() == Token::Outer {
+                        self.advance();
+                    }
+                    join_type
+                }
+                _ => break,
+            };
+            self.expect(Token::Join)?;
+
+            let table = self.parse_table_ref()?;
+            let on = if join_type == JoinType::Cross {
+                None
+            } else {
+                self.expect(Token::On)?;
+                Some(self.parse_expr()?)
+            };
+
+            joins.push(JoinClause {
+                join_type,
+                table,
+                on,
+            });
+        }
+
+        let where_clause = if *self.peek() == Token::Where {
+            Some(self.parse_where()?)
+        } else {
+            None
+        };
+
+        let group_by = if *self.peek() == Token::Group {
+            self.advance();
+            self.expect(Token::By)?;
+            self.parse_expr_list()?
+        } else {
+            Vec::new()
+        };
+
+        let having = if *self.peek() == Token::Having {
+            self.advance();
+            Some(self.parse_expr()?)
+        } else {
             None
         };
 
-        Ok(Statement::Select(SelectStmt {
+        Ok(SelectStmt {
             columns,
-            table_name,
+            from,
             joins,
             where_clause,
-        }))
+            group_by,
+            having,
+            order_by: Vec::new(),
+            limit: None,
+            offset: None,
+            compound: Vec::new(),
+        })
+    }
+
+    /// Parse `ORDER BY expr [ASC | DESC], ...`
+    fn parse_order_by(&mut self) -> Result<Vec<OrderByItem>, String> {
+        self.expect(Token::Order)?;
+        self.expect(Token::By)?;
+        let mut items = Vec::new();
+        loop {
+            let expr = self.parse_expr()?;
+            let descending = match self.peek() {
+                Token::Asc => {
+                    self.advance();
+                    false
+                }
+                Token::Desc => {
+                    self.advance();
+                    true
+                }
+                _ => false,
+            };
+            items.push(OrderByItem { expr, descending });
+            if *self.peek() == Token::Comma {
+                self.advance();
+            } else {
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    /// Parse `(SELECT ...)`
+    fn parse_subquery(&mut self) -> Result<Box<SelectStmt>, String> {
+        self.expect(Token::LeftParen)?;
+        let query = self.parse_select_stmt()?;
+        self.expect(Token::RightParen)?;
+        Ok(Box::new(query))
+    }
+
+    /// Whether the next tokens open a subquery: `(SELECT`
+    fn at_subquery(&self) -> bool {
+        *self.peek() == Token::LeftParen && self.tokens.get(self.pos + 1) == Some(&Token::Select)
+    }
+
+    /// Parse a comma-separated list of expressions
+    fn parse_expr_list(&mut self) -> Result<Vec<Expr>, String> {
+        let mut exprs = vec![self.parse_expr()?];
+        while *self.peek() == Token::Comma {
+            self.advance();
+            exprs.push(self.parse_expr()?);
+        }
+        Ok(exprs)
+    }
+
+    /// Parse the row count of a LIMIT or OFFSET
+    fn expect_count(&mut self) -> Result<usize, String> {
+        match self.advance() {
+            Token::Number(n) => Ok(n as usize),
+            other => Err(format!("Expected row count, got {:?}", other)),
+        }
+    }
+
+    /// Parse `table [[AS] alias]` or `(SELECT ...) [AS] alias`
+    fn parse_table_ref(&mut self) -> Result<TableRef, String> {
+        let subquery = if self.at_subquery() {
+            Some(self.parse_subquery()?)
+        } else {
+            None
+        };
+        let name = match subquery {
+            Some(_) => String::new(),
+            None => self.expect_identifier()?,
+        };
+        let alias = match self.peek() {
+            Token::As => {
+                self.advance();
+                Some(self.expect_identifier()?)
+            }
+            // RETURNING ends an INSERT ... SELECT rather than naming its table
+            Token::Identifier(_) if !self.at_word("RETURNING") => Some(self.expect_identifier()?),
+            _ => None,
+        };
+
+        // A derived table is known only by its alias
+        if subquery.is_some() {
+            let alias = alias.ok_or("Subquery in FROM must have an alias")?;
+            return Ok(TableRef {
+                name: alias,
+                alias: None,
+                subquery,
+            });
+        }
+        Ok(TableRef {
+            name,
+            alias,
+            subquery,
+        })
+    }
+
+    /// Parse `column` or `table.column`
+    fn parse_column_ref(&mut self) -> Result<ColumnRef, String> {
+        let first = self.expect_identifier()?;
+        if *self.peek() == Token::Dot {
+            self.advance();
+            let column = self.expect_identifier()?;
+            Ok(ColumnRef {
+                table: Some(first),
+                column,
+            })
+        } else {
+            Ok(ColumnRef {
+                table: None,
+                column: first,
+            })
+        }
     }
 
     fn parse_delete(&mut self) -> Result<Statement, String> {
@@ -394,6 +1451,7 @@ impl Parser {
         Ok(Statement::Delete(DeleteStmt {
             table_name,
             where_clause,
+            returning: self.parse_returning()?,
         }))
     }
 
@@ -406,13 +1464,7 @@ impl Parser {
         loop {
             let col_name = self.expect_identifier()?;
             self.expect(Token::Equals)?;
-            let value = match self.advance() {
-                Token::Number(n) => Value::Integer(n),
-                Token::StringLiteral(s) => Value::Text(s),
-                Token::Identifier(s) => Value::Identifier(s),
-                other => return Err(format!("Expected value, got {:?}", other)),
-            };
-            assignments.push((col_name, value));
+            assignments.push((col_name, self.parse_expr()?));
 
             if *self.peek() == Token::Comma {
                 self.advance();
@@ -431,6 +1483,7 @@ impl Parser {
             table_name,
             assignments,
             where_clause,
+            returning: self.parse_returning()?,
         }))
     }
 
@@ -465,54 +1518,451 @@ impl Parser {
         }))
     }
 
-    fn parse_where(&mut self) -> Result<WhereClause, String> {
+    fn parse_where(&mut self) -> Result<Expr, String> {
         self.advance(); // consume WHERE
+        self.parse_expr()
+    }
 
-        let mut conditions = Vec::new();
-        let mut operators = Vec::new();
-
-        loop {
-            let column = self.expect_identifier()?;
-
-            let operator = match self.advance() {
-                Token::Equals => CompareOp::Equals,
-                Token::NotEquals => CompareOp::NotEquals,
-                Token::LessThan => CompareOp::LessThan,
-                Token::GreaterThan => CompareOp::GreaterThan,
-                Token::LessEquals => CompareOp::LessEquals,
-                Token::GreaterEquals => CompareOp::GreaterEquals,
-                other => return Err(format!("Expected comparison operator, got {:?}", other)),
+    /// Parse an expression. AND binds tighter than OR.
+    fn parse_expr(&mut self) -> Result<Expr, String> {
+        let mut left = self.parse_and()?;
+        while *self.peek() == Token::Or {
+            self.advance();
+            let right = self.parse_and()?;
+            left = Expr::Logical {
+                left: Box::new(left),
+                op: LogicalOp::Or,
+                right: Box::new(right),
             };
+        }
+        Ok(left)
+    }
 
-            let value = match self.advance() {
-                Token::Number(n) => Value::Integer(n),
-                Token::StringLiteral(s) => Value::Text(s),
-                Token::Identifier(s) => Value::Identifier(s),
-                other => return Err(format!("Expected value, got {:?}", other)),
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        let mut left = self.parse_comparison()?;
+        while *self.peek() == Token::And {
+            self.advance();
+            let right = self.parse_comparison()?;
+            left = Expr::Logical {
+                left: Box::new(left),
+                op: LogicalOp::And,
+                right: Box::new(right),
             };
+        }
+        Ok(left)
+    }
 
-            conditions.push(Condition {
-                column,
-                operator,
-                value,
+    fn parse_comparison(&mut self) -> Result<Expr, String> {
+        let left = self.parse_additive()?;
+
+        // expr IS [NOT] NULL
+        if *self.peek() == Token::Is {
+            self.advance();
+            let negated = if *self.peek() == Token::Not {
+                self.advance();
+                true
+            } else {
+                false
+            };
+            self.expect(Token::Null)?;
+            return Ok(Expr::IsNull {
+                expr: Box::new(left),
+                negated,
             });
+        }
 
-            match self.peek() {
-                Token::And => {
-                    self.advance();
-                    operators.push(LogicalOp::And);
-                }
-                Token::Or => {
-                    self.advance();
-                    operators.push(LogicalOp::Or);
+        // expr [NOT] IN (expr, ...) / expr [NOT] BETWEEN low AND high /
+        // expr [NOT] LIKE pattern
+        let negated = if *self.peek() == Token::Not {
+            self.advance();
+            true
+        } else {
+            false
+        };
+        if *self.peek() == Token::Between {
+            self.advance();
+            let low = self.parse_additive()?;
+            self.expect(Token::And)?;
+            let high = self.parse_additive()?;
+            return Ok(Expr::Between {
+                expr: Box::new(left),
+                low: Box::new(low),
+                high: Box::new(high),
+                negated,
+            });
+        }
+        if *self.peek() == Token::Like {
+            self.advance();
+            let pattern = self.parse_additive()?;
+            return Ok(Expr::Like {
+                expr: Box::new(left),
+                pattern: Box::new(pattern),
+                negated,
+            });
+        }
+        if negated || *self.peek() == Token::In {
+            self.expect(Token::In)?;
+            if self.at_subquery() {
+                return Ok(Expr::InSubquery {
+                    expr: Box::new(left),
+                    query: self.parse_subquery()?,
+                    negated,
+                });
+            }
+            self.expect(Token::LeftParen)?;
+            let mut list = Vec::new();
+            loop {
+                list.push(self.parse_expr()?);
+                match self.advance() {
+                    Token::Comma => {}
+                    Token::RightParen => break,
+                    other => return Err(format!("Expected ',' or ')', got {:?}", other)),
                 }
-                _ => break,
             }
+            return Ok(Expr::InList {
+                expr: Box::new(left),
+                list,
+                negated,
+            });
+        }
+
+        let op = match self.peek() {
+            Token::Equals => CompareOp::Equals,
+            Token::NotEquals => CompareOp::NotEquals,
+            Token::LessThan => CompareOp::LessThan,
+            Token::GreaterThan => CompareOp::GreaterThan,
+            Token::LessEquals => CompareOp::LessEquals,
+            Token::GreaterEquals => CompareOp::GreaterEquals,
+            _ => return Ok(left),
+        };
+        self.advance();
+
+        let right = self.parse_additive()?;
+        Ok(Expr::Compare {
+            left: Box::new(left),
+            op,
+            right: Box::new(right),
+        })
+    }
+
+    /// Parse `+` and `-`, which bind looser than `*`, `/` and `%`
+    fn parse_additive(&mut self) -> Result<Expr, String> {
+        let mut left = self.parse_multiplicative()?;
+        loop {
+            let op = match self.peek() {
+                Token::Plus => ArithmeticOp::Add,
+                Token::Minus => ArithmeticOp::Subtract,
+                _ => return Ok(left),
+            };
+            self.advance();
+            let right = self.parse_multiplicative()?;
+            left = Expr::Arithmetic {
+                left: Box::new(left),
+                op,
+                right: Box::new(right),
+            };
+        }
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Expr, String> {
+        let mut left = self.parse_unary()?;
+        loop {
+            let op = match self.peek() {
+                Token::Asterisk => ArithmeticOp::Multiply,
+                Token::Slash => ArithmeticOp::Divide,
+                Token::Percent => ArithmeticOp::Remainder,
+                _ => return Ok(left),
+            };
+            self.advance();
+            let right = self.parse_unary()?;
+            left = Expr::Arithmetic {
+                left: Box::new(left),
+                op,
+                right: Box::new(right),
+            };
+        }
+    }
+
+    /// Parse a primary expression with any leading signs
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Token::Minus => {
+                self.advance();
+                Ok(Expr::Negate(Box::new(self.parse_unary()?)))
+            }
+            Token::Plus => {
+                self.advance();
+                self.parse_unary()
+            }
+            _ => self.parse_primary(),
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        match self.peek().clone() {
+            Token::Number(n) => {
+                self.advance();
+                Ok(Expr::Literal(Value::Integer(n)))
+            }
+            Token::StringLiteral(s) => {
+                self.advance();
+                Ok(Expr::Literal(Value::Text(s)))
+            }
+            Token::Null => {
+                self.advance();
+                Ok(Expr::Literal(Value::Null))
+            }
+            Token::Case => self.parse_case(),
+            Token::Identifier(name) if self.tokens.get(self.pos + 1) == Some(&Token::LeftParen) => {
+                self.parse_function(&name)
+            }
+            Token::Identifier(_) => Ok(Expr::Column(self.parse_column_ref()?)),
+            Token::LeftParen if self.at_subquery() => Ok(Expr::Subquery(self.parse_subquery()?)),
+            Token::Exists => {
+                self.advance();
+                Ok(Expr::Exists {
+                    query: self.parse_subquery()?,
+                    negated: false,
+                })
+            }
+            Token::Not if self.tokens.get(self.pos + 1) == Some(&Token::Exists) => {
+                self.advance();
+                self.advance();
+                Ok(Expr::Exists {
+                    query: self.parse_subquery()?,
+                    negated: true,
+                })
+            }
+            Token::LeftParen => {
+                self.advance();
+                let expr = self.parse_expr()?;
+                self.expect(Token::RightParen)?;
+                Ok(expr)
+            }
+            other => Err(format!("Expected expression, got {:?}", other)),
+        }
+    }
+
+    /// Parse a function call: an aggregate `FUNC(expr)` or `COUNT(*)`,
+    /// either of which may be followed by OVER (...), or a window-only
+    /// function such as ROW_NUMBER(), which must be
+    fn parse_function(&mut self, name: &str) -> Result<Expr, String> {
+        let func = match WindowFunc::from_name(name) {
+            Some(func) => func,
+            None => return self.parse_scalar_function(name),
+        };
+        self.advance(); // consume name
+        self.expect(Token::LeftParen)?;
+
+        let count_all =
+            func == WindowFunc::Aggregate(AggregateFunc::Count) && *self.peek() == Token::Asterisk;
+        let mut args = if count_all {
+            self.advance();
+            Vec::new()
+        } else if *self.peek() == Token::RightParen {
+            Vec::new()
+        } else {
+            self.parse_expr_list()?
+        };
+        self.expect(Token::RightParen)?;
+
+        let arity = match func {
+            WindowFunc::RowNumber | WindowFunc::Rank | WindowFunc::DenseRank => 0..=0,
+            // LAG/LEAD(expr [, offset [, default]])
+            WindowFunc::Lag | WindowFunc::Lead => 1..=3,
+            WindowFunc::Aggregate(_) if count_all => 0..=0,
+            WindowFunc::Aggregate(_) => 1..=1,
+        };
+        if !arity.contains(&args.len()) {
+            return Err(format!(
+                "Wrong number of arguments to function {}()",
+                func.name()
+            ));
+        }
+
+        if *self.peek() == Token::Over {
+            let over = self.parse_over()?;
+            return Ok(Expr::Window { func, args, over });
+        }
+        match func {
+            WindowFunc::Aggregate(func) => Ok(Expr::Aggregate {
+                func,
+                arg: args.pop().map(Box::new),
+            }),
+            _ => Err(format!("{}() requires an OVER clause", func.name())),
+        }
+    }
+
+    /// Parse a call of a scalar function. The conditional functions have
+    /// their number of arguments checked here; any other function is
+    /// looked up in the executor's registry when the statement is planned.
+    fn parse_scalar_function(&mut self, name: &str) -> Result<Expr, String> {
+        let name = name.to_uppercase();
+        let arity = match name.as_str() {
+            "COALESCE" => 2..=usize::MAX,
+            "NULLIF" | "IFNULL" => 2..=2,
+            "IIF" => 3..=3,
+            _ => 0..=usize::MAX,
+        };
+        self.advance(); // consume name
+        self.expect(Token::LeftParen)?;
+        let args = if *self.peek() == Token::RightParen {
+            Vec::new()
+        } else {
+            self.parse_expr_list()?
+        };
+        self.expect(Token::RightParen)?;
+
+        if !arity.contains(&args.len()) {
+            return Err(format!("Wrong number of arguments to function {}()", name));
         }
+        Ok(Expr::Function { name, args })
+    }
+
+    /// Parse `CASE [operand] WHEN expr THEN expr ... [ELSE expr] END`
+    fn parse_case(&mut self) -> Result<Expr, String> {
+        self.expect(Token::Case)?;
+        let operand = match self.peek() {
+            Token::When => None,
+            _ => Some(Box::new(self.parse_expr()?)),
+        };
 
-        Ok(WhereClause {
-            conditions,
-            operators,
+        let mut branches = Vec::new();
+        while *self.peek() == Token::When {
+            self.advance();
+            let when = self.parse_expr()?;
+            self.expect(Token::Then)?;
+            branches.push((when, self.parse_expr()?));
+        }
+        if branches.is_empty() {
+            return Err(format!("Expected WHEN, got {:?}", self.peek()));
+        }
+
+        let else_result = if *self.peek() == Token::Else {
+            self.advance();
+            Some(Box::new(self.parse_expr()?))
+        } else {
+            None
+        };
+        self.expect(Token::End)?;
+
+        Ok(Expr::Case {
+            operand,
+            branches,
+            else_result,
         })
     }
+
+    /// Parse `OVER ([PARTITION BY expr, ...] [ORDER BY ...] [ROWS frame])`
+    fn parse_over(&mut self) -> Result<WindowSpec, String> {
+        self.expect(Token::Over)?;
+        self.expect(Token::LeftParen)?;
+
+        let partition_by = if *self.peek() == Token::Partition {
+            self.advance();
+            self.expect(Token::By)?;
+            self.parse_expr_list()?
+        } else {
+            Vec::new()
+        };
+        let order_by = if *self.peek() == Token::Order {
+            self.parse_order_by()?
+        } else {
+            Vec::new()
+        };
+        let frame = if self.at_word("rows") {
+            self.advance();
+            Some(self.parse_frame()?)
+        } else {
+            None
+        };
+        self.expect(Token::RightParen)?;
+
+        Ok(WindowSpec {
+            partition_by,
+            order_by,
+            frame,
+        })
+    }
+
+    /// Parse `BETWEEN start AND end`, or a lone `start`, which ends the
+    /// frame at the current row. The frame words are not reserved.
+    fn parse_frame(&mut self) -> Result<WindowFrame, String> {
+        let (start, end) = if *self.peek() == Token::Between {
+            self.advance();
+            let start = self.parse_frame_bound()?;
+            self.expect(Token::And)?;
+            (start, self.parse_frame_bound()?)
+        } else {
+            (self.parse_frame_bound()?, FrameBound::CurrentRow)
+        };
+
+        if start == FrameBound::UnboundedFollowing {
+            return Err("Frame start cannot be UNBOUNDED FOLLOWING".to_string());
+        }
+        if end == FrameBound::UnboundedPreceding {
+            return Err("Frame end cannot be UNBOUNDED PRECEDING".to_string());
+        }
+        Ok(WindowFrame { start, end })
+    }
+
+    /// Parse `UNBOUNDED PRECEDING`, `n PRECEDING`, `CURRENT ROW`,
+    /// `n FOLLOWING` or `UNBOUNDED FOLLOWING`
+    fn parse_frame_bound(&mut self) -> Result<FrameBound, String> {
+        if self.at_word("current") {
+            self.advance();
+            self.expect_word("row")?;
+            return Ok(FrameBound::CurrentRow);
+        }
+        let rows = match self.advance() {
+            Token::Identifier(word) if word.eq_ignore_ascii_case("unbounded") => None,
+            Token::Number(n) => Some(n as usize),
+            other => return Err(format!("Expected frame bound, got {:?}", other)),
+        };
+        let preceding = if self.at_word("preceding") {
+            true
+        } else if self.at_word("following") {
+            false
+        } else {
+            return Err(format!(
+                "Expected PRECEDING or FOLLOWING, got {:?}",
+                self.peek()
+            ));
+        };
+        self.advance();
+
+        Ok(match (rows, preceding) {
+            (None, true) => FrameBound::UnboundedPreceding,
+            (None, false) => FrameBound::UnboundedFollowing,
+            (Some(n), true) => FrameBound::Preceding(n),
+            (Some(n), false) => FrameBound::Following(n),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::Tokenizer;
+
+    fn parse(sql: &str) -> Result<Statement, String> {
+        Parser::new(Tokenizer::new(sql).tokenize()).parse()
+    }
+
+    #[test]
+    fn insert_values_accept_null() {
+        let Ok(Statement::Insert(insert)) = parse("INSERT INTO t VALUES (1, NULL, 'x', null)")
+        else {
+            panic!("expected an INSERT");
+        };
+        assert_eq!(
+            insert.values,
+            vec![
+                Value::Integer(1),
+                Value::Null,
+                Value::Text("x".to_string()),
+                Value::Null,
+            ]
+        );
+    }
 }
@@ -20,13 +20,49 @@ pub enum Token {
     Join,
     On,
     Inner,
+    Left,
+    Right,
+    Full,
+    Outer,
+    Cross,
+    Is,
+    Not,
+    Null,
+    In,
+    Between,
+    Like,
+    Order,
+    By,
+    Asc,
+    Desc,
+    Limit,
+    Offset,
+    Group,
+    Having,
     Begin,
     Commit,
     Rollback,
+    Analyze,
+    Explain,
+    Exists,
+    With,
+    Recursive,
+    Union,
+    All,
+    Intersect,
+    Except,
+    Over,
+    Partition,
+    Case,
+    When,
+    Then,
+    Else,
+    End,
     Index,
     Unique,
     Database,
     Connect,
+    As,
 
     // Data types
     Integer,
@@ -44,6 +80,10 @@ pub enum Token {
     GreaterThan,   // >
     LessEquals,    // <=
     GreaterEquals, // >=
+    Plus,          // +
+    Minus,         // -
+    Slash,         // /
+    Percent,       // %
 
     // Punctuation
     Comma,
@@ -51,6 +91,7 @@ pub enum Token {
     LeftParen,
     RightParen,
     Asterisk,
+    Dot,
 
     // End of input
     Eof,
@@ -155,6 +196,26 @@ impl Tokenizer {
                     self.advance();
                     Token::Asterisk
                 }
+                '.' => {
+                    self.advance();
+                    Token::Dot
+                }
+                '+' => {
+                    self.advance();
+                    Token::Plus
+                }
+                '-' => {
+                    self.advance();
+                    Token::Minus
+                }
+                '/' => {
+                    self.advance();
+                    Token::Slash
+                }
+                '%' => {
+                    self.advance();
+                    Token::Percent
+                }
                 '=' => {
                     self.advance();
                     Token::Equals
@@ -213,13 +274,49 @@ impl Tokenizer {
                         "JOIN" => Token::Join,
                         "ON" => Token::On,
                         "INNER" => Token::Inner,
+                        "LEFT" => Token::Left,
+                        "RIGHT" => Token::Right,
+                        "FULL" => Token::Full,
+                        "OUTER" => Token::Outer,
+                        "CROSS" => Token::Cross,
+                        "IS" => Token::Is,
+                        "NOT" => Token::Not,
+                        "NULL" => Token::Null,
+                        "IN" => Token::In,
+                        "BETWEEN" => Token::Between,
+                        "LIKE" => Token::Like,
+                        "ORDER" => Token::Order,
+                        "BY" => Token::By,
+                        "ASC" => Token::Asc,
+                        "DESC" => Token::Desc,
+                        "LIMIT" => Token::Limit,
+                        "OFFSET" => Token::Offset,
+                        "GROUP" => Token::Group,
+                        "HAVING" => Token::Having,
                         "BEGIN" => Token::Begin,
                         "COMMIT" => Token::Commit,
                         "ROLLBACK" => Token::Rollback,
+                        "ANALYZE" => Token::Analyze,
+                        "EXPLAIN" => Token::Explain,
+                        "EXISTS" => Token::Exists,
+                        "WITH" => Token::With,
+                        "RECURSIVE" => Token::Recursive,
+                        "UNION" => Token::Union,
+                        "ALL" => Token::All,
+                        "INTERSECT" => Token::Intersect,
+                        "EXCEPT" => Token::Except,
+                        "OVER" => Token::Over,
+                        "PARTITION" => Token::Partition,
+                        "CASE" => Token::Case,
+                        "WHEN" => Token::When,
+                        "THEN" => Token::Then,
+                        "ELSE" => Token::Else,
+                        "END" => Token::End,
                         "INDEX" => Token::Index,
                         "UNIQUE" => Token::Unique,
                         "DATABASE" => Token::Database,
                         "CONNECT" => Token::Connect,
+                        "AS" => Token::As,
                         "INTEGER" | "INT" => Token::Integer,
                         "TEXT" | "VARCHAR" => Token::Text,
                         _ => Token::Identifier(ident),
@@ -0,0 +1,227 @@
+//! Runtime SQL values produced while evaluating queries
+
+use crate::parser::{ArithmeticOp, Value};
+use std::cmp::Ordering;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+impl SqlValue {
+    /// Compare two values. NULL is not comparable with anything. Numbers
+    /// compare by value and text with text byte by byte; text compared with
+    /// a number is read as one if it spells one, and otherwise sorts after it.
+    pub fn compare(&self, other: &SqlValue) -> Option<Ordering> {
+        match (self, other) {
+            (SqlValue::Null, _) | (_, SqlValue::Null) => None,
+            (SqlValue::Integer(a), SqlValue::Integer(b)) => Some(a.cmp(b)),
+            (SqlValue::Text(a), SqlValue::Text(b)) => Some(a.cmp(b)),
+            (SqlValue::Text(s), number) => match parse_number(s) {
+                Some(value) => value.compare(number),
+                None => Some(Ordering::Greater),
+            },
+            (number, SqlValue::Text(s)) => match parse_number(s) {
+                Some(value) => number.compare(&value),
+                None => Some(Ordering::Less),
+            },
+            (a, b) => a.as_real()?.partial_cmp(&b.as_real()?),
+        }
+    }
+
+    /// Total order used to sort values and to keep them in an index: NULL
+    /// first, then numbers by value, then text byte by byte
+    pub fn sort_order(&self, other: &SqlValue) -> Ordering {
+        let rank = |value: &SqlValue| match value {
+            SqlValue::Null => 0,
+            SqlValue::Integer(_) | SqlValue::Real(_) => 1,
+            SqlValue::Text(_) => 2,
+        };
+        match (self, other) {
+            (SqlValue::Integer(a), SqlValue::Integer(b)) => a.cmp(b),
+            (SqlValue::Text(a), SqlValue::Text(b)) => a.cmp(b),
+            (a, b) if rank(a) != rank(b) => rank(a).cmp(&rank(b)),
+            (SqlValue::Null, _) => Ordering::Equal,
+            (a, b) => {
+                let real = |value: &SqlValue| value.as_real().unwrap_or(0.0);
+                real(a).total_cmp(&real(b))
+            }
+        }
+    }
+
+    /// The value as an integer, if it is one or is text spelling one
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            SqlValue::Integer(n) => Some(*n),
+            SqlValue::Text(s) => s.parse().ok(),
+            SqlValue::Real(_) | SqlValue::Null => None,
+        }
+    }
+
+    /// The value as a floating point number, if it is numeric
+    pub fn as_real(&self) -> Option<f64> {
+        match self {
+            SqlValue::Integer(n) => Some(*n as f64),
+            SqlValue::Real(r) => Some(*r),
+            SqlValue::Text(s) => s.parse().ok(),
+            SqlValue::Null => None,
+        }
+    }
+
+    /// Apply an arithmetic operator. NULL in gives NULL out, and so does
+    /// dividing by zero; two integers give an integer, otherwise a real.
+    pub fn arithmetic(&self, op: ArithmeticOp, other: &SqlValue) -> Result<SqlValue, String> {
+        let symbol = op.to_string();
+        match (self.numeric(&symbol)?, other.numeric(&symbol)?) {
+            (SqlValue::Null, _) | (_, SqlValue::Null) => Ok(SqlValue::Null),
+            (SqlValue::Integer(a), SqlValue::Integer(b)) => {
+                let result = match op {
+                    ArithmeticOp::Divide | ArithmeticOp::Remainder if b == 0 => {
+                        return Ok(SqlValue::Null)
+                    }
+                    ArithmeticOp::Add => a.checked_add(b),
+                    ArithmeticOp::Subtract => a.checked_sub(b),
+                    ArithmeticOp::Multiply => a.checked_mul(b),
+                    ArithmeticOp::Divide => a.checked_div(b),
+                    ArithmeticOp::Remainder => a.checked_rem(b),
+                };
+                result
+                    .map(SqlValue::Integer)
+                    .ok_or_else(|| "Integer overflow".to_string())
+            }
+            (a, b) => {
+                let (a, b) = (a.as_real().unwrap_or(0.0), b.as_real().unwrap_or(0.0));
+                Ok(match op {
+                    ArithmeticOp::Divide | ArithmeticOp::Remainder if b == 0.0 => SqlValue::Null,
+                    ArithmeticOp::Add => SqlValue::Real(a + b),
+                    ArithmeticOp::Subtract => SqlValue::Real(a - b),
+                    ArithmeticOp::Multiply => SqlValue::Real(a * b),
+                    ArithmeticOp::Divide => SqlValue::Real(a / b),
+                    ArithmeticOp::Remainder => SqlValue::Real(a % b),
+                })
+            }
+        }
+    }
+
+    /// The value with its sign flipped; NULL stays NULL
+    pub fn negate(&self) -> Result<SqlValue, String> {
+        match self.numeric("-")? {
+            SqlValue::Integer(n) => n
+                .checked_neg()
+                .map(SqlValue::Integer)
+                .ok_or_else(|| "Integer overflow".to_string()),
+            SqlValue::Real(r) => Ok(SqlValue::Real(-r)),
+            other => Ok(other),
+        }
+    }
+
+    /// The value as an operand of `operator`: numbers as they are, text
+    /// read as a number. Text that isn't one is an error.
+    fn numeric(&self, operator: &str) -> Result<SqlValue, String> {
+        match self {
+            SqlValue::Text(s) => match (s.parse::<i64>(), s.parse::<f64>()) {
+                (Ok(n), _) => Ok(SqlValue::Integer(n)),
+                (_, Ok(r)) => Ok(SqlValue::Real(r)),
+                _ => Err(format!(
+                    "Cannot apply {} to non-numeric value '{}'",
+                    operator, s
+                )),
+            },
+            value => Ok(value.clone()),
+        }
+    }
+
+    /// Key under which values group together: numbers by value, text as
+    /// it is spelled. NULL never equals anything, so it has no key.
+    pub fn hash_key(&self) -> Option<String> {
+        match self {
+            SqlValue::Null => None,
+            SqlValue::Text(s) => Some(s.clone()),
+            number => number_key(number),
+        }
+    }
+
+    /// Key a hash join buckets values by. Text that spells a number joins
+    /// with that number, so it takes the number's key; rows sharing a key
+    /// are still checked against the join condition.
+    pub fn join_key(&self) -> Option<String> {
+        match self {
+            SqlValue::Text(s) => parse_number(s)
+                .and_then(|number| number_key(&number))
+                .or_else(|| Some(s.clone())),
+            value => value.hash_key(),
+        }
+    }
+
+    /// The B-Tree key this value addresses, if it is a valid primary key
+    pub fn to_key(&self) -> Option<u32> {
+        match self {
+            SqlValue::Integer(n) => u32::try_from(*n).ok(),
+            SqlValue::Real(r) if r.fract() == 0.0 => u32::try_from(*r as i64).ok(),
+            SqlValue::Text(s) => parse_number(s)?.to_key(),
+            SqlValue::Real(_) | SqlValue::Null => None,
+        }
+    }
+
+    /// Truthiness used by WHERE and ON: non-zero integers and non-empty text
+    pub fn is_true(&self) -> bool {
+        match self {
+            SqlValue::Null => false,
+            SqlValue::Integer(n) => *n != 0,
+            SqlValue::Real(r) => *r != 0.0,
+            SqlValue::Text(s) => !s.is_empty(),
+        }
+    }
+}
+
+/// The number text spells, if it spells one: an integer when it can be
+pub fn parse_number(s: &str) -> Option<SqlValue> {
+    match s.parse::<i64>() {
+        Ok(n) => Some(SqlValue::Integer(n)),
+        Err(_) => s
+            .parse::<f64>()
+            .ok()
+            .filter(|r| !r.is_nan())
+            .map(SqlValue::Real),
+    }
+}
+
+/// One spelling for each number, whether integer or real
+fn number_key(value: &SqlValue) -> Option<String> {
+    match value {
+        SqlValue::Integer(n) => Some(n.to_string()),
+        SqlValue::Real(r) if r.fract() == 0.0 && r.abs() < i64::MAX as f64 => {
+            Some((*r as i64).to_string())
+        }
+        SqlValue::Real(r) => Some(r.to_string()),
+        _ => None,
+    }
+}
+
+impl From<&Value> for SqlValue {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Integer(n) => SqlValue::Integer(*n),
+            Value::Text(s) => SqlValue::Text(s.clone()),
+            Value::Identifier(s) => SqlValue::Text(s.clone()),
+            Value::Null => SqlValue::Null,
+        }
+    }
+}
+
+impl fmt::Display for SqlValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SqlValue::Null => write!(f, "NULL"),
+            SqlValue::Integer(n) => write!(f, "{}", n),
+            // Whole reals keep a decimal point so they read as reals: 2.0
+            SqlValue::Real(r) if r.fract() == 0.0 => write!(f, "{:.1}", r),
+            SqlValue::Real(r) => write!(f, "{}", r),
+            SqlValue::Text(s) => write!(f, "{}", s),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! User-defined functions registered by an embedding application
+
+use rsql::executor::{ExecuteResult, Executor};
+use rsql::parser::Parser;
+use rsql::tokenizer::Tokenizer;
+use rsql::value::SqlValue;
+
+fn run(executor: &mut Executor, sql: &str) -> Result<ExecuteResult, String> {
+    let tokens = Tokenizer::new(sql).tokenize();
+    let statement = Parser::new(tokens).parse()?;
+    executor.execute(statement)
+}
+
+fn rows(executor: &mut Executor, sql: &str) -> Vec<Vec<String>> {
+    match run(executor, sql).unwrap() {
+        ExecuteResult::Rows { rows, .. } => rows,
+        other => panic!("expected rows, got {:?}", other),
+    }
+}
+
+fn executor(name: &str) -> Executor {
+    let dir = std::env::temp_dir().join(format!("rsql-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut executor = Executor::new();
+    executor.db_base_path = dir;
+    run(&mut executor, "CREATE DATABASE test").unwrap();
+    run(&mut executor, "CONNECT test").unwrap();
+    run(
+        &mut executor,
+        "CREATE TABLE staff (id INTEGER, name TEXT, dept TEXT)",
+    )
+    .unwrap();
+    for (id, name, dept) in [(1, "ann", "ops"), (2, "bob", "dev"), (3, "cy", "ops")] {
+        let sql = format!("INSERT INTO staff VALUES ({}, '{}', '{}')", id, name, dept);
+        run(&mut executor, &sql).unwrap();
+    }
+    executor
+}
+
+#[test]
+fn scalar_and_aggregate_functions_are_called_from_sql() {
+    let mut executor = executor("udf");
+    executor
+        .register_scalar_function("double", Some(1), |args| {
+            Ok(SqlValue::Integer(args[0].as_integer().unwrap_or(0) * 2))
+        })
+        .unwrap();
+    executor
+        .register_aggregate_function::<Vec<String>>(
+            "list",
+            Some(1),
+            |names, args| {
+                names.push(args[0].to_string());
+                Ok(())
+            },
+            |names| Ok(SqlValue::Text(names.join(", "))),
+        )
+        .unwrap();
+
+    assert_eq!(
+        rows(
+            &mut executor,
+            "SELECT id, double(id) FROM staff WHERE id = 2"
+        ),
+        vec![vec!["2".to_string(), "4".to_string()]]
+    );
+    assert_eq!(
+        rows(
+            &mut executor,
+            "SELECT dept, list(name), double(COUNT(*)) FROM staff GROUP BY dept ORDER BY dept"
+        ),
+        vec![
+            vec!["dev".to_string(), "bob".to_string(), "2".to_string()],
+            vec!["ops".to_string(), "ann, cy".to_string(), "4".to_string()],
+        ]
+    );
+    let _ = std::fs::remove_dir_all(&executor.db_base_path);
+}
+
+#[test]
+fn registered_functions_are_checked_when_planned() {
+    let mut executor = executor("udf-arity");
+    executor
+        .register_scalar_function("double", Some(1), |args| {
+            Ok(SqlValue::Integer(args[0].as_integer().unwrap_or(0) * 2))
+        })
+        .unwrap();
+
+    assert!(run(&mut executor, "SELECT double(id, id) FROM staff").is_err());
+    assert!(executor
+        .register_scalar_function("count", Some(1), |_| Ok(SqlValue::Null))
+        .is_err());
+    let _ = std::fs::remove_dir_all(&executor.db_base_path);
+}
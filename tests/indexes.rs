@@ -0,0 +1,196 @@
+//! Queries answered through secondary indexes return the same rows as
+//! the same queries answered by scanning
+
+use rsql::executor::{ExecuteResult, Executor};
+use rsql::parser::Parser;
+use rsql::tokenizer::Tokenizer;
+
+fn run(executor: &mut Executor, sql: &str) -> Result<ExecuteResult, String> {
+    let tokens = Tokenizer::new(sql).tokenize();
+    let statement = Parser::new(tokens).parse()?;
+    executor.execute(statement)
+}
+
+fn rows(executor: &mut Executor, sql: &str) -> Vec<Vec<String>> {
+    match run(executor, sql).unwrap() {
+        ExecuteResult::Rows { rows, .. } => rows,
+        other => panic!("expected rows, got {:?}", other),
+    }
+}
+
+fn ids(executor: &mut Executor, sql: &str) -> Vec<String> {
+    rows(executor, sql)
+        .into_iter()
+        .map(|row| row[0].clone())
+        .collect()
+}
+
+fn executor(name: &str) -> Executor {
+    let dir = std::env::temp_dir().join(format!("rsql-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut executor = Executor::new();
+    executor.db_base_path = dir;
+    run(&mut executor, "CREATE DATABASE test").unwrap();
+    run(&mut executor, "CONNECT test").unwrap();
+    run(
+        &mut executor,
+        "CREATE TABLE items (id INTEGER, code TEXT(12), qty INTEGER)",
+    )
+    .unwrap();
+    let codes = [
+        "1", "01", "1.0", "abc", "Abd", "inf", "Infinity", "1000", "1e3", "2", "-5", "",
+    ];
+    for (i, code) in codes.iter().enumerate() {
+        let sql = format!(
+            "INSERT INTO items VALUES ({}, '{}', {})",
+            i + 1,
+            code,
+            i * 10
+        );
+        run(&mut executor, &sql).unwrap();
+    }
+    run(&mut executor, "CREATE TABLE refs (id INTEGER, n INTEGER)").unwrap();
+    for (id, n) in [(1, 1), (2, 2), (3, 1000), (4, 7)] {
+        let sql = format!("INSERT INTO refs VALUES ({}, {})", id, n);
+        run(&mut executor, &sql).unwrap();
+    }
+    executor
+}
+
+const QUERIES: &[&str] = &[
+    "SELECT id FROM items WHERE code = '1'",
+    "SELECT id FROM items WHERE code = 1",
+    "SELECT id FROM items WHERE code = 1000",
+    "SELECT id FROM items WHERE code = '1.0'",
+    "SELECT id FROM items WHERE code IN (2, 'abc', -5)",
+    "SELECT id FROM items WHERE code > 5",
+    "SELECT id FROM items WHERE code < 100",
+    "SELECT id FROM items WHERE code >= '1' AND code < '2'",
+    "SELECT id FROM items WHERE code BETWEEN 1 AND 2",
+    "SELECT id FROM items WHERE code > 'a'",
+    "SELECT id FROM items WHERE code LIKE '1%'",
+    "SELECT id FROM items WHERE code LIKE 'A%'",
+    "SELECT id FROM items WHERE code LIKE 'i%'",
+    "SELECT id FROM items WHERE qty = '30'",
+    "SELECT id FROM items WHERE qty > '20'",
+    "SELECT id FROM items WHERE qty >= 10 AND qty < 40",
+    "SELECT id FROM items WHERE qty < 'abc'",
+    "SELECT items.id, refs.id FROM items JOIN refs ON items.code = refs.n",
+    "SELECT refs.id, items.id FROM refs JOIN items ON refs.n = items.code",
+    "SELECT refs.id, items.id FROM refs JOIN items ON refs.n = items.qty",
+];
+
+/// Every query's rows, sorted so that only which rows come back matters
+fn answers(executor: &mut Executor) -> Vec<Vec<Vec<String>>> {
+    QUERIES
+        .iter()
+        .map(|sql| {
+            let mut rows = rows(executor, sql);
+            rows.sort();
+            rows
+        })
+        .collect()
+}
+
+#[test]
+fn lookups_match_with_and_without_an_index() {
+    let mut executor = executor("index-lookups");
+    let scanned = answers(&mut executor);
+    run(&mut executor, "CREATE INDEX idx_code ON items (code)").unwrap();
+    run(&mut executor, "CREATE INDEX idx_qty ON items (qty)").unwrap();
+    // With statistics, joins into items probe its indexes
+    run(&mut executor, "ANALYZE").unwrap();
+    let indexed = answers(&mut executor);
+
+    for ((sql, scanned), indexed) in QUERIES.iter().zip(&scanned).zip(&indexed) {
+        assert_eq!(scanned, indexed, "{}", sql);
+    }
+    // Text equals text spelled the same way, and a number it spells
+    assert_eq!(ids(&mut executor, QUERIES[0]), vec!["1"]);
+    assert_eq!(ids(&mut executor, QUERIES[1]), vec!["1", "2", "3"]);
+    assert_eq!(ids(&mut executor, QUERIES[2]), vec!["8", "9"]);
+    assert_eq!(ids(&mut executor, QUERIES[10]), vec!["1", "3", "8", "9"]);
+    let _ = std::fs::remove_dir_all(&executor.db_base_path);
+}
+
+#[test]
+fn ordered_scans_match_a_sort() {
+    let mut executor = executor("index-order");
+    let queries = [
+        "SELECT id FROM items ORDER BY code",
+        "SELECT id FROM items ORDER BY code DESC LIMIT 4",
+        "SELECT id FROM items WHERE code > 'A' ORDER BY code DESC",
+        "SELECT id FROM items WHERE qty BETWEEN 0 AND 50 ORDER BY qty",
+    ];
+    let sorted: Vec<Vec<String>> = queries.iter().map(|sql| ids(&mut executor, sql)).collect();
+    run(&mut executor, "CREATE INDEX idx_code ON items (code)").unwrap();
+    run(&mut executor, "CREATE INDEX idx_qty ON items (qty)").unwrap();
+
+    for (sql, sorted) in queries.iter().zip(&sorted) {
+        assert_eq!(&ids(&mut executor, sql), sorted, "{}", sql);
+    }
+
+    // The table itself is walked backwards for a descending primary key
+    assert_eq!(
+        ids(
+            &mut executor,
+            "SELECT id FROM items WHERE id >= 3 AND id < 7 ORDER BY id DESC"
+        ),
+        vec!["6", "5", "4", "3"]
+    );
+    assert_eq!(
+        ids(
+            &mut executor,
+            "SELECT id FROM items ORDER BY id DESC LIMIT 2"
+        ),
+        vec!["12", "11"]
+    );
+    let _ = std::fs::remove_dir_all(&executor.db_base_path);
+}
+
+#[test]
+fn a_missing_index_file_is_rebuilt_on_connect() {
+    let mut executor = executor("index-missing");
+    run(&mut executor, "CREATE INDEX idx_code ON items (code)").unwrap();
+    let expected = ids(&mut executor, "SELECT id FROM items WHERE code = 1");
+    let dir = executor.db_base_path.clone();
+    drop(executor);
+
+    std::fs::remove_file(dir.join("test").join("items_idx_code.idx")).unwrap();
+    let mut executor = Executor::new();
+    executor.db_base_path = dir;
+    run(&mut executor, "CONNECT test").unwrap();
+    assert_eq!(
+        ids(&mut executor, "SELECT id FROM items WHERE code = 1"),
+        expected
+    );
+    let _ = std::fs::remove_dir_all(&executor.db_base_path);
+}
+
+#[test]
+fn equality_ranges_and_prefixes_are_read_from_the_index() {
+    let mut executor = executor("index-paths");
+    run(&mut executor, "CREATE INDEX idx_code ON items (code)").unwrap();
+    run(&mut executor, "CREATE INDEX idx_qty ON items (qty)").unwrap();
+    for (sql, expected) in [
+        ("SELECT id FROM items WHERE code = 'abc'", vec!["4"]),
+        (
+            "SELECT id FROM items WHERE qty BETWEEN 20 AND 40",
+            vec!["3", "4", "5"],
+        ),
+        ("SELECT id FROM items WHERE qty > 90", vec!["11", "12"]),
+        ("SELECT id FROM items WHERE code LIKE 'A%'", vec!["5"]),
+    ] {
+        let plan = rows(&mut executor, &format!("EXPLAIN {}", sql));
+        assert!(
+            plan.iter().any(|row| row[1].contains("using index")),
+            "{}: {:?}",
+            sql,
+            plan
+        );
+        let mut found = ids(&mut executor, sql);
+        found.sort_by_key(|id| id.parse::<u32>().unwrap());
+        assert_eq!(found, expected, "{}", sql);
+    }
+    let _ = std::fs::remove_dir_all(&executor.db_base_path);
+}
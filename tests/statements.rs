@@ -0,0 +1,283 @@
+//! Statements run end to end: INSERT checks, common table expressions and
+//! transactions
+
+use rsql::executor::{ExecuteResult, Executor};
+use rsql::parser::Parser;
+use rsql::tokenizer::Tokenizer;
+
+fn run(executor: &mut Executor, sql: &str) -> Result<ExecuteResult, String> {
+    let tokens = Tokenizer::new(sql).tokenize();
+    let statement = Parser::new(tokens).parse()?;
+    executor.execute(statement)
+}
+
+fn rows(executor: &mut Executor, sql: &str) -> Vec<Vec<String>> {
+    match run(executor, sql).unwrap() {
+        ExecuteResult::Rows { rows, .. } => rows,
+        other => panic!("expected rows, got {:?}", other),
+    }
+}
+
+fn executor(name: &str) -> Executor {
+    let dir = std::env::temp_dir().join(format!("rsql-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut executor = Executor::new();
+    executor.db_base_path = dir;
+    run(&mut executor, "CREATE DATABASE test").unwrap();
+    run(&mut executor, "CONNECT test").unwrap();
+    executor
+}
+
+#[test]
+fn insert_checks_values_like_update() {
+    let mut executor = executor("insert-checks");
+    run(
+        &mut executor,
+        "CREATE TABLE src (id INTEGER, n INTEGER, s TEXT(10))",
+    )
+    .unwrap();
+    run(
+        &mut executor,
+        "CREATE TABLE t (id INTEGER, n INTEGER, s TEXT(3))",
+    )
+    .unwrap();
+    run(&mut executor, "INSERT INTO src VALUES (1, 5, 'abcdef')").unwrap();
+    run(&mut executor, "INSERT INTO src VALUES (2, 6, 'ab')").unwrap();
+
+    for sql in [
+        // A negative id doesn't wrap around to a huge one
+        "INSERT INTO t SELECT id - 10, n, s FROM src",
+        "INSERT INTO t SELECT id, s, n FROM src",
+        "INSERT INTO t SELECT id, n, s FROM src",
+        "INSERT INTO t SELECT id, n FROM src",
+        "INSERT INTO t (s, n) VALUES ('xy', 7)",
+        "INSERT INTO t (id, id) VALUES (8, 8)",
+        "INSERT INTO t (id, nope) VALUES (8, 7)",
+        "INSERT INTO t VALUES (9, 1)",
+    ] {
+        assert!(run(&mut executor, sql).is_err(), "{}", sql);
+    }
+    // Failed statements leave nothing behind
+    assert!(rows(&mut executor, "SELECT * FROM t").is_empty());
+
+    run(&mut executor, "INSERT INTO t (s, id) VALUES ('xy', 7)").unwrap();
+    run(
+        &mut executor,
+        "INSERT INTO t (n, id) SELECT n, id + 20 FROM src",
+    )
+    .unwrap();
+    assert_eq!(
+        rows(&mut executor, "SELECT id, n FROM t"),
+        vec![
+            vec!["7".to_string(), "NULL".to_string()],
+            vec!["21".to_string(), "5".to_string()],
+            vec!["22".to_string(), "6".to_string()],
+        ]
+    );
+    let _ = std::fs::remove_dir_all(&executor.db_base_path);
+}
+
+#[test]
+fn recursive_cte_shadows_a_table_of_the_same_name() {
+    let mut executor = executor("cte-shadow");
+    run(
+        &mut executor,
+        "CREATE TABLE categories (id INTEGER, name TEXT(10), parent INTEGER)",
+    )
+    .unwrap();
+    for (id, name, parent) in [
+        (1, "root", 0),
+        (2, "a", 1),
+        (3, "b", 1),
+        (4, "c", 2),
+        (5, "d", 9),
+    ] {
+        let sql = format!(
+            "INSERT INTO categories VALUES ({}, '{}', {})",
+            id, name, parent
+        );
+        run(&mut executor, &sql).unwrap();
+    }
+    run(
+        &mut executor,
+        "CREATE TABLE below (id INTEGER, name TEXT(10))",
+    )
+    .unwrap();
+    run(&mut executor, "INSERT INTO below VALUES (1, 'stale')").unwrap();
+
+    let tree = "below (id, name) AS (
+        SELECT id, name FROM categories WHERE id = 1
+        UNION ALL
+        SELECT c.id, c.name FROM categories c JOIN below b ON c.parent = b.id
+    ) SELECT id, name FROM below ORDER BY id";
+    let ids = |rows: Vec<Vec<String>>| -> Vec<String> {
+        rows.into_iter().map(|row| row[0].clone()).collect()
+    };
+
+    // The step reads the rows the CTE added, not the table
+    assert_eq!(
+        ids(rows(&mut executor, &format!("WITH RECURSIVE {}", tree))),
+        vec!["1", "2", "3", "4"]
+    );
+    // Without RECURSIVE the step reads the table
+    assert_eq!(
+        rows(&mut executor, &format!("WITH {}", tree)),
+        vec![
+            vec!["1".to_string(), "root".to_string()],
+            vec!["2".to_string(), "a".to_string()],
+            vec!["3".to_string(), "b".to_string()],
+        ]
+    );
+    // A CTE that doesn't read itself reads the table it shadows
+    assert_eq!(
+        ids(rows(
+            &mut executor,
+            "WITH RECURSIVE below AS (SELECT id, name FROM below) SELECT id FROM below"
+        )),
+        vec!["1"]
+    );
+    let _ = std::fs::remove_dir_all(&executor.db_base_path);
+}
+
+#[test]
+fn null_text_is_not_empty_text() {
+    let mut executor = executor("null-text");
+    run(&mut executor, "CREATE TABLE t (id INTEGER, s TEXT(5))").unwrap();
+    run(&mut executor, "CREATE INDEX idx_s ON t (s)").unwrap();
+    run(&mut executor, "INSERT INTO t VALUES (1, NULL)").unwrap();
+    run(&mut executor, "INSERT INTO t VALUES (2, '')").unwrap();
+    run(&mut executor, "INSERT INTO t VALUES (3, 'abc')").unwrap();
+    run(&mut executor, "UPDATE t SET s = NULL WHERE id = 3").unwrap();
+
+    let ids = |executor: &mut Executor, sql: &str| -> Vec<String> {
+        rows(executor, sql)
+            .into_iter()
+            .map(|row| row[0].clone())
+            .collect()
+    };
+    assert_eq!(
+        ids(&mut executor, "SELECT id FROM t WHERE s IS NULL"),
+        vec!["1", "3"]
+    );
+    assert_eq!(
+        ids(&mut executor, "SELECT id FROM t WHERE s = ''"),
+        vec!["2"]
+    );
+    assert_eq!(
+        rows(&mut executor, "SELECT s FROM t WHERE id = 3"),
+        vec![vec!["NULL".to_string()]]
+    );
+    let _ = std::fs::remove_dir_all(&executor.db_base_path);
+}
+
+#[test]
+fn schema_changes_are_refused_inside_a_transaction() {
+    let mut executor = executor("transaction-ddl");
+    run(&mut executor, "CREATE TABLE t (id INTEGER, s TEXT(5))").unwrap();
+    run(&mut executor, "INSERT INTO t VALUES (1, 'a')").unwrap();
+
+    run(&mut executor, "BEGIN").unwrap();
+    run(&mut executor, "INSERT INTO t VALUES (2, 'b')").unwrap();
+    for sql in [
+        "CREATE INDEX idx_s ON t (s)",
+        "CREATE TABLE u (id INTEGER)",
+        "DROP TABLE t",
+    ] {
+        assert!(run(&mut executor, sql).is_err(), "{}", sql);
+    }
+    run(&mut executor, "ROLLBACK").unwrap();
+
+    // No index was left behind to answer with the rolled-back row
+    assert_eq!(
+        rows(&mut executor, "SELECT id FROM t WHERE s = 'b'"),
+        Vec::<Vec<String>>::new()
+    );
+    run(&mut executor, "CREATE INDEX idx_s ON t (s)").unwrap();
+    assert_eq!(
+        rows(&mut executor, "SELECT id FROM t WHERE s = 'a'"),
+        vec![vec!["1".to_string()]]
+    );
+    let _ = std::fs::remove_dir_all(&executor.db_base_path);
+}
+
+#[test]
+fn recursive_ctes_count_and_walk_a_graph() {
+    let mut executor = executor("cte-recursive");
+    run(&mut executor, "CREATE TABLE one (id INTEGER)").unwrap();
+    run(&mut executor, "INSERT INTO one VALUES (1)").unwrap();
+    run(
+        &mut executor,
+        "CREATE TABLE edges (id INTEGER, src INTEGER, dst INTEGER)",
+    )
+    .unwrap();
+    for (id, src, dst) in [(1, 1, 2), (2, 2, 3), (3, 3, 1), (4, 4, 5)] {
+        let sql = format!("INSERT INTO edges VALUES ({}, {}, {})", id, src, dst);
+        run(&mut executor, &sql).unwrap();
+    }
+
+    assert_eq!(
+        rows(
+            &mut executor,
+            "WITH RECURSIVE n (x) AS (
+                SELECT id FROM one UNION ALL SELECT x + 1 FROM n WHERE x < 10
+            ) SELECT COUNT(*), SUM(x) FROM n"
+        ),
+        vec![vec!["10".to_string(), "55".to_string()]]
+    );
+    // UNION drops rows already found, so a cycle ends the recursion
+    assert_eq!(
+        rows(
+            &mut executor,
+            "WITH RECURSIVE reach (node) AS (
+                SELECT dst FROM edges WHERE src = 1
+                UNION
+                SELECT e.dst FROM edges e JOIN reach r ON e.src = r.node
+            ) SELECT node FROM reach ORDER BY node"
+        ),
+        vec![
+            vec!["1".to_string()],
+            vec!["2".to_string()],
+            vec!["3".to_string()],
+        ]
+    );
+    let _ = std::fs::remove_dir_all(&executor.db_base_path);
+}
+
+#[test]
+fn rollback_restores_indexes_with_their_tables() {
+    let mut executor = executor("rollback-indexes");
+    run(&mut executor, "CREATE TABLE t (id INTEGER, s TEXT(8))").unwrap();
+    run(&mut executor, "CREATE UNIQUE INDEX idx_s ON t (s)").unwrap();
+    for id in 1..=300 {
+        let sql = format!("INSERT INTO t VALUES ({}, 's{}')", id, id);
+        run(&mut executor, &sql).unwrap();
+    }
+    let lookups = [
+        "SELECT id FROM t WHERE s = 's7'",
+        "SELECT id FROM t WHERE s = 'new'",
+        "SELECT id FROM t WHERE s = 's8'",
+        "SELECT COUNT(*) FROM t WHERE s >= 's1' AND s < 's2'",
+        "SELECT COUNT(*) FROM t WHERE s LIKE 's2%'",
+        "SELECT COUNT(*) FROM t",
+    ];
+    let before: Vec<_> = lookups.iter().map(|sql| rows(&mut executor, sql)).collect();
+
+    run(&mut executor, "BEGIN").unwrap();
+    run(&mut executor, "INSERT INTO t VALUES (301, 'new')").unwrap();
+    run(&mut executor, "UPDATE t SET s = 'x7' WHERE id = 7").unwrap();
+    run(&mut executor, "DELETE FROM t WHERE id >= 100 AND id < 250").unwrap();
+    assert_eq!(
+        rows(&mut executor, lookups[1]),
+        vec![vec!["301".to_string()]]
+    );
+    assert!(rows(&mut executor, lookups[0]).is_empty());
+    run(&mut executor, "ROLLBACK").unwrap();
+
+    for (sql, before) in lookups.iter().zip(&before) {
+        assert_eq!(&rows(&mut executor, sql), before, "{}", sql);
+    }
+    // The rolled-back value is free again, and the restored one is taken
+    run(&mut executor, "INSERT INTO t VALUES (301, 'new')").unwrap();
+    assert!(run(&mut executor, "INSERT INTO t VALUES (302, 's7')").is_err());
+    let _ = std::fs::remove_dir_all(&executor.db_base_path);
+}
@@ -0,0 +1,250 @@
+//! Databases written before table files had a header open, upgraded, and
+//! answer queries like any other
+
+use rsql::executor::{ExecuteResult, Executor};
+use rsql::parser::Parser;
+use rsql::tokenizer::Tokenizer;
+
+fn run(executor: &mut Executor, sql: &str) -> Result<ExecuteResult, String> {
+    let tokens = Tokenizer::new(sql).tokenize();
+    let statement = Parser::new(tokens).parse()?;
+    executor.execute(statement)
+}
+
+fn rows(executor: &mut Executor, sql: &str) -> Vec<Vec<String>> {
+    match run(executor, sql).unwrap() {
+        ExecuteResult::Rows { rows, .. } => rows,
+        other => panic!("expected rows, got {:?}", other),
+    }
+}
+
+const PAGE_SIZE: usize = 4096;
+const HEADER_SIZE: usize = 14;
+
+/// A table file as the first versions wrote it, built by replaying how
+/// they inserted a row: the root stays on page 0, and a split adds the new
+/// leaf to the root without moving its right child, so later keys reach
+/// leaves out of order. Only the leaf chain keeps every row.
+struct LegacyTable {
+    pages: Vec<[u8; PAGE_SIZE]>,
+    cell_size: usize,
+}
+
+fn read_u32(page: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(page[offset..offset + 4].try_into().unwrap())
+}
+
+fn write_u32(page: &mut [u8], offset: usize, value: u32) {
+    page[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+// Node header: type (0 internal, 1 leaf), is-root flag, parent pointer,
+// then the cell or key count and the next leaf or right child
+fn is_leaf(page: &[u8]) -> bool {
+    page[0] == 1
+}
+
+fn count(page: &[u8]) -> u32 {
+    read_u32(page, 6)
+}
+
+fn init_leaf(page: &mut [u8; PAGE_SIZE]) {
+    page[..HEADER_SIZE].fill(0);
+    page[0] = 1;
+}
+
+fn internal_key(page: &[u8], i: u32) -> u32 {
+    read_u32(page, HEADER_SIZE + i as usize * 8 + 4)
+}
+
+fn internal_child(page: &[u8], i: u32) -> u32 {
+    match i == count(page) {
+        true => read_u32(page, 10),
+        false => read_u32(page, HEADER_SIZE + i as usize * 8),
+    }
+}
+
+fn set_internal_child(page: &mut [u8], i: u32, child: u32) {
+    match i == count(page) {
+        true => write_u32(page, 10, child),
+        false => write_u32(page, HEADER_SIZE + i as usize * 8, child),
+    }
+}
+
+impl LegacyTable {
+    fn new(row_size: usize) -> Self {
+        let mut root = [0u8; PAGE_SIZE];
+        init_leaf(&mut root);
+        root[1] = 1;
+        LegacyTable {
+            pages: vec![root],
+            cell_size: 4 + row_size,
+        }
+    }
+
+    fn cells(&self, page_num: usize) -> Vec<(u32, Vec<u8>)> {
+        let page = &self.pages[page_num];
+        (0..count(page) as usize)
+            .map(|i| {
+                let cell = &page[HEADER_SIZE + i * self.cell_size..][..self.cell_size];
+                (read_u32(cell, 0), cell[4..].to_vec())
+            })
+            .collect()
+    }
+
+    fn write_cells(&mut self, page_num: usize, cells: &[(u32, Vec<u8>)]) {
+        let cell_size = self.cell_size;
+        let page = &mut self.pages[page_num];
+        for (i, (key, row)) in cells.iter().enumerate() {
+            let cell = &mut page[HEADER_SIZE + i * cell_size..][..cell_size];
+            write_u32(cell, 0, *key);
+            cell[4..].copy_from_slice(row);
+        }
+        write_u32(page, 6, cells.len() as u32);
+    }
+
+    fn insert(&mut self, key: u32, row: Vec<u8>) {
+        let mut page_num = 0;
+        while !is_leaf(&self.pages[page_num]) {
+            let page = &self.pages[page_num];
+            let child = (0..count(page))
+                .find(|&i| internal_key(page, i) >= key)
+                .unwrap_or(count(page));
+            page_num = internal_child(page, child) as usize;
+        }
+        let mut cells = self.cells(page_num);
+        let slot = cells.partition_point(|(k, _)| *k < key);
+        cells.insert(slot, (key, row));
+        if cells.len() <= (PAGE_SIZE - HEADER_SIZE) / self.cell_size {
+            self.write_cells(page_num, &cells);
+            return;
+        }
+
+        // Split, the new leaf taking the upper half
+        let new_page = self.pages.len();
+        let mut page = [0u8; PAGE_SIZE];
+        init_leaf(&mut page);
+        let next = read_u32(&self.pages[page_num], 10);
+        write_u32(&mut page, 10, next);
+        self.pages.push(page);
+        write_u32(&mut self.pages[page_num], 10, new_page as u32);
+        let left = cells.len().div_ceil(2);
+        self.write_cells(page_num, &cells[..left]);
+        self.write_cells(new_page, &cells[left..]);
+        let split_key = cells[left - 1].0;
+
+        if page_num == 0 {
+            // The root leaf moves to a new page under a new internal root
+            let new_left = self.pages.len();
+            let mut copy = self.pages[0];
+            copy[1] = 0;
+            self.pages.push(copy);
+            let root = &mut self.pages[0];
+            root[..HEADER_SIZE].fill(0);
+            root[1] = 1;
+            write_u32(root, 6, 1);
+            set_internal_child(root, 0, new_left as u32);
+            write_u32(root, HEADER_SIZE + 4, split_key);
+            write_u32(root, 10, new_page as u32);
+            return;
+        }
+
+        let root = &mut self.pages[0];
+        let keys = count(root);
+        let at = (0..keys)
+            .find(|&i| internal_key(root, i) > split_key)
+            .unwrap_or(keys);
+        for i in (at..keys).rev() {
+            let (key, child) = (internal_key(root, i), internal_child(root, i + 1));
+            write_u32(root, HEADER_SIZE + (i as usize + 1) * 8 + 4, key);
+            set_internal_child(root, i + 2, child);
+        }
+        set_internal_child(root, at + 1, new_page as u32);
+        write_u32(root, HEADER_SIZE + at as usize * 8 + 4, split_key);
+        write_u32(root, 6, keys + 1);
+    }
+}
+
+/// A row of `items (id INTEGER, code TEXT(8), qty INTEGER)` as it was
+/// stored: the id column left empty, the others as text
+fn legacy_row(id: u32) -> Vec<u8> {
+    let mut row = vec![0u8; 16];
+    let code = format!("c{}", id);
+    row[4..4 + code.len()].copy_from_slice(code.as_bytes());
+    let qty = (id % 7).to_string();
+    row[12..12 + qty.len()].copy_from_slice(qty.as_bytes());
+    row
+}
+
+#[test]
+fn a_legacy_table_is_upgraded_on_connect() {
+    let base = std::env::temp_dir().join(format!("rsql-upgrade-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&base);
+    let dir = base.join("old");
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut table = LegacyTable::new(16);
+    for id in 1..=600 {
+        table.insert(id, legacy_row(id));
+    }
+    std::fs::write(dir.join("items.db"), table.pages.concat()).unwrap();
+    // Its index is from the same days, and is built again from the table
+    let mut index = [0u8; PAGE_SIZE];
+    init_leaf(&mut index);
+    std::fs::write(dir.join("items_idx_code.idx"), index).unwrap();
+    std::fs::write(
+        dir.join("metadata.json"),
+        "{\"tables\":{\"items\":{\"columns\":[\
+         {\"name\":\"id\",\"type\":\"INTEGER\"},\
+         {\"name\":\"code\",\"type\":\"TEXT(8)\"},\
+         {\"name\":\"qty\",\"type\":\"INTEGER\"}],\
+         \"indexes\":[{\"name\":\"idx_code\",\"column\":\"code\",\"unique\":true}]}}}",
+    )
+    .unwrap();
+
+    let mut executor = Executor::new();
+    executor.db_base_path = base.clone();
+    run(&mut executor, "CONNECT old").unwrap();
+    for id in 1..=600 {
+        assert_eq!(
+            rows(
+                &mut executor,
+                &format!("SELECT code FROM items WHERE id = {}", id)
+            ),
+            vec![vec![format!("c{}", id)]],
+            "id = {}",
+            id
+        );
+    }
+    assert_eq!(
+        rows(
+            &mut executor,
+            "SELECT COUNT(*), MIN(id), MAX(id) FROM items"
+        ),
+        vec![vec!["600".to_string(), "1".to_string(), "600".to_string()]]
+    );
+    assert_eq!(
+        rows(
+            &mut executor,
+            "SELECT id, qty FROM items WHERE code = 'c345'"
+        ),
+        vec![vec!["345".to_string(), "2".to_string()]]
+    );
+
+    // The upgraded files take writes and reopen as they are
+    run(&mut executor, "INSERT INTO items VALUES (601, 'c601', 6)").unwrap();
+    assert!(run(&mut executor, "INSERT INTO items VALUES (602, 'c20', 0)").is_err());
+    run(&mut executor, "DELETE FROM items WHERE id < 300").unwrap();
+    let mut executor = Executor::new();
+    executor.db_base_path = base.clone();
+    run(&mut executor, "CONNECT old").unwrap();
+    assert_eq!(
+        rows(&mut executor, "SELECT COUNT(*) FROM items WHERE id >= 300"),
+        vec![vec!["302".to_string()]]
+    );
+    assert_eq!(
+        rows(&mut executor, "SELECT id FROM items WHERE code = 'c601'"),
+        vec![vec!["601".to_string()]]
+    );
+    let _ = std::fs::remove_dir_all(&base);
+}